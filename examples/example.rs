@@ -5,14 +5,12 @@ extern crate parng;
 extern crate time;
 
 use byteorder::{LittleEndian, WriteBytesExt};
-use parng::simple::Image;
+use parng::simple::{Image, LoadOptions};
 use std::env;
 use std::fs::File;
 use std::io::{self, BufWriter, Write};
 use std::process;
 
-const OUTPUT_BPP: u32 = 4;
-
 fn usage() -> ! {
     write!(io::stderr(), "usage: example image.png image.tga");
     process::exit(0)
@@ -24,7 +22,12 @@ fn main() {
     let out_path = args.next().unwrap_or_else(|| usage());
 
     let before = time::precise_time_ns();
-    let image = Image::load(&mut File::open(&in_path).unwrap()).unwrap();
+    // TGA images are stored bottom-up by default, so have `parng` flip the image for us.
+    let options = LoadOptions {
+        flip_vertically: true,
+        ..LoadOptions::default()
+    };
+    let image = Image::load_with_options(&mut File::open(&in_path).unwrap(), &options).unwrap();
     let elapsed = time::precise_time_ns() - before;
     println!("Elapsed time: {}ms", elapsed as f32 / 1_000_000.0);
 
@@ -40,14 +43,17 @@ fn main() {
         .unwrap();
     output.write_all(&[24, 0]).unwrap();
 
+    // For 16-bit images, just use the most significant byte of each sample.
+    let bytes_per_sample = image.bit_depth as usize / 8;
+    let bytes_per_pixel = bytes_per_sample * 4;
+
     for y in 0..image.height {
-        let y = image.height - y - 1;
         for x in 0..image.width {
-            let start = (image.stride * (y as usize)) + (x as usize) * (OUTPUT_BPP as usize);
-            let src_r = image.pixels[start + 0] as f32;
-            let src_g = image.pixels[start + 1] as f32;
-            let src_b = image.pixels[start + 2] as f32;
-            let src_a = (image.pixels[start + 3] as f32) / 255.0;
+            let start = (image.stride * (y as usize)) + (x as usize) * bytes_per_pixel;
+            let src_r = image.pixels[start + 0 * bytes_per_sample] as f32;
+            let src_g = image.pixels[start + 1 * bytes_per_sample] as f32;
+            let src_b = image.pixels[start + 2 * bytes_per_sample] as f32;
+            let src_a = (image.pixels[start + 3 * bytes_per_sample] as f32) / 255.0;
 
             // Blend with a checkerboard pattern so transparency will be visible.
             let dest = if (x % 32 < 16 && y % 32 < 16) || (x % 32 >= 16 && y % 32 >= 16) {
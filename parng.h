@@ -27,6 +27,9 @@
 #define PARNG_ERROR_INVALID_SCANLINE_PREDICTOR                  4
 #define PARNG_ERROR_ENTROPY_DECODING_ERROR                      5
 #define PARNG_ERROR_NO_DATA_PROVIDER                            6
+#define PARNG_ERROR_INVALID_CRC                                 7
+#define PARNG_ERROR_INVALID_BUFFER                              8
+#define PARNG_ERROR_INVALID_OPTION                              9
 
 #define PARNG_FILTER_METHOD_ADAPTIVE                            0
 
@@ -276,7 +279,8 @@ struct parng_data_provider {
     //
     // `indexed` is true if the image has a color palette. If it is true, then the scanlines
     // returned should have 8 bits of storage per pixel. Otherwise, the data provider should
-    // return scanlines with 32 bits of storage per pixel.
+    // return scanlines with 32 bits of storage per pixel, or 64 bits of storage per pixel if the
+    // image has 16 bits per sample.
     //
     // `user_data` is the contents of the data provider's `user_data` field.
     void (*fetch_scanlines_for_prediction)(int32_t reference_scanline,
@@ -335,7 +339,8 @@ struct parng_data_provider {
     void *user_data;
 };
 
-// An in-memory decoded image in big-endian RGBA format, 32 bits per pixel.
+// An in-memory decoded image in big-endian RGBA format, 32 bits per pixel (or 64 bits per pixel
+// for images with 16 bits per sample).
 struct parng_image {
     // The width of the image, in pixels.
     uint32_t width;
@@ -360,6 +365,12 @@ struct parng_image {
 
     // A pointer to the actual pixels.
     uint8_t *pixels;
+
+    // The number of bits per sample.
+    //
+    // The number of bits per sample: 8, or 16 if the PNG image had 16 bits per sample. Each pixel
+    // occupies 4 samples.
+    uint32_t bit_depth;
 };
 
 // Metadata found in the PNG header (dimensions, bit depth, etc.)
@@ -412,7 +423,8 @@ extern "C" {
 // Allocates space for and loads a PNG image stream from a reader into memory.
 //
 // Allocates space for and loads a PNG image stream from a reader into memory. The returned image
-// is big-endian, 32 bits per pixel RGBA.
+// is big-endian, 32 bits per pixel RGBA, or 64 bits per pixel RGBA if the PNG image has 16 bits per
+// sample.
 //
 // This method does not return until the image is fully loaded. If you need a different
 // in-memory representation, or you need to display the image before it's fully loaded,
@@ -447,10 +459,11 @@ void parng_image_loader_destroy(parng_image_loader *image_loader);
 // This function decodes an arbitrary amount of data, so repeated calls to it are necessary to
 // decode the entire image.
 //
-// If the metadata has been read (which is checkable ether via `parng_image_loader_get_metadata` or
-// by looking for a `PARNG_LOAD_PROGRESS_NEED_DATA_PROVIDER_AND_MORE_DATA` result), a data provider
-// must have be attached to this image loader via `parng_image_loader_set_data_provider` before
-// calling this function, or this function will fail with a `PARNG_ERROR_NO_DATA_PROVIDER` error.
+// If this function has returned `PARNG_LOAD_PROGRESS_NEED_DATA_PROVIDER_AND_MORE_DATA`, a data
+// provider must be attached to this image loader via `parng_image_loader_set_data_provider` before
+// calling this function again, or this function will fail with a `PARNG_ERROR_NO_DATA_PROVIDER`
+// error. That result is returned upon reaching the first `IDAT` (or, for animation frames, `fdAT`)
+// chunk, so by then all the chunks that precede the image data have been read.
 //
 // Returns a `parng_load_progress` value that describes the progress of loading the image.
 parng_error parng_image_loader_add_data(parng_image_loader *image_loader,
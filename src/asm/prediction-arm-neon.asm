@@ -24,6 +24,8 @@
 .global parng_predict_scanline_average_strided_24bpp
 .global parng_predict_scanline_paeth_strided_32bpp
 .global parng_predict_scanline_paeth_strided_24bpp
+.global parng_premultiply_scanline_packed_32bpp
+.global parng_premultiply_scanline_strided_32bpp
 
 #define dest r0
 #define src r1
@@ -35,38 +37,46 @@
 
 @ Helper functions to factor out the unsafe memory accesses in one place follow.
 
+@ Every routine may clobber r7, so that is saved along with the registers that the loop uses.
 .macro prolog
-    stmfd sp!,{r4-r6}
+    stmfd sp!,{r4-r7,lr}
     ldr stride,[sp,#5*4]
 .endm
 
+@ r5 counts the bytes of the destination written so far. As in the x86-64 version, `dest` and
+@ `prev` advance by the destination stride and `src` by the source stride on every iteration, so
+@ `[dest]`, `[prev]`, and `[src]` always refer to the current pixels.
 .macro loop_start
-    prolog
     mov r5,#0
 1:
 .endm
 
 .macro loop_end dest_stride, src_stride
+    add dest,dest,#\dest_stride
+    add prev,prev,#\dest_stride
+    add src,src,#\src_stride
     add r5,r5,#\dest_stride
-    add r6,r6,#\src_stride
     cmp r5,length
     blo 1b
 .endm
 
-.macro loop_end_stride stride
-    add r5,r5,#\stride
+.macro loop_end_stride src_stride
+    add dest,dest,stride
+    add prev,prev,stride
+    add src,src,#\src_stride
+    add r5,r5,stride
     cmp r5,length
     blo 1b
 .endm
 
 .macro epilog
-    ldmfd sp!,{r4-r6,pc}
+    ldmfd sp!,{r4-r7,pc}
 .endm
 
 @ We factor out this safe pattern for the benefit of the static analysis, which would interpret
 @ `[0]` as an unsafe memory access.
-.macro move_neon_byte_to_register dest,src
-    vmov.u8 \dest,\src[0]
+.macro move_neon_byte_to_register to,from
+    vmov.u8 \to,\from[0]
 .endm
 
 @ #begin-safe-code
@@ -132,7 +142,7 @@ parng_predict_scanline_none_strided_32bpp:
     prolog
     loop_start
     ldr r7,[src]
-    str r7,[src]
+    str r7,[dest]
     loop_end_stride 4
     epilog
 
@@ -535,3 +545,67 @@ parng_predict_scanline_paeth_strided_24bpp:
     loop_end_stride 3
     epilog
 
+@ load_premultiply_constants()
+@
+@ Register outputs: d2 = alpha broadcast table lookup mask, d3 = [ ff000000 x 2 ]
+@ Register clobbers: r7
+.macro load_premultiply_constants
+    ldr r7,=0xff030303
+    vmov s4,r7
+    ldr r7,=0xff070707
+    vmov s5,r7                  @ d2 = alpha broadcast table lookup mask
+    mov r7,#0xff000000
+    vmov s6,r7
+    vmov s7,r7                  @ d3 = [ ff000000 x 2 ]
+.endm
+
+@ premultiply_pixels()
+@
+@ Register inputs: d0 = 2 pixels, d2 = alpha broadcast table lookup mask, d3 = [ ff000000 x 2 ]
+@ Register outputs: d0 = 2 premultiplied pixels
+@ Register clobbers: d1, q2, q3
+@
+@ This computes round(color × alpha / 255) exactly, without division. Alpha is multiplied by 255
+@ so that it comes out unchanged.
+.macro premultiply_pixels
+    vtbl.8 d1,{d0},d2           @ d1 = [ 0, a, a, a ]
+    vorr d1,d1,d3               @ d1 = [ 255, a, a, a ]
+    vmull.u8 q2,d0,d1           @ q2 = x = color × alpha (16-bit)
+    vrshr.u16 q3,q2,#8          @ q3 = (x + 128) >> 8
+    vadd.i16 q2,q2,q3           @ q2 = x + ((x + 128) >> 8)
+    vrshrn.i16 d0,q2,#8         @ d0 = round(color × alpha / 255)
+.endm
+
+@ parng_premultiply_scanline_packed_32bpp(uint8x4 *dest,
+@                                         uint8x4 *src,
+@                                         uint8x4 *prev,
+@                                         uint32_t length,
+@                                         uint32_t stride)
+@
+@ `src` and `prev` are ignored; the scanline is premultiplied in place.
+parng_premultiply_scanline_packed_32bpp:
+    prolog
+    load_premultiply_constants
+    loop_start
+    vldr d0,[dest]
+    premultiply_pixels
+    vstr d0,[dest]
+    loop_end 8,8
+    epilog
+
+@ parng_premultiply_scanline_strided_32bpp(uint8x4 *dest,
+@                                          uint8x4 *src,
+@                                          uint8x4 *prev,
+@                                          uint32_t length,
+@                                          uint32_t stride)
+@
+@ `src` and `prev` are ignored; the scanline is premultiplied in place.
+parng_premultiply_scanline_strided_32bpp:
+    prolog
+    load_premultiply_constants
+    loop_start
+    vldr s0,[dest]
+    premultiply_pixels
+    vstr s0,[dest]
+    loop_end_stride 4
+    epilog
@@ -26,6 +26,8 @@ global parng_predict_scanline_average_strided_32bpp
 global parng_predict_scanline_average_strided_24bpp
 global parng_predict_scanline_paeth_strided_32bpp
 global parng_predict_scanline_paeth_strided_24bpp
+global parng_premultiply_scanline_packed_32bpp
+global parng_premultiply_scanline_strided_32bpp
 
 ; Abstract over Windows and System V calling conventions.
 %ifidn __OUTPUT_FORMAT__,win64
@@ -135,6 +137,16 @@ section .text
     movq %1,r11                 ; dest = 64bpp → 32bpp opaque alpha shuffle mask
 %endmacro
 
+; load_alpha_broadcast_shuffle_mask(r128 dest)
+;
+; Register clobbers: r11
+%macro load_alpha_broadcast_shuffle_mask 1
+    mov r11,0x0706070607060706
+    movq %1,r11
+    mov r11,0x0f0e0f0e0f0e0f0e
+    pinsrq %1,r11,1                             ; dest = 16-bit alpha broadcast shuffle mask
+%endmacro
+
 ; load_32bpp_opaque_alpha_mask(r128 dest)
 ;
 ; Register clobbers: r11
@@ -165,8 +177,8 @@ parng_predict_scanline_none_packed_32bpp:
 parng_predict_scanline_none_strided_32bpp:
     prolog
     loop_start
-    mov r11,[src]
-    mov [dest],r11
+    mov r11d,[src]
+    mov [dest],r11d
     loop_end_stride 4
     epilog
 
@@ -576,3 +588,78 @@ parng_predict_scanline_paeth_strided_24bpp:
     loop_end_stride 3
     epilog
 
+; premultiply_pixels(r128 pixels)
+;
+; Register inputs: %1 = 2 pixels (16-bit), xmm4 = [ 0080 x 8 ], xmm5 = alpha broadcast shuffle
+;                  mask, xmm6 = [ 00ff000000000000 x 2 ]
+; Register outputs: %1 = 2 premultiplied pixels (16-bit)
+; Register clobbers: xmm3
+;
+; This computes round(color × alpha / 255) without division, as x + (x >> 8) >> 8 where
+; x = color × alpha + 128, which is exact for all 8-bit inputs. Alpha is multiplied by 255 so that
+; it comes out unchanged.
+%macro premultiply_pixels 1
+    vpshufb xmm3,%1,xmm5                        ; xmm3 = [ a, a, a, a ]
+    por xmm3,xmm6                               ; xmm3 = [ 255, a, a, a ]
+    pmullw %1,xmm3                              ; %1 = color × alpha
+    paddw %1,xmm4                               ; %1 = x = color × alpha + 128
+    vpsrlw xmm3,%1,8                            ; xmm3 = x >> 8
+    paddw %1,xmm3                               ; %1 = x + (x >> 8)
+    psrlw %1,8                                  ; %1 = round(color × alpha / 255)
+%endmacro
+
+; load_premultiply_constants()
+;
+; Register outputs: xmm4 = [ 0080 x 8 ], xmm5 = alpha broadcast shuffle mask,
+;                   xmm6 = [ 00ff000000000000 x 2 ]
+; Register clobbers: r11
+%macro load_premultiply_constants 0
+    mov r11,0x0080008000800080
+    movq xmm4,r11
+    movddup xmm4,xmm4                           ; xmm4 = [ 0080 x 8 ]
+    load_alpha_broadcast_shuffle_mask xmm5
+    mov r11,0x00ff000000000000
+    movq xmm6,r11
+    movddup xmm6,xmm6                           ; xmm6 = [ 00ff000000000000 x 2 ]
+%endmacro
+
+; parng_premultiply_scanline_packed_32bpp(uint8x4 *dest,
+;                                         uint8x4 *src,
+;                                         uint8x4 *prev,
+;                                         uint64_t length,
+;                                         uint64_t stride)
+;
+; `src` and `prev` are ignored; the scanline is premultiplied in place.
+parng_premultiply_scanline_packed_32bpp:
+    prolog
+    load_premultiply_constants
+    loop_start
+    movdqa xmm0,[dest]                          ; xmm0 = 4 pixels (8-bit)
+    pmovzxbw xmm1,xmm0                          ; xmm1 = first 2 pixels (16-bit)
+    vpsrldq xmm2,xmm0,8
+    pmovzxbw xmm2,xmm2                          ; xmm2 = last 2 pixels (16-bit)
+    premultiply_pixels xmm1
+    premultiply_pixels xmm2
+    packuswb xmm1,xmm2                          ; xmm1 = 4 premultiplied pixels (8-bit)
+    movdqa [dest],xmm1                          ; write result
+    loop_end 16,16
+    epilog
+
+; parng_premultiply_scanline_strided_32bpp(uint8x4 *dest,
+;                                          uint8x4 *src,
+;                                          uint8x4 *prev,
+;                                          uint64_t length,
+;                                          uint64_t stride)
+;
+; `src` and `prev` are ignored; the scanline is premultiplied in place.
+parng_premultiply_scanline_strided_32bpp:
+    prolog
+    load_premultiply_constants
+    loop_start
+    movd xmm1,[dest]                            ; xmm1 = pixel (8-bit)
+    pmovzxbw xmm1,xmm1                          ; xmm1 = pixel (16-bit)
+    premultiply_pixels xmm1
+    packuswb xmm1,xmm1                          ; xmm1 = premultiplied pixel (8-bit)
+    movd [dest],xmm1                            ; write result
+    loop_end_stride 4
+    epilog
@@ -59,6 +59,9 @@ pub const PARNG_ERROR_INVALID_SCANLINE_PREDICTOR: u32 = 3;
 pub const PARNG_ERROR_ENTROPY_DECODING_ERROR: u32 = 4;
 pub const PARNG_ERROR_NO_DATA_PROVIDER: u32 = 5;
 pub const PARNG_ERROR_DECOMPRESS: u32 = 6;
+pub const PARNG_ERROR_INVALID_CRC: u32 = 7;
+pub const PARNG_ERROR_INVALID_BUFFER: u32 = 8;
+pub const PARNG_ERROR_INVALID_OPTION: u32 = 9;
 
 pub const PARNG_FILTER_METHOD_ADAPTIVE: u32 = 0;
 
@@ -206,6 +209,7 @@ pub struct parng_image {
     stride: size_t,
     capacity: size_t,
     pixels: *mut u8,
+    bit_depth: u32,
 }
 
 /// The fields of this structure are intentionally private so that the rest of `parng` can't
@@ -474,6 +478,9 @@ fn png_error_to_c_error(err: PngError) -> parng_error {
         PngError::EntropyDecodingError => PARNG_ERROR_ENTROPY_DECODING_ERROR,
         PngError::NoDataProvider => PARNG_ERROR_NO_DATA_PROVIDER,
         PngError::Decompress(_) => PARNG_ERROR_DECOMPRESS,
+        PngError::InvalidCrc(..) => PARNG_ERROR_INVALID_CRC,
+        PngError::BufferTooSmall(_) | PngError::StrideTooSmall(_) => PARNG_ERROR_INVALID_BUFFER,
+        PngError::InvalidOption(_) => PARNG_ERROR_INVALID_OPTION,
     }
 }
 
@@ -586,14 +593,63 @@ fn interlace_method_to_c_interlace_method(
 }
 
 unsafe fn image_to_c_image(mut image: Image) -> parng_image {
-    assert!(image.stride * image.height as usize == image.pixels.len());
+    // The pixels may be followed by some slack for the predictor, which C callers needn't know
+    // about.
+    assert!(image.stride * image.height as usize <= image.pixels.len());
     let c_image = parng_image {
         width: image.width,
         height: image.height,
         stride: image.stride,
         capacity: image.pixels.capacity(),
         pixels: image.pixels.as_mut_ptr(),
+        bit_depth: image.bit_depth as u32,
     };
     mem::forget(image.pixels);
     c_image
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::{Compression, Crc};
+    use std::io::Write;
+
+    fn write_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+        let mut crc = Crc::new();
+        crc.update(chunk_type);
+        crc.update(data);
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        png.extend_from_slice(chunk_type);
+        png.extend_from_slice(data);
+        png.extend_from_slice(&crc.sum().to_be_bytes());
+    }
+
+    #[test]
+    fn image_reports_16_bit_samples() {
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(&[0, 0x12, 0x34, 0xab, 0xcd]).unwrap();
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        write_chunk(&mut png, b"IHDR", b"\0\0\0\x02\0\0\0\x01\x10\0\0\0\0");
+        write_chunk(&mut png, b"IDAT", &encoder.finish().unwrap());
+        write_chunk(&mut png, b"IEND", &[]);
+
+        unsafe {
+            let mut c_image = mem::zeroed();
+            let error = parng_image_load_from_memory(&mut c_image, png.as_ptr(), png.len());
+            assert_eq!(error, PARNG_SUCCESS);
+            assert_eq!(
+                (c_image.width, c_image.height, c_image.bit_depth),
+                (2, 1, 16)
+            );
+            assert_eq!(
+                slice::from_raw_parts(c_image.pixels, 16),
+                [
+                    0x12, 0x34, 0x12, 0x34, 0x12, 0x34, 0xff, 0xff, 0xab, 0xcd, 0xab, 0xcd, 0xab,
+                    0xcd, 0xff, 0xff
+                ]
+            );
+            parng_image_destroy(&mut c_image);
+        }
+    }
+}
@@ -0,0 +1,574 @@
+// parng/colortransform.rs
+//
+// Copyright (c) 2016 Mozilla Foundation
+
+//! Conversion of images to sRGB or other RGB color spaces, as described by their ICC profiles or
+//! their `cHRM` and `gAMA` chunks.
+//!
+//! Only the simplest kind of ICC profile is supported: matrix/TRC profiles, version 2 or 4, which
+//! describe a color space with a tone curve per channel followed by a 3×3 matrix to CIE XYZ. Most
+//! profiles embedded in images, such as those of Display P3 and Adobe RGB, are of this kind.
+
+use crate::metadata::{Chromaticities, ColorManagement, Gamma};
+use crate::PngError;
+use byteorder::{BigEndian, ByteOrder};
+
+/// A 3×3 matrix, stored row by row.
+pub type Matrix = [[f64; 3]; 3];
+
+/// The white point of the ICC profile connection space, D50, as CIE XYZ.
+const D50: [f64; 3] = [0.9642, 1.0, 0.8249];
+
+/// The matrix of the Bradford chromatic adaptation transform, from CIE XYZ to cone responses.
+const BRADFORD: Matrix = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+/// The primaries and white point of sRGB and BT.709.
+pub const SRGB_CHROMATICITIES: Chromaticities = Chromaticities {
+    white_point: (31270, 32900),
+    red: (64000, 33000),
+    green: (30000, 60000),
+    blue: (15000, 6000),
+};
+
+/// The color space that `ImageLoader::set_color_transform()` converts images to.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TargetColorSpace {
+    /// sRGB.
+    Srgb,
+    /// The given primaries and white point, with the sRGB transfer function, as Display P3 has.
+    Primaries(Chromaticities),
+}
+
+impl TargetColorSpace {
+    /// Returns the primaries and white point of the color space.
+    pub fn chromaticities(&self) -> Chromaticities {
+        match *self {
+            TargetColorSpace::Srgb => SRGB_CHROMATICITIES,
+            TargetColorSpace::Primaries(chromaticities) => chromaticities,
+        }
+    }
+
+    /// Returns the matrix from CIE XYZ with a D50 white point to linear RGB in the color space,
+    /// or an error if its primaries are degenerate.
+    pub fn from_xyz(&self) -> Result<Matrix, PngError> {
+        invert(&rgb_to_d50_xyz(&self.chromaticities())?)
+    }
+}
+
+/// A function that maps encoded samples in the range 0.0 to 1.0 to linear light, as found in the
+/// `rTRC`, `gTRC`, `bTRC`, and `kTRC` tags of ICC profiles.
+#[derive(Clone, PartialEq, Debug)]
+pub enum ToneCurve {
+    /// The piecewise sRGB transfer function.
+    Srgb,
+    /// A power function with the given exponent, such as 2.2.
+    Power(f64),
+    /// Evenly spaced samples of the curve, which is linearly interpolated between them, scaled
+    /// such that 65535 is 1.0.
+    Table(Vec<u16>),
+    /// An ICC parametric curve, with the parameters g, a, b, c, d, e, and f: `(a * x + b)^g + e`
+    /// if x is at least d, and `c * x + f` otherwise. All five ICC function types take this form.
+    Parametric([f64; 7]),
+}
+
+impl ToneCurve {
+    /// Decodes a sample in the range 0.0 to 1.0 to linear light.
+    pub fn to_linear(&self, value: f64) -> f64 {
+        match *self {
+            ToneCurve::Srgb if value <= 0.04045 => value / 12.92,
+            ToneCurve::Srgb => ((value + 0.055) / 1.055).powf(2.4),
+            ToneCurve::Power(exponent) => value.powf(exponent),
+            ToneCurve::Table(ref table) => {
+                let position = value * (table.len() - 1) as f64;
+                let index = (position as usize).min(table.len() - 2);
+                let fraction = position - index as f64;
+                let (start, end) = (table[index] as f64, table[index + 1] as f64);
+                (start + (end - start) * fraction) / 65535.0
+            }
+            ToneCurve::Parametric([g, a, b, c, d, e, f]) => {
+                if value >= d {
+                    let base = a * value + b;
+                    if base > 0.0 {
+                        base.powf(g) + e
+                    } else {
+                        e
+                    }
+                } else {
+                    c * value + f
+                }
+            }
+        }
+    }
+}
+
+/// The color space of an image, as a tone curve per channel followed by a matrix from the
+/// resulting linear RGB to CIE XYZ adapted to D50, as in an ICC matrix/TRC profile.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SourceColorSpace {
+    /// The tone curves of the red, green, and blue channels, which are all the same for
+    /// grayscale images.
+    pub curves: [ToneCurve; 3],
+    /// The matrix from linear RGB to CIE XYZ with a D50 white point.
+    pub to_xyz: Matrix,
+}
+
+impl SourceColorSpace {
+    /// Returns the color space of sRGB.
+    pub fn srgb() -> SourceColorSpace {
+        SourceColorSpace {
+            curves: [ToneCurve::Srgb, ToneCurve::Srgb, ToneCurve::Srgb],
+            to_xyz: rgb_to_d50_xyz(&SRGB_CHROMATICITIES).unwrap(),
+        }
+    }
+
+    /// Determines the color space of an image from its color-management chunks: the `iCCP`
+    /// chunk if it holds a supported profile, otherwise the `sRGB` chunk, and otherwise the
+    /// `gAMA` chunk, together with the `cHRM` chunk or, if that is absent, the sRGB primaries.
+    /// Returns `None` if none of these apply.
+    ///
+    /// `grayscale` specifies whether the image is grayscale, which requires a grayscale ICC
+    /// profile instead of an RGB one.
+    pub fn from_color_management(
+        color_management: &ColorManagement,
+        grayscale: bool,
+    ) -> Option<SourceColorSpace> {
+        if let Some(ref icc_profile) = color_management.icc_profile {
+            if let Ok(color_space) =
+                SourceColorSpace::from_icc_profile(&icc_profile.profile, grayscale)
+            {
+                return Some(color_space);
+            }
+        }
+        if color_management.srgb_rendering_intent.is_some() {
+            return Some(SourceColorSpace::srgb());
+        }
+        let gamma = color_management.gamma.as_ref()?;
+        let chromaticities = if grayscale {
+            None
+        } else {
+            color_management.chromaticities.as_ref()
+        };
+        SourceColorSpace::from_chromaticities(chromaticities, gamma).ok()
+    }
+
+    /// Returns the color space with the given primaries and white point, or those of sRGB if
+    /// `None` is given, and the given gamma.
+    pub fn from_chromaticities(
+        chromaticities: Option<&Chromaticities>,
+        gamma: &Gamma,
+    ) -> Result<SourceColorSpace, PngError> {
+        let curve = ToneCurve::Power(1.0 / gamma.value());
+        Ok(SourceColorSpace {
+            curves: [curve.clone(), curve.clone(), curve],
+            to_xyz: rgb_to_d50_xyz(chromaticities.unwrap_or(&SRGB_CHROMATICITIES))?,
+        })
+    }
+
+    /// Parses a matrix/TRC ICC profile, which must be an RGB profile, or a grayscale profile if
+    /// `grayscale` is true. Other profiles result in an error.
+    pub fn from_icc_profile(profile: &[u8], grayscale: bool) -> Result<SourceColorSpace, PngError> {
+        if profile.len() < 132 {
+            return Err(unsupported_profile("too short"));
+        }
+        if profile[8] != 2 && profile[8] != 4 {
+            return Err(unsupported_profile("unsupported version"));
+        }
+        let expected_color_space = if grayscale { b"GRAY" } else { b"RGB " };
+        if &profile[16..20] != expected_color_space {
+            return Err(unsupported_profile("wrong color space"));
+        }
+
+        if grayscale {
+            // Gray stays gray, so only the tone curve matters.
+            let curve = load_tone_curve(profile, b"kTRC")?;
+            return Ok(SourceColorSpace {
+                curves: [curve.clone(), curve.clone(), curve],
+                to_xyz: rgb_to_d50_xyz(&SRGB_CHROMATICITIES)?,
+            });
+        }
+
+        let mut to_xyz = [[0.0; 3]; 3];
+        for (column, tag) in [b"rXYZ", b"gXYZ", b"bXYZ"].iter().enumerate() {
+            let data = find_tag(profile, tag)?;
+            if data.len() < 20 || &data[0..4] != b"XYZ " {
+                return Err(unsupported_profile("invalid colorant"));
+            }
+            for (row, value) in data[8..20].chunks(4).enumerate() {
+                to_xyz[row][column] = s15_fixed_16(value)
+            }
+        }
+        Ok(SourceColorSpace {
+            curves: [
+                load_tone_curve(profile, b"rTRC")?,
+                load_tone_curve(profile, b"gTRC")?,
+                load_tone_curve(profile, b"bTRC")?,
+            ],
+            to_xyz,
+        })
+    }
+}
+
+/// The conversion from the color space of an image to a target color space that is performed
+/// during RGBA conversion: a tone curve per channel, a matrix, and then the sRGB transfer
+/// function.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ColorTransform {
+    /// The tone curves that decode the red, green, and blue samples of the image to linear
+    /// light.
+    pub curves: [ToneCurve; 3],
+    /// The matrix from linear RGB in the color space of the image to linear RGB in the target
+    /// color space.
+    pub matrix: Matrix,
+}
+
+impl ColorTransform {
+    /// Returns the transform from the given source color space to the given target color space,
+    /// or an error if the primaries of the target are degenerate.
+    pub fn new(
+        source: &SourceColorSpace,
+        target: &TargetColorSpace,
+    ) -> Result<ColorTransform, PngError> {
+        Ok(ColorTransform {
+            curves: source.curves.clone(),
+            matrix: multiply(&target.from_xyz()?, &source.to_xyz),
+        })
+    }
+}
+
+/// Returns the matrix from linear RGB with the given primaries and white point to CIE XYZ,
+/// adapted to D50 with the Bradford transform as ICC profiles are.
+fn rgb_to_d50_xyz(chromaticities: &Chromaticities) -> Result<Matrix, PngError> {
+    let xy_to_xyz = |(x, y): (u32, u32)| -> Result<[f64; 3], PngError> {
+        if y == 0 {
+            return Err(PngError::InvalidMetadata(
+                "chromaticity has a y of zero".to_owned(),
+            ));
+        }
+        let (x, y) = (x as f64 / 100000.0, y as f64 / 100000.0);
+        Ok([x / y, 1.0, (1.0 - x - y) / y])
+    };
+    let (red, green, blue) = (
+        xy_to_xyz(chromaticities.red)?,
+        xy_to_xyz(chromaticities.green)?,
+        xy_to_xyz(chromaticities.blue)?,
+    );
+    let white = xy_to_xyz(chromaticities.white_point)?;
+    let primaries = [
+        [red[0], green[0], blue[0]],
+        [red[1], green[1], blue[1]],
+        [red[2], green[2], blue[2]],
+    ];
+
+    // Scale the primaries such that full intensity of all three yields the white point.
+    let scale = apply(&invert(&primaries)?, &white);
+    let mut to_xyz = primaries;
+    for row in &mut to_xyz {
+        for (value, &scale) in row.iter_mut().zip(scale.iter()) {
+            *value *= scale
+        }
+    }
+
+    // Adapt the white point to D50.
+    let (source_cone, d50_cone) = (apply(&BRADFORD, &white), apply(&BRADFORD, &D50));
+    let mut cone_scale = [[0.0; 3]; 3];
+    for index in 0..3 {
+        cone_scale[index][index] = d50_cone[index] / source_cone[index]
+    }
+    let adaptation = multiply(&invert(&BRADFORD)?, &multiply(&cone_scale, &BRADFORD));
+    Ok(multiply(&adaptation, &to_xyz))
+}
+
+/// Finds the data of the tag with the given signature in an ICC profile.
+fn find_tag<'a>(profile: &'a [u8], signature: &[u8; 4]) -> Result<&'a [u8], PngError> {
+    let tag_count = BigEndian::read_u32(&profile[128..132]) as usize;
+    for index in 0..tag_count {
+        let entry_start = 132 + index * 12;
+        let entry = match profile.get(entry_start..(entry_start + 12)) {
+            Some(entry) => entry,
+            None => return Err(unsupported_profile("tag table too short")),
+        };
+        if &entry[0..4] != signature {
+            continue;
+        }
+        let offset = BigEndian::read_u32(&entry[4..8]) as usize;
+        let size = BigEndian::read_u32(&entry[8..12]) as usize;
+        return match offset.checked_add(size) {
+            Some(end) if end <= profile.len() => Ok(&profile[offset..end]),
+            _ => Err(unsupported_profile("tag out of bounds")),
+        };
+    }
+    Err(unsupported_profile("missing tag"))
+}
+
+/// Parses the `curv` or `para` tag with the given signature in an ICC profile.
+fn load_tone_curve(profile: &[u8], signature: &[u8; 4]) -> Result<ToneCurve, PngError> {
+    let data = find_tag(profile, signature)?;
+    if data.len() < 12 {
+        return Err(unsupported_profile("tone curve too short"));
+    }
+    match &data[0..4] {
+        b"curv" => {
+            let count = BigEndian::read_u32(&data[8..12]) as usize;
+            let entries = match count
+                .checked_mul(2)
+                .and_then(|size| data.get(12..(12 + size)))
+            {
+                Some(entries) => entries,
+                None => return Err(unsupported_profile("tone curve too short")),
+            };
+            match count {
+                0 => Ok(ToneCurve::Power(1.0)),
+                1 => Ok(ToneCurve::Power(
+                    BigEndian::read_u16(entries) as f64 / 256.0,
+                )),
+                _ => Ok(ToneCurve::Table(
+                    entries.chunks(2).map(BigEndian::read_u16).collect(),
+                )),
+            }
+        }
+        b"para" => {
+            let parameter_count = match BigEndian::read_u16(&data[8..10]) {
+                0 => 1,
+                1 => 3,
+                2 => 4,
+                3 => 5,
+                4 => 7,
+                _ => return Err(unsupported_profile("unknown parametric curve")),
+            };
+            let parameters: Vec<f64> = match data.get(12..(12 + parameter_count * 4)) {
+                Some(parameters) => parameters.chunks(4).map(s15_fixed_16).collect(),
+                None => return Err(unsupported_profile("tone curve too short")),
+            };
+            // Express every function type as the most general one, type 4.
+            let (g, a, b) = (
+                parameters[0],
+                *parameters.get(1).unwrap_or(&1.0),
+                *parameters.get(2).unwrap_or(&0.0),
+            );
+            let threshold = if a != 0.0 { -b / a } else { 0.0 };
+            Ok(ToneCurve::Parametric(match parameter_count {
+                1 => [g, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+                3 => [g, a, b, 0.0, threshold, 0.0, 0.0],
+                4 => [g, a, b, 0.0, threshold, parameters[3], parameters[3]],
+                5 => [g, a, b, parameters[3], parameters[4], 0.0, 0.0],
+                _ => [
+                    g,
+                    a,
+                    b,
+                    parameters[3],
+                    parameters[4],
+                    parameters[5],
+                    parameters[6],
+                ],
+            }))
+        }
+        _ => Err(unsupported_profile("unknown tone curve type")),
+    }
+}
+
+fn unsupported_profile(reason: &str) -> PngError {
+    PngError::InvalidMetadata(format!("unsupported ICC profile: {}", reason))
+}
+
+/// Decodes an ICC `s15Fixed16Number`.
+fn s15_fixed_16(data: &[u8]) -> f64 {
+    BigEndian::read_i32(data) as f64 / 65536.0
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut product = [[0.0; 3]; 3];
+    for row in 0..3 {
+        for column in 0..3 {
+            product[row][column] = (0..3).map(|index| a[row][index] * b[index][column]).sum()
+        }
+    }
+    product
+}
+
+fn apply(matrix: &Matrix, vector: &[f64; 3]) -> [f64; 3] {
+    let mut result = [0.0; 3];
+    for (row, result) in matrix.iter().zip(result.iter_mut()) {
+        *result = row.iter().zip(vector.iter()).map(|(a, b)| a * b).sum()
+    }
+    result
+}
+
+fn invert(m: &Matrix) -> Result<Matrix, PngError> {
+    let cofactor = |row: usize, column: usize| {
+        let (r0, r1) = ((row + 1) % 3, (row + 2) % 3);
+        let (c0, c1) = ((column + 1) % 3, (column + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let determinant: f64 = (0..3)
+        .map(|column| m[0][column] * cofactor(0, column))
+        .sum();
+    if determinant.abs() < 1e-12 {
+        return Err(PngError::InvalidMetadata(
+            "primaries are degenerate".to_owned(),
+        ));
+    }
+    let mut inverse = [[0.0; 3]; 3];
+    for (row, inverse_row) in inverse.iter_mut().enumerate() {
+        for (column, value) in inverse_row.iter_mut().enumerate() {
+            *value = cofactor(column, row) / determinant
+        }
+    }
+    Ok(inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_matrix_close(actual: &Matrix, expected: &Matrix, tolerance: f64) {
+        for (actual_row, expected_row) in actual.iter().zip(expected.iter()) {
+            for (actual, expected) in actual_row.iter().zip(expected_row.iter()) {
+                assert!(
+                    (actual - expected).abs() < tolerance,
+                    "expected {:?}, got {:?}",
+                    expected_row,
+                    actual_row
+                );
+            }
+        }
+    }
+
+    const IDENTITY: Matrix = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+    /// Builds an ICC profile with the given color space and tags.
+    fn profile(color_space: &[u8; 4], tags: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut profile = vec![0; 128];
+        profile[8] = 2;
+        profile[16..20].copy_from_slice(color_space);
+        profile.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+        let mut offset = profile.len() + 12 * tags.len();
+        let mut data = vec![];
+        for &(signature, ref tag) in tags {
+            profile.extend_from_slice(&signature[..]);
+            profile.extend_from_slice(&(offset as u32).to_be_bytes());
+            profile.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+            data.extend_from_slice(tag);
+            offset += tag.len();
+        }
+        profile.extend_from_slice(&data);
+        profile
+    }
+
+    fn xyz_tag(xyz: [f64; 3]) -> Vec<u8> {
+        let mut tag = b"XYZ \0\0\0\0".to_vec();
+        for &value in &xyz {
+            tag.extend_from_slice(&((value * 65536.0).round() as i32).to_be_bytes())
+        }
+        tag
+    }
+
+    #[test]
+    fn invert_matrices() {
+        let matrix = [[2.0, 0.0, 1.0], [1.0, 3.0, 0.0], [0.0, 1.0, 4.0]];
+        let inverse = invert(&matrix).unwrap();
+        assert_matrix_close(&multiply(&matrix, &inverse), &IDENTITY, 1e-12);
+        assert_matrix_close(&multiply(&inverse, &matrix), &IDENTITY, 1e-12);
+
+        let singular = [[1.0, 2.0, 3.0], [2.0, 4.0, 6.0], [0.0, 1.0, 1.0]];
+        assert!(invert(&singular).is_err());
+    }
+
+    #[test]
+    fn srgb_primaries_adapted_to_d50() {
+        // The colorants of the sRGB ICC profile.
+        let expected = [
+            [0.4361, 0.3851, 0.1431],
+            [0.2225, 0.7169, 0.0606],
+            [0.0139, 0.0971, 0.7141],
+        ];
+        let to_xyz = rgb_to_d50_xyz(&SRGB_CHROMATICITIES).unwrap();
+        assert_matrix_close(&to_xyz, &expected, 1e-3);
+        let white = apply(&to_xyz, &[1.0, 1.0, 1.0]);
+        for (white, d50) in white.iter().zip(D50.iter()) {
+            assert!((white - d50).abs() < 1e-9);
+        }
+
+        let transform = ColorTransform::new(&SourceColorSpace::srgb(), &TargetColorSpace::Srgb);
+        assert_matrix_close(&transform.unwrap().matrix, &IDENTITY, 1e-9);
+    }
+
+    #[test]
+    fn degenerate_primaries() {
+        let mut chromaticities = SRGB_CHROMATICITIES;
+        chromaticities.blue = chromaticities.red;
+        assert!(TargetColorSpace::Primaries(chromaticities)
+            .from_xyz()
+            .is_err());
+        chromaticities.blue = (15000, 0);
+        assert!(TargetColorSpace::Primaries(chromaticities)
+            .from_xyz()
+            .is_err());
+        assert!(TargetColorSpace::Srgb.from_xyz().is_ok());
+    }
+
+    #[test]
+    fn matrix_trc_profile() {
+        let colorants = [
+            [0.4361, 0.2225, 0.0139],
+            [0.3851, 0.7169, 0.0971],
+            [0.1431, 0.0606, 0.7141],
+        ];
+        // A gamma of 2.2 in 8.8 fixed point, the sRGB curve as a type 3 parametric curve, and a
+        // linear table.
+        let gamma = b"curv\0\0\0\0\0\0\0\x01\x02\x33".to_vec();
+        let mut srgb = b"para\0\0\0\0\0\x03\0\0".to_vec();
+        for &value in &[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045] {
+            srgb.extend_from_slice(&((value * 65536.0f64).round() as i32).to_be_bytes())
+        }
+        let table = b"curv\0\0\0\0\0\0\0\x03\0\0\x80\0\xff\xff".to_vec();
+        let data = profile(
+            b"RGB ",
+            &[
+                (b"rXYZ", xyz_tag(colorants[0])),
+                (b"gXYZ", xyz_tag(colorants[1])),
+                (b"bXYZ", xyz_tag(colorants[2])),
+                (b"rTRC", gamma),
+                (b"gTRC", srgb),
+                (b"bTRC", table),
+            ],
+        );
+        let color_space = SourceColorSpace::from_icc_profile(&data, false).unwrap();
+
+        for (column, colorant) in colorants.iter().enumerate() {
+            for (row, &value) in colorant.iter().enumerate() {
+                assert!((color_space.to_xyz[row][column] - value).abs() < 1e-4);
+            }
+        }
+        assert_eq!(color_space.curves[0], ToneCurve::Power(563.0 / 256.0));
+        for &value in &[0.0, 0.02, 0.04045, 0.2, 0.5, 1.0] {
+            let expected = ToneCurve::Srgb.to_linear(value);
+            assert!((color_space.curves[1].to_linear(value) - expected).abs() < 1e-4);
+        }
+        assert_eq!(
+            color_space.curves[2],
+            ToneCurve::Table(vec![0, 0x8000, 0xffff])
+        );
+        assert!((color_space.curves[2].to_linear(0.25) - 0x4000 as f64 / 65535.0).abs() < 1e-9);
+
+        // The same profile doesn't describe a grayscale image.
+        assert!(SourceColorSpace::from_icc_profile(&data, true).is_err());
+        assert!(SourceColorSpace::from_icc_profile(&data[0..131], false).is_err());
+    }
+
+    #[test]
+    fn grayscale_profile() {
+        let data = profile(b"GRAY", &[(b"kTRC", b"curv\0\0\0\0\0\0\0\0".to_vec())]);
+        let color_space = SourceColorSpace::from_icc_profile(&data, true).unwrap();
+        assert_eq!(color_space.curves[0], ToneCurve::Power(1.0));
+        assert!(SourceColorSpace::from_icc_profile(&data, false).is_err());
+
+        // A profile whose only tag runs past its end.
+        let mut truncated = data.clone();
+        truncated.truncate(data.len() - 1);
+        assert!(SourceColorSpace::from_icc_profile(&truncated, true).is_err());
+    }
+}
@@ -8,12 +8,17 @@
 //! the image is in the process of decoding via the `DataProvider` trait. This trait also allows
 //! for complete control over the layout and storage of image data in memory.
 
-use crate::metadata::{ChunkHeader, ColorType, InterlaceMethod, Metadata};
+use crate::colortransform::{ColorTransform, SourceColorSpace, TargetColorSpace};
+use crate::metadata::{AnimationControl, Chromaticities, ChunkHeader, CodingIndependentCodePoints};
+use crate::metadata::{ColorManagement, ColorType, ContentLightLevel, CrcPolicy, Dimensions, Exif};
+use crate::metadata::{FrameControl, Gamma, IccProfile, InterlaceMethod};
+use crate::metadata::{MasteringDisplayColorVolume, Metadata, PhysicalDimensions, PhysicalScale};
+use crate::metadata::{RenderingIntent, SignificantBits, TextChunk};
 use crate::prediction::{MainThreadToPredictorThreadComm, MainThreadToPredictorThreadMsg};
 use crate::prediction::{PerformRgbaConversionRequest, PredictionRequest, Predictor};
-use crate::prediction::{PredictorThreadToMainThreadMsg, ScanlineToPredict};
+use crate::prediction::{PredictorThreadToMainThreadMsg, ScanlineToPredict, TransferFunction};
 use crate::PngError;
-use byteorder::{self, ReadBytesExt};
+use byteorder::{self, BigEndian, ByteOrder, ReadBytesExt};
 use flate2::*;
 use libc::c_int;
 use std::cmp;
@@ -23,7 +28,16 @@ use std::mem;
 const BUFFER_SIZE: usize = 16384;
 const PIXELS_PER_PREDICTION_CHUNK: u32 = 1024;
 
+/// The gamma of the sRGB color space, approximated as a simple power function.
+const SRGB_GAMMA: f64 = 0.45455;
+/// Gamma correction is skipped if it would change samples by less than this exponent.
+const GAMMA_THRESHOLD: f64 = 0.01;
+
 /// An object that encapsulates the load process for a single image.
+///
+/// The options that determine what the data provider receives, such as the output format, the
+/// channel order, and the postprocessing performed during RGBA conversion, must be set before the
+/// data provider is attached.
 pub struct ImageLoader {
     entropy_decoder: Decompress,
     metadata: Option<Metadata>,
@@ -31,6 +45,10 @@ pub struct ImageLoader {
     compressed_data_consumed: usize,
     palette: Vec<u8>,
     transparency: Transparency,
+    background: Option<Background>,
+    physical_dimensions: Option<PhysicalDimensions>,
+    physical_scale: Option<PhysicalScale>,
+    significant_bits: Option<SignificantBits>,
     scanline_data_buffer: Vec<u8>,
     scanline_data_buffer_size: usize,
     cached_scanline_data_buffers: Vec<Vec<u8>>,
@@ -48,6 +66,46 @@ pub struct ImageLoader {
 
     predictor_thread_comm: MainThreadToPredictorThreadComm,
     have_data_provider: bool,
+
+    /// The `acTL` chunk, if this is an animated image.
+    animation_control: Option<AnimationControl>,
+    /// The `fcTL` chunk describing the frame currently being decoded, if any.
+    frame_control: Option<FrameControl>,
+    decode_animation: bool,
+    /// The number of images whose decoding has begun, not counting the default image. This is
+    /// therefore 0 while decoding the default image.
+    frame_number: u32,
+    /// True if we've seen any image data (`IDAT` or `fdAT`) for the current image.
+    image_data_seen: bool,
+    text_chunks: Vec<TextChunk>,
+    exif: Option<Exif>,
+    color_management: ColorManagement,
+    /// The color space of the image, if its color-management chunks describe one that we
+    /// support.
+    source_color_space: Option<SourceColorSpace>,
+    /// True if the color space of the image is sRGB, in which case it needn't be converted to
+    /// sRGB.
+    source_is_srgb: bool,
+    display_exponent: Option<f64>,
+    premultiply_alpha: bool,
+    channel_order: ChannelOrder,
+    output_format: OutputFormat,
+    linear_light: bool,
+    preserve_grayscale: bool,
+    preserve_indexed: bool,
+    dither: bool,
+    flatten_alpha: Option<FlattenBackground>,
+    rescale_significant_bits: bool,
+    color_transform_target: Option<TargetColorSpace>,
+
+    crc_policy: CrcPolicy,
+    ancillary_crc_policy: CrcPolicy,
+    /// The header and stream offset of the chunk currently being read, for checksum verification.
+    current_chunk: ChunkHeader,
+    current_chunk_offset: u64,
+    /// The running checksum of the type and data of the chunk currently being read.
+    current_chunk_crc: Crc,
+    warnings: Vec<PngError>,
 }
 
 impl ImageLoader {
@@ -60,6 +118,10 @@ impl ImageLoader {
             compressed_data_consumed: 0,
             palette: vec![],
             transparency: Transparency::None,
+            background: None,
+            physical_dimensions: None,
+            physical_scale: None,
+            significant_bits: None,
             scanline_data_buffer: vec![],
             scanline_data_buffer_size: 0,
             scanline_data_buffer_info: vec![],
@@ -72,6 +134,36 @@ impl ImageLoader {
             decode_state: DecodeState::Start,
             predictor_thread_comm: MainThreadToPredictorThreadComm::new(),
             have_data_provider: false,
+            animation_control: None,
+            frame_control: None,
+            decode_animation: false,
+            frame_number: 0,
+            image_data_seen: false,
+            text_chunks: vec![],
+            exif: None,
+            color_management: ColorManagement::default(),
+            source_color_space: None,
+            source_is_srgb: false,
+            display_exponent: None,
+            premultiply_alpha: false,
+            channel_order: ChannelOrder::Rgba,
+            output_format: OutputFormat::Rgba,
+            linear_light: false,
+            preserve_grayscale: false,
+            preserve_indexed: false,
+            dither: false,
+            flatten_alpha: None,
+            rescale_significant_bits: false,
+            color_transform_target: None,
+            crc_policy: CrcPolicy::Error,
+            ancillary_crc_policy: CrcPolicy::Ignore,
+            current_chunk: ChunkHeader {
+                length: 0,
+                chunk_type: [0; 4],
+            },
+            current_chunk_offset: 0,
+            current_chunk_crc: Crc::new(),
+            warnings: vec![],
         }
     }
 
@@ -80,10 +172,13 @@ impl ImageLoader {
     /// This method decodes an arbitrary amount of data, so repeated calls to it are necessary to
     /// decode the entire image.
     ///
-    /// If the metadata has been read (which is checkable ether via `ImageLoader::metadata()` or by
-    /// looking for a `LoadProgress::NeedDataProviderAndMoreData` result), a data provider must
-    /// have be attached to this image loader via `ImageLoader::set_data_provider()` before calling
-    /// this method, or this function will fail with a `PngError::NoDataProvider` error.
+    /// If this method has returned `LoadProgress::NeedDataProviderAndMoreData`, a data provider
+    /// must be attached to this image loader via `ImageLoader::set_data_provider()` before calling
+    /// this method again, or this function will fail with a `PngError::NoDataProvider` error.
+    /// That result is returned upon reaching the image data, so by then all the chunks that
+    /// precede it have been read, and the metadata that they carry, such as
+    /// `ImageLoader::palette()` or `ImageLoader::physical_dimensions()`, is complete. Layout can
+    /// thus begin before the pixels are decoded.
     ///
     /// Returns a `LoadProgress` value that describes the progress of loading the image.
     #[inline(never)]
@@ -97,13 +192,12 @@ impl ImageLoader {
             }
 
             match self.decode_state {
-                DecodeState::Start => match Metadata::load(reader) {
+                DecodeState::Start => match Metadata::load_with_crc_policy(
+                    reader,
+                    self.crc_policy,
+                    &mut self.warnings,
+                ) {
                     Ok(metadata) => {
-                        self.current_lod = match metadata.interlace_method {
-                            InterlaceMethod::Adam7 => LevelOfDetail::Adam7(0),
-                            InterlaceMethod::Disabled => LevelOfDetail::None,
-                        };
-
                         self.decode_state = if metadata.color_type == ColorType::Indexed {
                             DecodeState::LookingForPalette
                         } else {
@@ -111,61 +205,99 @@ impl ImageLoader {
                         };
 
                         self.metadata = Some(metadata);
-
-                        return if self.have_data_provider {
-                            Ok(LoadProgress::NeedMoreData)
-                        } else {
-                            Ok(LoadProgress::NeedDataProviderAndMoreData)
-                        };
+                        self.current_lod = self.first_level_of_detail();
                     }
                     Err(error) => return Err(error),
                 },
                 DecodeState::LookingForPalette => {
+                    let chunk_offset = reader.stream_position().map_err(PngError::Io)?;
                     let chunk_header = match ChunkHeader::load(reader) {
                         Err(error) => return Err(error),
                         Ok(chunk_header) => chunk_header,
                     };
+                    self.begin_chunk(&chunk_header, chunk_offset);
                     if &chunk_header.chunk_type == b"PLTE" {
                         self.decode_state = DecodeState::ReadingPalette(chunk_header.length);
                     } else {
-                        // Skip over this chunk, adding 4 to move past the CRC.
-                        reader
-                            .seek(SeekFrom::Current((chunk_header.length as i64) + 4))
-                            .map_err(PngError::Io)?;
+                        match self.read_ancillary_chunk(reader)? {
+                            AncillaryChunkProgress::Read => {}
+                            AncillaryChunkProgress::Unknown => self.skip_chunk(reader)?,
+                            AncillaryChunkProgress::NeedMoreData => {
+                                return Ok(LoadProgress::NeedMoreData)
+                            }
+                        }
                     }
                 }
                 DecodeState::LookingForImageData => {
+                    let chunk_offset = reader.stream_position().map_err(PngError::Io)?;
                     let chunk_header = match ChunkHeader::load(reader) {
                         Err(error) => return Err(error),
                         Ok(chunk_header) => chunk_header,
                     };
+                    self.begin_chunk(&chunk_header, chunk_offset);
 
-                    if &chunk_header.chunk_type == b"IDAT" {
+                    if &chunk_header.chunk_type == b"IDAT" && self.frame_number == 0 {
+                        self.image_data_seen = true;
                         self.decode_state = DecodeState::DecodingData(chunk_header.length);
-                    } else if &chunk_header.chunk_type == b"IEND" {
-                        if self
-                            .metadata
-                            .as_ref()
-                            .expect("No metadata before `IEND`?!")
-                            .color_depth
-                            != 32
-                        {
-                            self.send_scanlines_to_predictor_thread_to_convert_to_rgba()
+                        if !self.have_data_provider {
+                            return Ok(LoadProgress::NeedDataProviderAndMoreData);
                         }
-
-                        self.predictor_thread_comm
-                            .sender
-                            .send(MainThreadToPredictorThreadMsg::Finished)
-                            .unwrap();
-
+                    } else if &chunk_header.chunk_type == b"fdAT"
+                        && self.decode_animation
+                        && self.frame_number > 0
+                    {
+                        // Skip over the sequence number. The rest of the chunk is just like
+                        // `IDAT`.
+                        let sequence_number =
+                            reader.read_u32::<BigEndian>().map_err(PngError::Io)?;
+                        self.current_chunk_crc.update(&[
+                            (sequence_number >> 24) as u8,
+                            (sequence_number >> 16) as u8,
+                            (sequence_number >> 8) as u8,
+                            sequence_number as u8,
+                        ]);
+                        let length =
+                            chunk_header
+                                .length
+                                .checked_sub(4)
+                                .ok_or(PngError::InvalidMetadata(
+                                    "`fdAT` chunk too short".to_owned(),
+                                ))?;
+
+                        self.image_data_seen = true;
+                        self.decode_state = DecodeState::DecodingData(length);
+                        if !self.have_data_provider {
+                            return Ok(LoadProgress::NeedDataProviderAndMoreData);
+                        }
+                    } else if &chunk_header.chunk_type == b"fcTL"
+                        && self.decode_animation
+                        && self.image_data_seen
+                    {
+                        // This marks the beginning of the next frame, so finish up the current
+                        // one. We have to wait for the predictor thread to finish it, as it
+                        // shares the decoding state with the next frame.
+                        let data = match self.read_chunk_data(reader)? {
+                            Some(data) => data,
+                            None => return Ok(LoadProgress::NeedMoreData),
+                        };
+                        let frame_control = self.load_frame_control(&data)?;
+                        self.finish_image();
+                        self.wait_until_finished()?;
+                        self.start_frame(frame_control);
+                    } else if &chunk_header.chunk_type == b"IEND" {
+                        self.finish_chunk(reader)?;
+                        self.finish_image();
                         self.decode_state = DecodeState::Finished
                     } else if &chunk_header.chunk_type == b"tRNS" {
                         self.decode_state = DecodeState::ReadingTransparency(chunk_header.length)
                     } else {
-                        // Skip over this chunk, adding 4 to move past the CRC.
-                        reader
-                            .seek(SeekFrom::Current((chunk_header.length as i64) + 4))
-                            .map_err(PngError::Io)?;
+                        match self.read_ancillary_chunk(reader)? {
+                            AncillaryChunkProgress::Read => {}
+                            AncillaryChunkProgress::Unknown => self.skip_chunk(reader)?,
+                            AncillaryChunkProgress::NeedMoreData => {
+                                return Ok(LoadProgress::NeedMoreData)
+                            }
+                        }
                     }
                 }
                 DecodeState::ReadingPalette(mut bytes_left_in_chunk) => {
@@ -177,13 +309,14 @@ impl ImageLoader {
                         .map_err(PngError::Io)?;
                     bytes_left_in_chunk -= bytes_read as u32;
                     self.palette.truncate(original_palette_size + bytes_read);
+                    self.current_chunk_crc
+                        .update(&self.palette[original_palette_size..]);
                     if bytes_left_in_chunk > 0 {
                         self.decode_state = DecodeState::ReadingPalette(bytes_left_in_chunk);
                         continue;
                     }
 
-                    // Move past the CRC.
-                    reader.seek(SeekFrom::Current(4)).map_err(PngError::Io)?;
+                    self.finish_chunk(reader)?;
 
                     // Start looking for the image data.
                     self.decode_state = DecodeState::LookingForImageData
@@ -193,13 +326,10 @@ impl ImageLoader {
                         return Err(PngError::NoDataProvider);
                     }
 
-                    let (width, color_depth) = {
-                        let metadata = self.metadata.as_ref().expect("No metadata?!");
-                        (metadata.dimensions.width, metadata.color_depth)
-                    };
-                    let _bytes_per_pixel = (color_depth / 8) as u32;
-                    let stride = InterlacingInfo::new(0, color_depth, self.current_lod)
-                        .scanline_stride(width, color_depth);
+                    let width = self.dimensions().width;
+                    let color_depth = self.metadata.as_ref().expect("No metadata?!").color_depth;
+                    let stride =
+                        InterlacingInfo::scanline_stride(width, color_depth, self.current_lod);
 
                     // Wait for the predictor thread to catch up if necessary.
                     let scanlines_to_buffer = self.scanlines_to_buffer();
@@ -217,10 +347,12 @@ impl ImageLoader {
                         bytes_read =
                             reader.read(&mut self.compressed_data_buffer[original_length..])?;
                         debug_assert!(
-                            self.compressed_data_buffer.len() <= original_length + bytes_read
+                            original_length + bytes_read <= self.compressed_data_buffer.len()
                         );
                         self.compressed_data_buffer
                             .truncate(original_length + bytes_read);
+                        self.current_chunk_crc
+                            .update(&self.compressed_data_buffer[original_length..]);
                     } else {
                         bytes_read = 0
                     }
@@ -233,7 +365,11 @@ impl ImageLoader {
                     // TODO(pcwalton): This may well show up in profiles. Probably we are going
                     // to want to read multiple scanlines at once. Before we do this, though,
                     // we are going to have to deal with SSE alignment restrictions.
-                    if avail_in == 0 {
+                    //
+                    // Note that even if we have no more input in this chunk, the entropy decoder
+                    // may still be holding on to decompressed data, so we have to keep calling it
+                    // until it stops producing output.
+                    if avail_in == 0 && bytes_left_in_chunk > 0 {
                         return Ok(LoadProgress::NeedMoreData);
                     }
 
@@ -270,8 +406,9 @@ impl ImageLoader {
                     }
 
                     // Advance the decompressed data offset.
-                    self.scanline_data_buffer_size = original_size
-                        + (self.entropy_decoder.total_out() - before_decompression_out) as usize;
+                    let bytes_decompressed =
+                        (self.entropy_decoder.total_out() - before_decompression_out) as usize;
+                    self.scanline_data_buffer_size = original_size + bytes_decompressed;
 
                     // Save the buffer and advance the Y position if necessary.
                     if self.scanline_data_buffer_size == 1 + stride as usize {
@@ -289,20 +426,15 @@ impl ImageLoader {
                             y: self.current_y,
                         });
                         self.current_y += 1;
-                        let height = self
-                            .metadata
-                            .as_ref()
-                            .expect("No metadata?!")
-                            .dimensions
-                            .height;
+                        let dimensions = self.dimensions();
                         let height_of_lod =
-                            InterlacingInfo::height_of_lod(height, self.current_lod);
-                        if self.current_y == height_of_lod && !self.finished_entropy_decoding() {
-                            self.current_y = 0;
-                            if let LevelOfDetail::Adam7(ref mut current_lod) = self.current_lod {
-                                if *current_lod < 6 {
-                                    *current_lod += 1
-                                }
+                            InterlacingInfo::height_of_lod(dimensions.height, self.current_lod);
+                        if self.current_y == height_of_lod {
+                            // Passes with no pixels in them are omitted from the data stream
+                            // entirely, so skip over them.
+                            if let Some(next_lod) = self.current_lod.next(dimensions) {
+                                self.current_y = 0;
+                                self.current_lod = next_lod
                             }
                         }
 
@@ -312,47 +444,52 @@ impl ImageLoader {
                     let bytes_left_in_chunk_after_read = bytes_left_in_chunk - bytes_read as u32;
                     self.decode_state = if bytes_left_in_chunk_after_read == 0
                         && self.compressed_data_consumed >= self.compressed_data_buffer.len()
+                        && bytes_decompressed < avail_out
                     {
-                        // Skip over the CRC.
-                        reader.seek(SeekFrom::Current(4)).map_err(PngError::Io)?;
+                        self.finish_chunk(reader)?;
                         DecodeState::LookingForImageData
                     } else {
                         DecodeState::DecodingData(bytes_left_in_chunk_after_read)
                     }
                 }
                 DecodeState::ReadingTransparency(mut bytes_left_in_chunk) => {
-                    let initial_pos = reader.seek(SeekFrom::Current(0)).map_err(PngError::Io)?;
-                    match self
+                    let initial_pos = reader.stream_position().map_err(PngError::Io)?;
+                    let color_type = self
                         .metadata
                         .as_ref()
                         .expect("No metadata before transparency info?!")
-                        .color_type
-                    {
-                        ColorType::Grayscale => match reader.read_u8() {
-                            Ok(value) => {
-                                self.transparency = Transparency::MagicColor(value, value, value)
-                            }
-                            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                                reader
-                                    .seek(SeekFrom::Start(initial_pos))
-                                    .map_err(PngError::Io)?;
-                                return Ok(LoadProgress::NeedMoreData);
+                        .color_type;
+                    match color_type {
+                        ColorType::Grayscale | ColorType::Rgb => {
+                            // The length comes from the file, so make sure that it's plausible
+                            // before reading anything.
+                            let mut data = [0; 6];
+                            if bytes_left_in_chunk as usize > data.len() {
+                                self.warnings.push(PngError::InvalidMetadata(format!(
+                                    "`tRNS` chunk is {} bytes long, but should be at most {}",
+                                    bytes_left_in_chunk,
+                                    data.len()
+                                )));
+                                self.skip_chunk(reader)?;
+                                self.decode_state = DecodeState::LookingForImageData;
+                                continue;
                             }
-                            Err(io_error) => return Err(PngError::Io(io_error)),
-                        },
-                        ColorType::Rgb => {
-                            let mut buffer = [0, 0, 0];
-                            match reader.read(&mut buffer[..]) {
-                                Ok(3) => {
-                                    self.transparency =
-                                        Transparency::MagicColor(buffer[0], buffer[1], buffer[2])
-                                }
-                                Ok(_) => {
-                                    reader.seek(SeekFrom::Start(initial_pos))?;
+                            let data = &mut data[0..(bytes_left_in_chunk as usize)];
+                            match reader.read_exact(data) {
+                                Ok(()) => {}
+                                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                                    reader
+                                        .seek(SeekFrom::Start(initial_pos))
+                                        .map_err(PngError::Io)?;
                                     return Ok(LoadProgress::NeedMoreData);
                                 }
                                 Err(io_error) => return Err(PngError::Io(io_error)),
                             }
+                            self.current_chunk_crc.update(&data[..]);
+                            match Transparency::load_magic_color(color_type, &data[..]) {
+                                Ok(transparency) => self.transparency = transparency,
+                                Err(error) => self.warnings.push(error),
+                            }
                         }
                         ColorType::Indexed => {
                             if let Transparency::None = self.transparency {
@@ -371,6 +508,8 @@ impl ImageLoader {
                                 reader.read(&mut transparency[original_transparency_size..])?;
                             bytes_left_in_chunk -= bytes_read as u32;
                             transparency.truncate(original_transparency_size + bytes_read);
+                            self.current_chunk_crc
+                                .update(&transparency[original_transparency_size..]);
                             if bytes_left_in_chunk > 0 {
                                 self.decode_state =
                                     DecodeState::ReadingTransparency(bytes_left_in_chunk);
@@ -378,12 +517,18 @@ impl ImageLoader {
                             }
                         }
                         ColorType::GrayscaleAlpha | ColorType::RgbAlpha => {
-                            panic!("Shouldn't be reading a `tRNS` chunk with an alpha color type!")
+                            // Images with an alpha channel can't have a `tRNS` chunk, but one
+                            // shouldn't stop them from loading either.
+                            self.warnings.push(PngError::InvalidMetadata(
+                                "`tRNS` chunk in an image with an alpha channel".to_owned(),
+                            ));
+                            self.skip_chunk(reader)?;
+                            self.decode_state = DecodeState::LookingForImageData;
+                            continue;
                         }
                     }
 
-                    // Move past the CRC.
-                    reader.seek(SeekFrom::Current(4))?;
+                    self.finish_chunk(reader)?;
 
                     // Keep looking for image data (although we should be done by now).
                     self.decode_state = DecodeState::LookingForImageData
@@ -393,14 +538,318 @@ impl ImageLoader {
         }
     }
 
+    /// Reads and parses an ancillary chunk, if it's one that we know about. Returns
+    /// `AncillaryChunkProgress::Unknown`, having read nothing, if we don't know about it, and
+    /// `AncillaryChunkProgress::NeedMoreData` if it hasn't all arrived yet, as
+    /// `ImageLoader::read_chunk_data()` describes.
+    ///
+    /// Ancillary chunks are optional, so a malformed one doesn't stop the image from loading: it's
+    /// recorded in `ImageLoader::warnings()` and otherwise ignored. The exceptions are the
+    /// animation chunks, without which the frames can't be decoded.
+    fn read_ancillary_chunk<R>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<AncillaryChunkProgress, PngError>
+    where
+        R: Read + Seek,
+    {
+        let chunk_type = self.current_chunk.chunk_type;
+        match (&chunk_type, self.image_data_seen) {
+            (b"acTL", false)
+            | (b"fcTL", false)
+            | (b"gAMA", false)
+            | (b"cHRM", false)
+            | (b"sRGB", false)
+            | (b"iCCP", false)
+            | (b"cICP", false)
+            | (b"mDCv", false)
+            | (b"cLLi", false)
+            | (b"bKGD", false)
+            | (b"sBIT", false)
+            | (b"pHYs", false)
+            | (b"sCAL", false)
+            | (b"tEXt", _)
+            | (b"zTXt", _)
+            | (b"iTXt", _)
+            | (b"eXIf", _) => {}
+            _ => return Ok(AncillaryChunkProgress::Unknown),
+        }
+
+        let data = match self.read_chunk_data(reader)? {
+            Some(data) => data,
+            None => return Ok(AncillaryChunkProgress::NeedMoreData),
+        };
+        match &chunk_type {
+            b"acTL" => self.animation_control = Some(AnimationControl::load(&mut &data[..])?),
+            b"fcTL" => {
+                // This describes the first frame of the animation, which is the default image.
+                self.frame_control = Some(self.load_frame_control(&data)?);
+            }
+            b"gAMA" | b"cHRM" | b"sRGB" | b"iCCP" | b"cICP" | b"mDCv" | b"cLLi" => {
+                if let Err(error) = self.parse_color_management_chunk(&data) {
+                    self.warnings.push(error)
+                }
+                let color_type = self.metadata.as_ref().expect("No metadata?!").color_type;
+                let grayscale =
+                    color_type == ColorType::Grayscale || color_type == ColorType::GrayscaleAlpha;
+                self.source_color_space =
+                    SourceColorSpace::from_color_management(&self.color_management, grayscale);
+                // Building the sRGB color space takes some arithmetic, so compare against it here
+                // rather than every time we check whether to transform colors.
+                self.source_is_srgb = self.source_color_space == Some(SourceColorSpace::srgb());
+            }
+            b"bKGD" => {
+                let color_type = self.metadata.as_ref().expect("No metadata?!").color_type;
+                match Background::load(color_type, &data) {
+                    Ok(background) => self.background = Some(background),
+                    Err(error) => self.warnings.push(error),
+                }
+            }
+            b"sBIT" => {
+                let (color_type, bit_depth) = {
+                    let metadata = self.metadata.as_ref().expect("No metadata?!");
+                    (metadata.color_type, metadata.bit_depth)
+                };
+                match SignificantBits::load(color_type, bit_depth, &data) {
+                    Ok(significant_bits) => self.significant_bits = Some(significant_bits),
+                    Err(error) => self.warnings.push(error),
+                }
+            }
+            b"pHYs" => match PhysicalDimensions::load(&mut &data[..]) {
+                Ok(physical_dimensions) => self.physical_dimensions = Some(physical_dimensions),
+                Err(error) => self.warnings.push(error),
+            },
+            b"sCAL" => match PhysicalScale::load(&data) {
+                Ok(physical_scale) => self.physical_scale = Some(physical_scale),
+                Err(error) => self.warnings.push(error),
+            },
+            b"tEXt" | b"zTXt" | b"iTXt" => match TextChunk::load(&chunk_type, &data) {
+                Ok(text_chunk) => self.text_chunks.push(text_chunk),
+                Err(error) => self.warnings.push(error),
+            },
+            b"eXIf" => {
+                // Only the first `eXIf` chunk counts.
+                if self.exif.is_none() {
+                    match Exif::load(&data) {
+                        Ok(exif) => self.exif = Some(exif),
+                        Err(error) => self.warnings.push(error),
+                    }
+                }
+            }
+            _ => panic!("Not an ancillary chunk that we know about?!"),
+        }
+        Ok(AncillaryChunkProgress::Read)
+    }
+
+    fn parse_color_management_chunk(&mut self, data: &[u8]) -> Result<(), PngError> {
+        let color_management = &mut self.color_management;
+        match &self.current_chunk.chunk_type {
+            b"gAMA" => color_management.gamma = Some(Gamma::load(&mut &data[..])?),
+            b"cHRM" => {
+                color_management.chromaticities = Some(Chromaticities::load(&mut &data[..])?)
+            }
+            b"sRGB" => {
+                color_management.srgb_rendering_intent =
+                    Some(RenderingIntent::load(&mut &data[..])?)
+            }
+            b"iCCP" => color_management.icc_profile = Some(IccProfile::load(data)?),
+            b"cICP" => {
+                color_management.cicp = Some(CodingIndependentCodePoints::load(&mut &data[..])?)
+            }
+            b"mDCv" => {
+                color_management.mastering_display =
+                    Some(MasteringDisplayColorVolume::load(&mut &data[..])?)
+            }
+            b"cLLi" => {
+                color_management.content_light_level =
+                    Some(ContentLightLevel::load(&mut &data[..])?)
+            }
+            _ => panic!("Not a color management chunk?!"),
+        }
+        Ok(())
+    }
+
+    /// Parses and validates the data of the `fcTL` chunk that is the current chunk.
+    fn load_frame_control(&self, data: &[u8]) -> Result<FrameControl, PngError> {
+        let frame_control = FrameControl::load(&mut &data[..])?;
+        let dimensions = self.metadata.as_ref().expect("No metadata?!").dimensions;
+        if frame_control.width == 0
+            || frame_control.height == 0
+            || frame_control.x_offset as u64 + frame_control.width as u64 > dimensions.width as u64
+            || frame_control.y_offset as u64 + frame_control.height as u64
+                > dimensions.height as u64
+        {
+            return Err(PngError::InvalidMetadata(format!(
+                "frame {} lies outside the image",
+                frame_control.sequence_number
+            )));
+        }
+        Ok(frame_control)
+    }
+
+    /// Reads all the data of the current chunk, verifying its CRC.
+    ///
+    /// If the chunk hasn't all arrived yet, returns `None` and rewinds the reader to the start of
+    /// the chunk, so that it's read again once more data has been added. Like the rest of
+    /// `ImageLoader::add_data()`, the caller should then return `LoadProgress::NeedMoreData`.
+    fn read_chunk_data<R>(&mut self, reader: &mut R) -> Result<Option<Vec<u8>>, PngError>
+    where
+        R: Read + Seek,
+    {
+        // The length comes from the file, so let the buffer grow with the data that actually
+        // arrives instead of trusting it up front. Read the CRC along with the data so that
+        // nothing is consumed until the whole chunk is here.
+        let length = self.current_chunk.length as usize;
+        let mut data = vec![];
+        reader
+            .by_ref()
+            .take(length as u64 + 4)
+            .read_to_end(&mut data)
+            .map_err(PngError::Io)?;
+        if data.len() < length + 4 {
+            reader
+                .seek(SeekFrom::Start(self.current_chunk_offset))
+                .map_err(PngError::Io)?;
+            return Ok(None);
+        }
+        let crc = BigEndian::read_u32(&data[length..]);
+        data.truncate(length);
+        self.current_chunk_crc.update(&data[..]);
+        self.check_chunk_crc(crc)?;
+        Ok(Some(data))
+    }
+
+    /// Tells the predictor thread that all the data for the current image has been sent.
+    fn finish_image(&mut self) {
+        if self.needs_rgba_conversion() {
+            self.send_scanlines_to_predictor_thread_to_convert_to_rgba()
+        }
+
+        self.predictor_thread_comm
+            .sender
+            .send(MainThreadToPredictorThreadMsg::Finished)
+            .unwrap();
+    }
+
+    /// Resets the decoding state to begin decoding a new animation frame.
+    fn start_frame(&mut self, frame_control: FrameControl) {
+        self.frame_number += 1;
+        self.frame_control = Some(frame_control);
+        self.image_data_seen = false;
+
+        // The predictor thread dropped the data provider when the last image finished.
+        self.have_data_provider = false;
+
+        self.entropy_decoder.reset(true);
+        self.current_y = 0;
+        self.current_lod = self.first_level_of_detail();
+        self.scanlines_decoded_in_this_lod = 0;
+        self.last_decoded_lod = LevelOfDetail::None;
+        self.rgba_conversion_complete = false;
+    }
+
+    fn first_level_of_detail(&self) -> LevelOfDetail {
+        match self
+            .metadata
+            .as_ref()
+            .expect("No metadata?!")
+            .interlace_method
+        {
+            InterlaceMethod::Adam7 => LevelOfDetail::Adam7(0),
+            InterlaceMethod::Disabled => LevelOfDetail::None,
+        }
+    }
+
+    /// Returns the dimensions of the image currently being decoded: either the whole image or the
+    /// current animation frame.
+    fn dimensions(&self) -> Dimensions {
+        match self.frame_control {
+            Some(ref frame_control) if self.frame_number > 0 => frame_control.dimensions(),
+            _ => self.metadata.as_ref().expect("No metadata?!").dimensions,
+        }
+    }
+
+    /// Starts accumulating the checksum of a new chunk.
+    fn begin_chunk(&mut self, chunk_header: &ChunkHeader, chunk_offset: u64) {
+        self.current_chunk = chunk_header.clone();
+        self.current_chunk_offset = chunk_offset;
+        self.current_chunk_crc.reset();
+        self.current_chunk_crc.update(&chunk_header.chunk_type);
+    }
+
+    fn crc_policy_for_current_chunk(&self) -> CrcPolicy {
+        if self.current_chunk.is_critical() {
+            self.crc_policy
+        } else {
+            self.ancillary_crc_policy
+        }
+    }
+
+    /// Reads the CRC that follows the data of the current chunk and verifies it against the
+    /// checksum we've accumulated, according to the CRC policy.
+    fn finish_chunk<R>(&mut self, reader: &mut R) -> Result<(), PngError>
+    where
+        R: Read + Seek,
+    {
+        let crc_policy = self.crc_policy_for_current_chunk();
+        if crc_policy == CrcPolicy::Ignore {
+            reader.seek(SeekFrom::Current(4)).map_err(PngError::Io)?;
+            return Ok(());
+        }
+
+        let crc = reader.read_u32::<BigEndian>().map_err(PngError::Io)?;
+        self.check_chunk_crc(crc)
+    }
+
+    /// Verifies the given CRC of the current chunk against the checksum we've accumulated,
+    /// according to the CRC policy.
+    fn check_chunk_crc(&mut self, crc: u32) -> Result<(), PngError> {
+        self.crc_policy_for_current_chunk().check(
+            self.current_chunk.chunk_type,
+            self.current_chunk_offset,
+            crc,
+            self.current_chunk_crc.sum(),
+            &mut self.warnings,
+        )
+    }
+
+    /// Skips over the data of the current chunk and its CRC. The data has to be read if the CRC
+    /// is to be verified; otherwise we just seek past it.
+    fn skip_chunk<R>(&mut self, reader: &mut R) -> Result<(), PngError>
+    where
+        R: Read + Seek,
+    {
+        let length = self.current_chunk.length;
+        if self.crc_policy_for_current_chunk() == CrcPolicy::Ignore {
+            // Add 4 to move past the CRC.
+            reader
+                .seek(SeekFrom::Current((length as i64) + 4))
+                .map_err(PngError::Io)?;
+            return Ok(());
+        }
+
+        let mut buffer = [0; BUFFER_SIZE];
+        let mut bytes_left_in_chunk = length as usize;
+        while bytes_left_in_chunk > 0 {
+            let bytes_to_read = cmp::min(bytes_left_in_chunk, BUFFER_SIZE);
+            reader
+                .read_exact(&mut buffer[0..bytes_to_read])
+                .map_err(PngError::Io)?;
+            self.current_chunk_crc.update(&buffer[0..bytes_to_read]);
+            bytes_left_in_chunk -= bytes_to_read;
+        }
+        self.finish_chunk(reader)
+    }
+
     #[inline(never)]
     fn send_scanlines_to_predictor_thread_to_predict_if_necessary(
         &mut self,
     ) -> Result<(), PngError> {
-        let (dimensions, color_depth, color_type) = match self.metadata {
+        let dimensions = self.dimensions();
+        let (bit_depth, color_depth, color_type) = match self.metadata {
             None => panic!("No metadata read yet?!"),
             Some(ref metadata) => (
-                metadata.dimensions,
+                metadata.bit_depth,
                 metadata.color_depth,
                 metadata.color_type,
             ),
@@ -412,8 +861,13 @@ impl ImageLoader {
             let mut request = PredictionRequest {
                 width: dimensions.width,
                 height: dimensions.height,
+                bit_depth,
                 color_depth: color_depth,
                 indexed_color: color_type == ColorType::Indexed,
+                rgba_conversion_pending: self.needs_rgba_conversion(),
+                premultiply_alpha: self.needs_premultiplication() && !self.needs_rgba_conversion(),
+                channel_order: self.channel_order,
+                pixel_format: self.pixel_format().expect("No metadata yet!"),
                 scanlines: Vec::with_capacity(buffered_scanline_count as usize),
             };
             for scanline_info in self.scanline_data_buffer_info.drain(..) {
@@ -440,18 +894,20 @@ impl ImageLoader {
 
     #[inline(never)]
     fn send_scanlines_to_predictor_thread_to_convert_to_rgba(&mut self) {
-        let transparency = mem::replace(&mut self.transparency, Transparency::None);
-        let (dimensions, color_depth, interlaced, indexed) = {
+        // Animation frames all share the same palette and transparency, so we copy them.
+        let transparency = self.transparency.clone();
+        let dimensions = self.dimensions();
+        let (bit_depth, color_depth, interlaced, indexed) = {
             let metadata = self.metadata.as_ref().expect("No metadata?!");
             (
-                metadata.dimensions,
+                metadata.bit_depth,
                 metadata.color_depth,
                 metadata.interlace_method != InterlaceMethod::Disabled,
                 metadata.color_type == ColorType::Indexed,
             )
         };
         let rgb_palette = if indexed {
-            Some(mem::replace(&mut self.palette, vec![]))
+            Some(self.palette.clone())
         } else {
             None
         };
@@ -463,8 +919,19 @@ impl ImageLoader {
                     transparency: transparency,
                     width: dimensions.width,
                     height: dimensions.height,
+                    bit_depth,
                     color_depth: color_depth,
                     interlaced: interlaced,
+                    gamma_exponent: self.gamma_exponent(),
+                    premultiply_alpha: self.needs_premultiplication(),
+                    channel_order: self.channel_order,
+                    pixel_format: self.pixel_format().expect("No metadata yet!"),
+                    output_format: self.output_format,
+                    dither: self.dither,
+                    background: self.flattening_background(),
+                    significant_bits: self.significant_bits_to_rescale(),
+                    color_transform: self.color_transform().map(Box::new),
+                    linearization: self.linearization(),
                 },
             ))
             .unwrap();
@@ -515,35 +982,250 @@ impl ImageLoader {
         Ok(())
     }
 
+    /// Returns true if the RGBA conversion pass must be run on the image, either because it isn't
+    /// in the output pixel format already or because it requires postprocessing.
+    ///
+    /// Premultiplication alone doesn't require it, because the predictor thread premultiplies
+    /// each scanline as soon as it's predicted if the pass won't run.
+    fn needs_rgba_conversion(&self) -> bool {
+        let color_type = self.metadata.as_ref().expect("No metadata yet!").color_type;
+        let needs_conversion = match self.pixel_format().unwrap() {
+            // Preserved indexed images are delivered exactly as they are in the file.
+            PixelFormat::Indexed => return false,
+            PixelFormat::Rgba => color_type != ColorType::RgbAlpha,
+            PixelFormat::Grayscale => false,
+            PixelFormat::GrayscaleAlpha => color_type != ColorType::GrayscaleAlpha,
+        };
+        needs_conversion
+            || self.gamma_exponent().is_some()
+            || self.needs_flattening()
+            || self.significant_bits_to_rescale().is_some()
+            || self.transforms_colors()
+            || self.output_format != OutputFormat::Rgba
+    }
+
+    /// Returns true if a color transform was requested and the color space of the image is
+    /// known and differs from the target.
+    fn transforms_colors(&self) -> bool {
+        let target = match self.color_transform_target {
+            None => return false,
+            Some(ref target) => target,
+        };
+        // Preserved indexed images are delivered exactly as they are in the file, and the color
+        // spaces of `cICP` chunks, which override the others, aren't supported.
+        if self.pixel_format() == Some(PixelFormat::Indexed)
+            || self.color_management.cicp_takes_precedence()
+        {
+            return false;
+        }
+        match self.source_color_space {
+            None => false,
+            Some(_) => *target != TargetColorSpace::Srgb || !self.source_is_srgb,
+        }
+    }
+
+    /// Returns the conversion of the colors of the image to the target color space, if one is to
+    /// be performed.
+    fn color_transform(&self) -> Option<ColorTransform> {
+        if !self.transforms_colors() {
+            return None;
+        }
+        let color_transform = ColorTransform::new(
+            self.source_color_space.as_ref().unwrap(),
+            self.color_transform_target.as_ref().unwrap(),
+        );
+        Some(color_transform.expect("The target color space was validated when it was set!"))
+    }
+
+    /// Returns the significant bits of the red, green, blue, and alpha samples, if rescaling was
+    /// requested and any samples of the image have fewer significant bits than they are stored
+    /// with.
+    fn significant_bits_to_rescale(&self) -> Option<[u8; 4]> {
+        if !self.rescale_significant_bits || self.pixel_format() == Some(PixelFormat::Indexed) {
+            return None;
+        }
+        let metadata = self.metadata.as_ref().expect("No metadata yet!");
+        let sample_depth = SignificantBits::sample_depth(metadata.color_type, metadata.bit_depth);
+        let significant_bits = self.significant_bits?.rgba(sample_depth);
+        if significant_bits.iter().all(|&bits| bits == sample_depth) {
+            return None;
+        }
+        Some(significant_bits)
+    }
+
+    /// Returns true if flattening alpha was requested and the image can have pixels that aren't
+    /// fully opaque.
+    fn needs_flattening(&self) -> bool {
+        if self.flatten_alpha.is_none() {
+            return false;
+        }
+        match (
+            self.metadata.as_ref().expect("No metadata yet!").color_type,
+            &self.transparency,
+        ) {
+            (ColorType::RgbAlpha, _) | (ColorType::GrayscaleAlpha, _) => true,
+            (_, &Transparency::None) => false,
+            (_, _) => true,
+        }
+    }
+
+    /// Returns the red, green, and blue samples of the color to flatten alpha against, in the
+    /// form that the samples of the image take after prediction, or `None` if alpha isn't to be
+    /// flattened.
+    fn flattening_background(&self) -> Option<[u16; 3]> {
+        if !self.needs_flattening() {
+            return None;
+        }
+        let bit_depth = self.metadata.as_ref().expect("No metadata yet!").bit_depth;
+        let fallback = match self.flatten_alpha {
+            Some(FlattenBackground::Image(color)) => {
+                let background = self
+                    .background
+                    .and_then(|background| background.color(bit_depth, &self.palette));
+                if background.is_some() {
+                    return background;
+                }
+                color
+            }
+            Some(FlattenBackground::Color(color)) => color,
+            None => return None,
+        };
+        let scale = if bit_depth == 16 { 257 } else { 1 };
+        Some([
+            fallback[0] as u16 * scale,
+            fallback[1] as u16 * scale,
+            fallback[2] as u16 * scale,
+        ])
+    }
+
+    /// Returns the transfer function that color samples are to be decoded with to obtain linear
+    /// light, or `None` if they're to be left as they are.
+    ///
+    /// The `sRGB` chunk takes precedence over `gAMA`, as the PNG specification requires. Images
+    /// with neither are assumed to be sRGB.
+    fn linearization(&self) -> Option<TransferFunction> {
+        if !self.linear_light || self.output_format != OutputFormat::RgbaFloat {
+            return None;
+        }
+        // Transformed colors are encoded with the sRGB transfer function.
+        if self.transforms_colors() {
+            return Some(TransferFunction::Srgb);
+        }
+        if self.color_management.cicp_takes_precedence() {
+            return self.cicp_transfer_function();
+        }
+        match self.color_management {
+            ColorManagement {
+                srgb_rendering_intent: Some(_),
+                ..
+            } => Some(TransferFunction::Srgb),
+            ColorManagement {
+                gamma: Some(ref gamma),
+                ..
+            } => Some(TransferFunction::Gamma(gamma.value())),
+            _ => Some(TransferFunction::Srgb),
+        }
+    }
+
+    /// Returns the transfer function of the `cICP` chunk, if the image has one and we support it.
+    ///
+    /// Narrow-range samples aren't supported, so `None` is returned for them too. Since the
+    /// `cICP` chunk still overrides the other color-management chunks, the samples of such images
+    /// are left as they are.
+    fn cicp_transfer_function(&self) -> Option<TransferFunction> {
+        match self.color_management.cicp {
+            Some(ref cicp) if cicp.video_full_range => {
+                TransferFunction::from_cicp(cicp.transfer_function)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns true if premultiplied alpha was requested and the image can have pixels that aren't
+    /// fully opaque.
+    fn needs_premultiplication(&self) -> bool {
+        // Flattened images are fully opaque, and preserved indexed images are delivered exactly as
+        // they are in the file.
+        if !self.premultiply_alpha
+            || self.flatten_alpha.is_some()
+            || self.pixel_format() == Some(PixelFormat::Indexed)
+        {
+            return false;
+        }
+        match (
+            self.metadata.as_ref().expect("No metadata yet!").color_type,
+            &self.transparency,
+        ) {
+            (ColorType::RgbAlpha, _) | (ColorType::GrayscaleAlpha, _) => true,
+            (_, &Transparency::None) => false,
+            (_, _) => true,
+        }
+    }
+
+    /// Returns the exponent that gamma correction raises samples to, or `None` if no gamma
+    /// correction is to be performed.
+    fn gamma_exponent(&self) -> Option<f64> {
+        let display_exponent = match self.display_exponent {
+            Some(_) if self.linearization().is_some() => return None,
+            None => return None,
+            Some(display_exponent) => display_exponent,
+        };
+        let image_gamma = if self.transforms_colors() {
+            SRGB_GAMMA
+        } else if self.color_management.cicp_takes_precedence() {
+            match self.cicp_transfer_function() {
+                Some(TransferFunction::Srgb) => SRGB_GAMMA,
+                Some(TransferFunction::Gamma(gamma)) => gamma,
+                // The other transfer functions aren't close enough to power functions to be
+                // gamma-corrected.
+                _ => return None,
+            }
+        } else {
+            match self.color_management {
+                ColorManagement {
+                    gamma: Some(ref gamma),
+                    ..
+                } => gamma.value(),
+                // sRGB images have a gamma of 1/2.2 for the purposes of simple gamma correction.
+                ColorManagement {
+                    srgb_rendering_intent: Some(_),
+                    ..
+                } => SRGB_GAMMA,
+                _ => return None,
+            }
+        };
+        let exponent = 1.0 / (image_gamma * display_exponent);
+        if (exponent - 1.0).abs() < GAMMA_THRESHOLD {
+            None
+        } else {
+            Some(exponent)
+        }
+    }
+
     fn finished_entropy_decoding(&self) -> bool {
-        let height = self
-            .metadata
-            .as_ref()
-            .expect("No metadata yet!")
-            .dimensions
-            .height;
-        (self.current_lod == LevelOfDetail::None || self.current_lod == LevelOfDetail::Adam7(6))
-            && self.current_y >= height
+        let dimensions = self.dimensions();
+        self.current_lod.next(dimensions).is_none()
+            && self.current_y >= InterlacingInfo::height_of_lod(dimensions.height, self.current_lod)
     }
 
     fn finished_decoding_altogether(&self) -> bool {
-        let (height, indexed) = {
-            let metadata = self.metadata.as_ref().expect("No metadata yet!");
-            (
-                metadata.dimensions.height,
-                metadata.color_type == ColorType::Indexed,
-            )
-        };
-        (self.current_lod == LevelOfDetail::None || self.current_lod == LevelOfDetail::Adam7(6))
-            && self.scanlines_decoded_in_this_lod >= height / 2
-            && (!indexed || self.rgba_conversion_complete)
+        let height = self.dimensions().height;
+        let needs_rgba_conversion = self.needs_rgba_conversion();
+        self.finished_entropy_decoding()
+            && self.last_decoded_lod == self.current_lod
+            && self.scanlines_decoded_in_this_lod
+                >= InterlacingInfo::height_of_lod(height, self.current_lod)
+            && (!needs_rgba_conversion || self.rgba_conversion_complete)
     }
 
     /// Attaches a data provider to this image loader.
     ///
     /// This can be called at any time, but it must be called prior to calling
-    /// `ImageLoader::add_data()` if the metadata is present. The metadata is present if
-    /// `ImageLoader::metadata()` returns `Some`.
+    /// `ImageLoader::add_data()` after it has returned `LoadProgress::NeedDataProviderAndMoreData`,
+    /// and after the options that determine what the data provider receives have been set.
+    ///
+    /// When decoding animation frames, the data provider is dropped after each image has finished
+    /// decoding, so a new one must be attached for every frame.
     #[inline(never)]
     pub fn set_data_provider(&mut self, data_provider: Box<dyn DataProvider>) {
         self.have_data_provider = true;
@@ -562,13 +1244,324 @@ impl ImageLoader {
         &self.metadata
     }
 
+    /// Returns the color-management information from the `gAMA`, `cHRM`, `sRGB`, `iCCP`, `cICP`,
+    /// `mDCv`, and `cLLi` chunks.
+    ///
+    /// These chunks precede the image data, so this is complete once `ImageLoader::add_data()`
+    /// returns `LoadProgress::NeedDataProviderAndMoreData`, before the data provider is attached.
+    #[inline]
+    pub fn color_management(&self) -> &ColorManagement {
+        &self.color_management
+    }
+
+    /// Returns the palette from the `PLTE` chunk, as consecutive red, green, and blue samples, or
+    /// an empty slice if the image has no palette.
+    #[inline]
+    pub fn palette(&self) -> &[u8] {
+        &self.palette
+    }
+
+    /// Returns the transparency information from the `tRNS` chunk. For indexed images, this is
+    /// the alpha of each palette entry; entries past the end of it are opaque.
+    #[inline]
+    pub fn transparency(&self) -> &Transparency {
+        &self.transparency
+    }
+
+    /// Returns the background color from the `bKGD` chunk, if the image has one.
+    #[inline]
+    pub fn background(&self) -> Option<Background> {
+        self.background
+    }
+
+    /// Returns the number of significant bits of each sample from the `sBIT` chunk, if the image
+    /// has one.
+    #[inline]
+    pub fn significant_bits(&self) -> Option<SignificantBits> {
+        self.significant_bits
+    }
+
+    /// Returns the intended pixel density or aspect ratio from the `pHYs` chunk, if the image has
+    /// one.
+    #[inline]
+    pub fn physical_dimensions(&self) -> Option<PhysicalDimensions> {
+        self.physical_dimensions
+    }
+
+    /// Returns the physical size of each pixel from the `sCAL` chunk, if the image has one.
+    #[inline]
+    pub fn physical_scale(&self) -> Option<PhysicalScale> {
+        self.physical_scale
+    }
+
+    /// Returns the horizontal and vertical pixel density of the image in dots per inch, if its
+    /// `pHYs` chunk gives it in meters.
+    pub fn dpi(&self) -> Option<(f64, f64)> {
+        self.physical_dimensions
+            .and_then(|physical_dimensions| physical_dimensions.dpi())
+    }
+
+    /// Returns the textual metadata from the `tEXt`, `zTXt`, and `iTXt` chunks read so far, in
+    /// the order in which they appear in the image.
+    ///
+    /// Text chunks may appear both before and after the image data, so the ones that precede it
+    /// are available when `ImageLoader::add_data()` returns
+    /// `LoadProgress::NeedDataProviderAndMoreData`, and all of them once it returns
+    /// `LoadProgress::Finished`.
+    #[inline]
+    pub fn text_chunks(&self) -> &[TextChunk] {
+        &self.text_chunks
+    }
+
+    /// Returns the Exif metadata from the `eXIf` chunk, if the image has one.
+    ///
+    /// An `eXIf` chunk normally precedes the image data, in which case it is available when
+    /// `ImageLoader::add_data()` returns `LoadProgress::NeedDataProviderAndMoreData`, but it may
+    /// also follow it, so it's only guaranteed to be available once that returns
+    /// `LoadProgress::Finished`.
+    #[inline]
+    pub fn exif(&self) -> Option<&Exif> {
+        self.exif.as_ref()
+    }
+
+    /// Returns the animation control information from the `acTL` chunk, if this is an animated
+    /// image. This is available once `ImageLoader::add_data()` has reached the image data.
+    #[inline]
+    pub fn animation_control(&self) -> &Option<AnimationControl> {
+        &self.animation_control
+    }
+
+    /// Returns the frame control information of the animation frame currently being decoded.
+    ///
+    /// While the default image is being decoded, this is `None` unless the default image is the
+    /// first frame of the animation. Subsequent frames are only decoded if
+    /// `ImageLoader::set_animation_decoding()` was called.
+    #[inline]
+    pub fn frame_control(&self) -> &Option<FrameControl> {
+        &self.frame_control
+    }
+
+    /// Enables or disables decoding of the frames of animated PNG images that follow the default
+    /// image. Disabled by default, in which case only the default image is decoded.
+    ///
+    /// When enabled, once the data for each image has been sent to the predictor thread,
+    /// `ImageLoader::add_data()` returns `LoadProgress::NeedDataProviderAndMoreData` again upon
+    /// reaching the data for the next frame, at which point `ImageLoader::frame_control()`
+    /// describes that frame. The previous frame has been fully decoded by then, and its data
+    /// provider has received the `DataProvider::finished()` call. Each frame is delivered to its
+    /// data provider as an image of the frame's own dimensions.
+    #[inline]
+    pub fn set_animation_decoding(&mut self, enabled: bool) {
+        self.decode_animation = enabled
+    }
+
+    /// Enables gamma correction for a display with the given exponent (typically 2.2), or
+    /// disables it if `None` is given. Gamma correction is disabled by default.
+    ///
+    /// If enabled, the color samples (but not alpha) of images with a `gAMA` or `sRGB` chunk are
+    /// corrected on the background thread as part of RGBA conversion, which then happens for all
+    /// images, RGBA or not. A `cICP` chunk overrides those chunks: it determines the image gamma
+    /// instead if it specifies full-range samples and the sRGB or linear transfer function, and
+    /// disables gamma correction otherwise, such as for PQ or HLG.
+    #[inline]
+    pub fn set_gamma_correction(&mut self, display_exponent: Option<f64>) {
+        self.display_exponent = display_exponent
+    }
+
+    /// Enables or disables premultiplied alpha output. Disabled by default, in which case the
+    /// color samples are delivered with straight (unassociated) alpha.
+    ///
+    /// If enabled, the color samples of images with an alpha channel or a `tRNS` chunk are
+    /// multiplied by alpha on the background thread, after any gamma correction. Images that are
+    /// already in the output pixel format and need no other postprocessing are premultiplied as
+    /// soon as each scanline is predicted; others, as part of RGBA conversion.
+    /// `DataProvider::scanline_complete()` reports when each scanline is final.
+    #[inline]
+    pub fn set_premultiplied_alpha(&mut self, enabled: bool) {
+        self.premultiply_alpha = enabled
+    }
+
+    /// Sets the order in which the channels of each output pixel are stored. The default is
+    /// `ChannelOrder::Rgba`.
+    #[inline]
+    pub fn set_channel_order(&mut self, channel_order: ChannelOrder) {
+        self.channel_order = channel_order
+    }
+
+    /// Sets the format of the output pixels. The default is `OutputFormat::Rgba`.
+    #[inline]
+    pub fn set_output_format(&mut self, output_format: OutputFormat) {
+        self.output_format = output_format
+    }
+
+    /// Returns the format of the output pixels, as set by `ImageLoader::set_output_format()`.
+    #[inline]
+    pub fn output_format(&self) -> OutputFormat {
+        self.output_format
+    }
+
+    /// Enables or disables ordered dithering when reducing samples to the fewer bits of the packed
+    /// 16-bit output formats, such as `OutputFormat::Rgb565`. Disabled by default, in which case
+    /// samples are rounded to the nearest representable value.
+    ///
+    /// Only the color samples are dithered.
+    #[inline]
+    pub fn set_dithering(&mut self, enabled: bool) {
+        self.dither = enabled
+    }
+
+    /// Enables or disables compositing of images that have alpha onto a background color during
+    /// RGBA conversion, producing fully opaque pixels. Disabled (`None`) by default.
+    ///
+    /// Compositing happens before gamma correction, in the color space of the image, as the
+    /// background color of the `bKGD` chunk is specified in. Images that are flattened are
+    /// delivered as RGBA even if delivery as grayscale was requested, and alpha premultiplication
+    /// is skipped, since it would have no effect. Indexed images delivered as palette indices are
+    /// not flattened.
+    #[inline]
+    pub fn set_alpha_flattening(&mut self, background: Option<FlattenBackground>) {
+        self.flatten_alpha = background
+    }
+
+    /// Enables or disables rescaling of samples to the full output range according to the
+    /// `sBIT` chunk during RGBA conversion. Disabled by default.
+    ///
+    /// Each sample is reduced to its significant bits, as given by
+    /// `ImageLoader::significant_bits()`, and then scaled so that their maximum becomes the
+    /// maximum of the output, rather than leaving the low bits as the encoder chose them. This
+    /// happens before flattening, gamma correction, and any reduction to a packed or
+    /// floating-point output format, and applies to 16-bit images too. Images without an `sBIT`
+    /// chunk are unaffected, as are indexed images delivered as palette indices, whose palette is
+    /// delivered as it is in the file.
+    #[inline]
+    pub fn set_significant_bits_rescaling(&mut self, enabled: bool) {
+        self.rescale_significant_bits = enabled
+    }
+
+    /// Enables conversion of the color samples to the given color space during RGBA conversion,
+    /// or disables it if `None` is given. Disabled by default.
+    ///
+    /// The color space of the image is taken from its `iCCP` chunk if that holds a matrix/TRC
+    /// profile, otherwise from its `sRGB` chunk, and otherwise from its `gAMA` chunk together
+    /// with its `cHRM` chunk or, failing that, the sRGB primaries, as
+    /// `colortransform::SourceColorSpace::from_color_management()` describes. Images without any
+    /// of these, and those with a `cICP` chunk, which takes precedence, are left alone, as are
+    /// indexed images delivered as palette indices. The converted samples are encoded with the
+    /// sRGB transfer function, and colors that the target can't represent are clipped.
+    ///
+    /// Conversion happens after flattening and before gamma correction, which then treats the
+    /// image as sRGB, as does decoding to linear light.
+    ///
+    /// Returns `PngError::InvalidOption` if the primaries of the target are degenerate.
+    pub fn set_color_transform(
+        &mut self,
+        target: Option<TargetColorSpace>,
+    ) -> Result<(), PngError> {
+        if let Some(ref target) = target {
+            if target.from_xyz().is_err() {
+                return Err(PngError::InvalidOption(
+                    "the target primaries are degenerate".to_owned(),
+                ));
+            }
+        }
+        self.color_transform_target = target;
+        Ok(())
+    }
+
+    /// Enables or disables delivery of grayscale images without expansion to RGBA. Disabled by
+    /// default.
+    ///
+    /// If enabled, grayscale images are delivered with one sample per pixel, and grayscale images
+    /// with an alpha channel or a `tRNS` chunk with two, as reported by
+    /// `ImageLoader::pixel_format()`. This has no effect if the output format is
+    /// `OutputFormat::RgbaFloat`.
+    #[inline]
+    pub fn set_preserve_grayscale(&mut self, enabled: bool) {
+        self.preserve_grayscale = enabled
+    }
+
+    /// Enables or disables delivery of indexed images as palette indices. Disabled by default.
+    ///
+    /// If enabled, indexed images skip the RGBA conversion pass entirely. The data provider
+    /// receives one 8-bit index per pixel in the scanlines that it supplies for prediction, and
+    /// the palette and its alpha are available from `ImageLoader::palette()` and
+    /// `ImageLoader::transparency()`. No postprocessing such as gamma correction or alpha
+    /// premultiplication is performed on such images. This has no effect if the output format is
+    /// `OutputFormat::RgbaFloat`.
+    #[inline]
+    pub fn set_preserve_indexed(&mut self, enabled: bool) {
+        self.preserve_indexed = enabled
+    }
+
+    /// Returns the format of the pixels that the data provider receives, or `None` if the image
+    /// header hasn't been read yet.
+    ///
+    /// This is final once `ImageLoader::add_data()` has returned
+    /// `LoadProgress::NeedDataProviderAndMoreData`, since it depends on whether the image has a
+    /// `tRNS` chunk.
+    pub fn pixel_format(&self) -> Option<PixelFormat> {
+        let color_type = match self.metadata {
+            None => return None,
+            Some(ref metadata) => metadata.color_type,
+        };
+        if self.output_format != OutputFormat::Rgba {
+            return Some(PixelFormat::Rgba);
+        }
+        match (color_type, &self.transparency) {
+            (ColorType::Indexed, _) if self.preserve_indexed => Some(PixelFormat::Indexed),
+            (_, _) if !self.preserve_grayscale || self.needs_flattening() => {
+                Some(PixelFormat::Rgba)
+            }
+            (ColorType::Grayscale, &Transparency::None) => Some(PixelFormat::Grayscale),
+            (ColorType::Grayscale, _) | (ColorType::GrayscaleAlpha, _) => {
+                Some(PixelFormat::GrayscaleAlpha)
+            }
+            (_, _) => Some(PixelFormat::Rgba),
+        }
+    }
+
+    /// Enables or disables decoding of color samples to linear light. Disabled by default.
+    ///
+    /// This only has an effect if the output format is `OutputFormat::RgbaFloat`, as integer
+    /// samples lack the precision to represent linear light without banding. The transfer function
+    /// is that of the `cICP` chunk if present, otherwise that of the `sRGB` chunk if present,
+    /// otherwise that of the `gAMA` chunk if present, and otherwise sRGB. Images whose `cICP`
+    /// chunk specifies narrow-range samples or a transfer function that isn't supported are left
+    /// as they are. Gamma correction is not performed when this is enabled.
+    ///
+    /// HDR images whose `cICP` chunk specifies PQ are decoded such that 1.0 is 10000 cd/m², and
+    /// those that specify HLG to relative scene light, without the HLG system gamma.
+    #[inline]
+    pub fn set_linear_light_output(&mut self, enabled: bool) {
+        self.linear_light = enabled
+    }
+
+    /// Sets how the CRC-32 checksums of critical chunks (`IHDR`, `PLTE`, `IDAT`, and `IEND`) are
+    /// verified. The default is `CrcPolicy::Error`.
+    ///
+    /// This must be called before the first call to `ImageLoader::add_data()` for it to apply to
+    /// the `IHDR` chunk.
+    #[inline]
+    pub fn set_crc_policy(&mut self, crc_policy: CrcPolicy) {
+        self.crc_policy = crc_policy
+    }
+
+    /// Sets how the CRC-32 checksums of ancillary chunks are verified. The default is
+    /// `CrcPolicy::Ignore`, which avoids reading chunks that `parng` doesn't understand.
+    #[inline]
+    pub fn set_ancillary_crc_policy(&mut self, crc_policy: CrcPolicy) {
+        self.ancillary_crc_policy = crc_policy
+    }
+
+    /// Returns the problems that were found in the image but didn't stop it from decoding, such as
+    /// checksum mismatches under `CrcPolicy::Warn`.
+    #[inline]
+    pub fn warnings(&self) -> &[PngError] {
+        &self.warnings
+    }
+
     fn scanlines_to_buffer(&self) -> u32 {
-        let width = self
-            .metadata
-            .as_ref()
-            .expect("No metadata?!")
-            .dimensions
-            .width;
+        let width = self.dimensions().width;
         cmp::max(PIXELS_PER_PREDICTION_CHUNK / width, 1)
     }
 }
@@ -593,12 +1586,26 @@ pub enum LoadProgress {
     /// to decode the image, call `ImageLoader::add_data()` again with more data.
     NeedMoreData,
 
-    /// Enough data was consumed to decode the image metadata, but no data provider has been set
-    /// up. Before calling `ImageLoader::add_data()` again to decode the image data proper, a data
-    /// provider must be installed via `ImageLoader::set_data_provider()`.
+    /// Enough data was consumed to decode the image metadata and reach the image data, but no
+    /// data provider has been set up. Before calling `ImageLoader::add_data()` again to decode the
+    /// image data proper, a data provider must be installed via
+    /// `ImageLoader::set_data_provider()`.
+    ///
+    /// If animation decoding is enabled, this is also returned at the start of each frame.
     NeedDataProviderAndMoreData,
 }
 
+/// What became of an ancillary chunk that `ImageLoader::read_ancillary_chunk()` was asked to read.
+#[derive(Copy, Clone, PartialEq)]
+enum AncillaryChunkProgress {
+    /// The chunk was read and parsed.
+    Read,
+    /// We don't know about the chunk, so nothing was read.
+    Unknown,
+    /// Not all of the chunk has arrived yet, so nothing was consumed.
+    NeedMoreData,
+}
+
 #[derive(Copy, Clone, PartialEq)]
 enum DecodeState {
     Start,
@@ -629,6 +1636,20 @@ fn aligned_scanline_buffer_offset(buffer: &[u8]) -> usize {
     }
 }
 
+/// Converts a sample from a `tRNS` or `bKGD` chunk to the value that it will have after
+/// prediction, scaling up samples with fewer than 8 bits to the full 8-bit range. Only the
+/// low-order bits that the bit depth allows for are significant.
+fn magic_color_sample(sample: u16, bit_depth: u8) -> u16 {
+    match bit_depth {
+        16 => sample,
+        8 => sample & 0xff,
+        _ => {
+            let mask = (1 << bit_depth) - 1;
+            (sample & mask) * (0xff / mask)
+        }
+    }
+}
+
 /// An interface that `parng` uses to access storage for the image data. By implementing this
 /// trait, you can choose any method you wish to store the image data and it will be transparent to
 /// `parng`.
@@ -642,13 +1663,19 @@ pub trait DataProvider: Send {
     /// `parng` requests one or two scanlines using this method: one for writing
     /// (`current_scanline`) and, optionally, one for reading (`reference_scanline`). It is
     /// guaranteed that the reference scanline will always have a smaller Y value than the current
-    /// scanline.
+    /// scanline. For images with fewer than 8 bits per pixel, `parng` keeps track of the reference
+    /// scanline itself and never requests one.
     ///
     /// `lod` specifies the level of detail, if the image is interlaced.
     ///
     /// `indexed` is true if the image has a color palette. If it is true, then the scanlines
     /// returned should have 8 bits of storage per pixel. Otherwise, the data provider should
-    /// return scanlines with 32 bits of storage per pixel.
+    /// return scanlines with 32 bits of storage per pixel, or 64 bits of storage per pixel if the
+    /// image has 16 bits per sample (i.e. if `Metadata::bit_depth` is 16), or 128 bits of storage
+    /// per pixel if the output format is `OutputFormat::RgbaFloat`. In the last case, prediction
+    /// fills in only the first 32 or 64 bits of each pixel, and the RGBA conversion pass expands
+    /// the pixels to floating point in place. If grayscale images are being preserved,
+    /// `ImageLoader::pixel_format()` gives the number of samples per pixel instead of four.
     fn fetch_scanlines_for_prediction<'a>(
         &'a mut self,
         reference_scanline: Option<u32>,
@@ -660,19 +1687,24 @@ pub trait DataProvider: Send {
     /// Called when `parng` has finished prediction for a scanline, optionally at a specific level
     /// of detail.
     ///
-    /// If the image is in RGBA or grayscale-alpha format, then the scanline is entirely finished
-    /// at this time. Otherwise, unless the image is in indexed format, the scanline is finished,
-    /// but the alpha values are not yet valid. Finally, if the image is in indexed format, the
-    /// scanline palette values are correct, but the indexed-to-truecolor conversion has not
-    /// occurred yet, so the scanline is not yet suitable for display.
+    /// If the image is in RGBA format and no postprocessing such as gamma correction or alpha
+    /// premultiplication was requested, then the scanline is entirely finished at this time.
+    /// Otherwise, unless the image is in indexed format, the samples are present, but they will
+    /// not be in their final form until the RGBA conversion pass has processed the scanline.
+    /// Finally, if the image is in indexed format, the scanline palette values are correct, but the
+    /// indexed-to-truecolor conversion has not occurred yet, so the scanline is not yet suitable
+    /// for display. (If indexed images are being preserved, that conversion never occurs, and the
+    /// scanline is finished.)
     fn prediction_complete_for_scanline(&mut self, scanline: u32, lod: LevelOfDetail);
 
     /// Called when `parng` needs to perform RGBA conversion for a scanline.
     ///
-    /// `lod` specifies the level of detail, if the image is interlaced. `indexed` is true if the
-    /// image has indexed color.
+    /// `scanline` is the index of the scanline within the level of detail `lod`, as in
+    /// `fetch_scanlines_for_prediction()`; `InterlacingInfo` can be used to find its position in
+    /// the final image. `indexed` is true if the image has indexed color.
     ///
-    /// This method will be called only if the image is not RGBA.
+    /// This method will be called only if the image is not RGBA or if postprocessing such as
+    /// gamma correction or alpha premultiplication was requested.
     fn fetch_scanlines_for_rgba_conversion<'a>(
         &'a mut self,
         scanline: u32,
@@ -683,10 +1715,35 @@ pub trait DataProvider: Send {
     /// Called when `parng` has finished RGBA conversion for a scanline, optionally at a specific
     /// level of detail.
     ///
-    /// This method will be called only if the image is not RGBA.
+    /// This method will be called only if the image is not RGBA or if postprocessing such as
+    /// gamma correction or alpha premultiplication was requested.
     fn rgba_conversion_complete_for_scanline(&mut self, scanline: u32, lod: LevelOfDetail);
 
-    /// Called when `parng` has completely finished decoding the image.
+    /// Called when `parng` needs somewhere to write a scanline in one of the packed 16-bit output
+    /// formats, such as `OutputFormat::Rgb565`, after it has converted the scanline supplied by
+    /// `fetch_scanlines_for_rgba_conversion()`.
+    ///
+    /// This lets the packed pixels go straight into a framebuffer, separate from the RGBA scanlines
+    /// that `parng` works in. The default implementation returns `None`, in which case each packed
+    /// pixel is written to the first two bytes of its slot in the RGBA scanline instead.
+    fn fetch_scanline_for_packed_output<'a>(
+        &'a mut self,
+        _scanline: u32,
+        _lod: LevelOfDetail,
+    ) -> Option<ScanlineForPackedOutput<'a>> {
+        None
+    }
+
+    /// Called when a scanline, optionally at a specific level of detail, is in its final form and
+    /// `parng` will not touch it again.
+    ///
+    /// This follows `prediction_complete_for_scanline()` if no RGBA conversion pass is needed and
+    /// `rgba_conversion_complete_for_scanline()` otherwise, so data providers that want to hand
+    /// scanlines off as soon as they're displayable need not work out which one applies. The
+    /// default implementation does nothing.
+    fn scanline_complete(&mut self, _scanline: u32, _lod: LevelOfDetail) {}
+
+    /// Called when `parng` has completely finished decoding the image (or the animation frame).
     fn finished(&mut self);
 }
 
@@ -695,18 +1752,33 @@ pub trait DataProvider: Send {
 pub struct ScanlinesForPrediction<'a> {
     /// The pixels of the reference scanline. This must be present if `parng` requested a reference
     /// scanline. There must be 4 bytes per pixel available in this array for truecolor modes (i.e.
-    /// when the `indexed` parameter is false), while for indexed modes (i.e. when the `indexed`
-    /// parameter is true) there must be 1 byte per pixel available.
+    /// when the `indexed` parameter is false), or 8 bytes per pixel if the image has 16 bits per
+    /// sample, while for indexed modes (i.e. when the `indexed` parameter is true) there must be 1
+    /// byte per pixel available.
     pub reference_scanline: Option<&'a mut [u8]>,
 
     /// The pixels of the current scanline. As with the reference scanline, there must be 4 bytes
-    /// per pixel available in this array for truecolor modes, and for indexed modes there must be
-    /// 1 byte per pixel available.
+    /// per pixel (8 bytes for 16 bits per sample) available in this array for truecolor modes,
+    /// and for indexed modes there must be 1 byte per pixel available.
     pub current_scanline: &'a mut [u8],
 
     /// The number of bytes between individual pixels in `reference_scanline` and
-    /// `current_scanline`. For truecolor modes, this must be at least 4. You are free to set any
-    /// number of bytes here.
+    /// `current_scanline`. For truecolor modes, this must be at least 4, or at least 8 if the
+    /// image has 16 bits per sample, or at least 16 if the output format is
+    /// `OutputFormat::RgbaFloat`. You are free to set any number of bytes here.
+    ///
+    /// This field is useful for in-place deinterlacing.
+    pub stride: u8,
+}
+
+/// Data providers use this structure to supply scanlines to `parng` in which to store pixels in
+/// the packed 16-bit output formats.
+pub struct ScanlineForPackedOutput<'a> {
+    /// The pixels of the scanline. There must be 2 bytes per pixel available in this array. Each
+    /// pixel is stored native-endian.
+    pub scanline: &'a mut [u8],
+
+    /// The number of bytes between individual pixels in `scanline`. This must be at least 2.
     ///
     /// This field is useful for in-place deinterlacing.
     pub stride: u8,
@@ -715,7 +1787,10 @@ pub struct ScanlinesForPrediction<'a> {
 /// Data providers use this structure to supply scanlines to `parng` in response to RGBA conversion
 /// requests.
 pub struct ScanlinesForRgbaConversion<'a> {
-    /// The pixels of the RGBA scanline. There must be 4 bytes per pixel available in this array.
+    /// The pixels of the RGBA scanline. There must be 4 bytes per pixel available in this array,
+    /// or 8 bytes per pixel if the image has 16 bits per sample. In the latter case, each sample is
+    /// stored big-endian. If the output format is `OutputFormat::RgbaFloat`, there must instead be
+    /// 16 bytes per pixel, and each sample is a native-endian `f32`.
     ///
     /// It is recommended that the address of this slice be aligned properly. To determine the
     /// optimum alignment, use the `align()` function.
@@ -728,7 +1803,9 @@ pub struct ScanlinesForRgbaConversion<'a> {
     /// optimum alignment, use the `align()` function.
     pub indexed_scanline: Option<&'a [u8]>,
 
-    /// The number of bytes between individiual pixels in `rgba_scanline`. This must be at least 4.
+    /// The number of bytes between individiual pixels in `rgba_scanline`. This must be at least 4,
+    /// or at least 8 if the image has 16 bits per sample, or at least 16 if the output format is
+    /// `OutputFormat::RgbaFloat`.
     ///
     /// This field is useful for in-place deinterlacing.
     pub rgba_stride: u8,
@@ -760,11 +1837,19 @@ impl UninitializedExtension for Vec<u8> {
 /// It is recommended that data providers use this function to determine the stride when allocating
 /// space internally, so as to allow `parng` the most opportunities for use of accelerated SIMD.
 pub fn align(stride: usize) -> usize {
-    let remainder = stride % 16;
+    align_to(stride, 16)
+}
+
+/// Rounds the given stride up to a multiple of `alignment`, which must be nonzero, for consumers
+/// that need a particular row pitch. An alignment of 1 leaves the stride as it is.
+///
+/// Only multiples of 16 preserve the opportunities for accelerated SIMD that `align()` provides.
+pub fn align_to(stride: usize, alignment: usize) -> usize {
+    let remainder = stride % alignment;
     if remainder == 0 {
         stride
     } else {
-        stride + 16 - remainder
+        stride + alignment - remainder
     }
 }
 
@@ -787,26 +1872,46 @@ impl InterlacingInfo {
     /// scanline and level of detail at the given color depth.
     ///
     /// `color_depth` specifies the number of bits per pixel. Thus, if you have for instance an
-    /// RGBA image, you supply 32 here. For indexed images, supply 8.
+    /// RGBA image, you supply 32 here. For indexed images, supply 8. For 16-bit RGBA images, supply
+    /// 64. Pixels of fewer than 8 bits are unpacked to a byte each, as the predictor delivers
+    /// them, so 1, 2, and 4 are treated like 8.
     pub fn new(y: u32, color_depth: u8, lod: LevelOfDetail) -> InterlacingInfo {
         let y_offset = InterlacingInfo::y_offset(lod);
         let y_scale_factor = InterlacingInfo::y_scale_factor(lod);
-        let color_depth = color_depth / 8;
-        let (stride, x_offset) = match lod {
-            LevelOfDetail::None => (1, 0),
-            LevelOfDetail::Adam7(0) => (8, 0),
-            LevelOfDetail::Adam7(1) => (8, 4),
-            LevelOfDetail::Adam7(2) => (4, 0),
-            LevelOfDetail::Adam7(3) => (4, 2),
-            LevelOfDetail::Adam7(4) => (2, 0),
-            LevelOfDetail::Adam7(5) => (2, 1),
-            LevelOfDetail::Adam7(6) => (1, 0),
-            LevelOfDetail::Adam7(_) => panic!("Unsupported Adam7 level of detail!"),
-        };
+        let bytes_per_pixel = InterlacingInfo::bytes_per_pixel(color_depth);
         InterlacingInfo {
             y: y * y_scale_factor + y_offset as u32,
-            stride: stride * color_depth,
-            offset: x_offset * color_depth,
+            stride: InterlacingInfo::x_scale_factor(lod) * bytes_per_pixel,
+            offset: InterlacingInfo::x_offset(lod) * bytes_per_pixel,
+        }
+    }
+
+    /// Returns the number of bytes that an unpacked pixel of the given color depth occupies.
+    fn bytes_per_pixel(color_depth: u8) -> u8 {
+        cmp::max(color_depth.div_ceil(8), 1)
+    }
+
+    fn x_offset(lod: LevelOfDetail) -> u8 {
+        match lod {
+            LevelOfDetail::None
+            | LevelOfDetail::Adam7(0)
+            | LevelOfDetail::Adam7(2)
+            | LevelOfDetail::Adam7(4)
+            | LevelOfDetail::Adam7(6) => 0,
+            LevelOfDetail::Adam7(5) => 1,
+            LevelOfDetail::Adam7(3) => 2,
+            LevelOfDetail::Adam7(1) => 4,
+            LevelOfDetail::Adam7(_) => panic!("Unsupported Adam7 level of detail!"),
+        }
+    }
+
+    fn x_scale_factor(lod: LevelOfDetail) -> u8 {
+        match lod {
+            LevelOfDetail::None | LevelOfDetail::Adam7(6) => 1,
+            LevelOfDetail::Adam7(0) | LevelOfDetail::Adam7(1) => 8,
+            LevelOfDetail::Adam7(2) | LevelOfDetail::Adam7(3) => 4,
+            LevelOfDetail::Adam7(4) | LevelOfDetail::Adam7(5) => 2,
+            LevelOfDetail::Adam7(_) => panic!("Unsupported Adam7 level of detail!"),
         }
     }
 
@@ -834,24 +1939,47 @@ impl InterlacingInfo {
         }
     }
 
-    // This formula is cribbed from `stb_image`.
+    // This formula is cribbed from `stb_image`. Like `InterlacingInfo::width_of_lod()`, this is
+    // zero if the image is too narrow for the level of detail to have any pixels, and also if the
+    // stride is too small to hold a pixel of the given color depth.
     pub fn scanline_width(&self, image_width: u32, color_depth: u8) -> u32 {
-        let bytes_per_pixel = color_depth / 8;
-        let x_offset = self.offset / bytes_per_pixel;
-        let x_scale_factor = self.stride / bytes_per_pixel;
-        (image_width - x_offset as u32 + x_scale_factor as u32 - 1) / x_scale_factor as u32
+        let bytes_per_pixel = InterlacingInfo::bytes_per_pixel(color_depth);
+        let x_offset = (self.offset / bytes_per_pixel) as u32;
+        let x_scale_factor = (self.stride / bytes_per_pixel) as u32;
+        if image_width <= x_offset || x_scale_factor == 0 {
+            return 0;
+        }
+        (image_width - x_offset).div_ceil(x_scale_factor)
     }
 
-    fn scanline_stride(&self, image_width: u32, color_depth: u8) -> u32 {
-        let bytes_per_pixel = color_depth / 8;
-        self.scanline_width(image_width, color_depth) * bytes_per_pixel as u32
+    /// Returns the number of bytes of image data in each scanline of the given level of detail,
+    /// not counting the predictor byte. Images with fewer than 8 bits per pixel pack multiple
+    /// pixels into each byte, with the final byte padded out as necessary.
+    fn scanline_stride(image_width: u32, color_depth: u8, lod: LevelOfDetail) -> u32 {
+        (InterlacingInfo::width_of_lod(image_width, lod) * color_depth as u32).div_ceil(8)
     }
 
+    /// Returns the number of pixels in each scanline of the given level of detail, for an image
+    /// of the given width. This may be zero for very narrow interlaced images.
+    pub fn width_of_lod(image_width: u32, lod: LevelOfDetail) -> u32 {
+        let x_offset = InterlacingInfo::x_offset(lod) as u32;
+        let x_scale_factor = InterlacingInfo::x_scale_factor(lod) as u32;
+        if image_width <= x_offset {
+            return 0;
+        }
+        (image_width - x_offset).div_ceil(x_scale_factor)
+    }
+
+    /// Returns the number of scanlines in the given level of detail, for an image of the given
+    /// height. This may be zero for very short interlaced images.
     // This formula is cribbed from `stb_image`.
-    fn height_of_lod(image_height: u32, lod: LevelOfDetail) -> u32 {
-        let y_offset = InterlacingInfo::y_offset(lod);
+    pub fn height_of_lod(image_height: u32, lod: LevelOfDetail) -> u32 {
+        let y_offset = InterlacingInfo::y_offset(lod) as u32;
         let y_scale_factor = InterlacingInfo::y_scale_factor(lod);
-        (image_height - y_offset as u32 + y_scale_factor as u32 - 1) / y_scale_factor as u32
+        if image_height <= y_offset {
+            return 0;
+        }
+        (image_height - y_offset).div_ceil(y_scale_factor)
     }
 }
 
@@ -875,15 +2003,293 @@ pub enum LevelOfDetail {
     Adam7(u8),
 }
 
+impl LevelOfDetail {
+    /// Returns the level of detail that follows this one in an image of the given dimensions,
+    /// skipping over any Adam7 passes that contain no pixels. Returns `None` if this is the last
+    /// level of detail.
+    pub fn next(self, dimensions: Dimensions) -> Option<LevelOfDetail> {
+        let mut lod = self;
+        loop {
+            lod = match lod {
+                LevelOfDetail::None | LevelOfDetail::Adam7(6) => return None,
+                LevelOfDetail::Adam7(level) => LevelOfDetail::Adam7(level + 1),
+            };
+            if InterlacingInfo::width_of_lod(dimensions.width, lod) > 0
+                && InterlacingInfo::height_of_lod(dimensions.height, lod) > 0
+            {
+                return Some(lod);
+            }
+        }
+    }
+}
+
+/// The order in which the channels of each pixel are stored in the output.
+///
+/// For images with 16 bits per sample, this is the order of the samples; each sample remains
+/// big-endian.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ChannelOrder {
+    /// Red, green, blue, alpha. This is the default.
+    Rgba,
+    /// Blue, green, red, alpha. This is the layout of native-endian ARGB32 surfaces (as used by
+    /// Windows, Skia, and Cairo) on little-endian machines.
+    Bgra,
+    /// Alpha, red, green, blue. This is the layout of native-endian ARGB32 surfaces on big-endian
+    /// machines.
+    Argb,
+    /// Alpha, blue, green, red.
+    Abgr,
+}
+
+impl ChannelOrder {
+    /// Returns the positions of the red, green, blue, and alpha samples within a pixel, in that
+    /// order.
+    #[inline]
+    pub fn sample_positions(self) -> [usize; 4] {
+        match self {
+            ChannelOrder::Rgba => [0, 1, 2, 3],
+            ChannelOrder::Bgra => [2, 1, 0, 3],
+            ChannelOrder::Argb => [1, 2, 3, 0],
+            ChannelOrder::Abgr => [3, 2, 1, 0],
+        }
+    }
+}
+
+impl Default for ChannelOrder {
+    #[inline]
+    fn default() -> ChannelOrder {
+        ChannelOrder::Rgba
+    }
+}
+
+/// The format of the pixels that `parng` delivers to the data provider.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// Integer RGBA samples of 8 bits each, or 16 bits each (big-endian) if the image has 16 bits
+    /// per sample. This is the default.
+    Rgba,
+    /// Native-endian 32-bit floating-point RGBA samples ranging from 0.0 to 1.0, 16 bytes per
+    /// pixel.
+    RgbaFloat,
+    /// Native-endian 16-bit pixels with 5 bits of red in the most significant bits, then 6 bits of
+    /// green, then 5 bits of blue. Alpha is discarded, so premultiplying it is advisable if the
+    /// image may be translucent.
+    Rgb565,
+    /// Native-endian 16-bit pixels with 4 bits each of red (in the most significant bits), green,
+    /// blue, and alpha.
+    Rgba4444,
+    /// Native-endian 16-bit pixels with 5 bits each of red (in the most significant bits), green,
+    /// and blue, then a single bit of alpha.
+    Rgba5551,
+}
+
+impl OutputFormat {
+    /// Returns true if this is one of the packed 16-bit formats.
+    ///
+    /// Pixels in these formats are produced by the RGBA conversion pass from integer RGBA pixels,
+    /// so data providers supply scanlines for prediction and RGBA conversion just as they would for
+    /// `OutputFormat::Rgba`. The packed pixels are then written to the scanlines that
+    /// `DataProvider::fetch_scanline_for_packed_output()` supplies. The channel order doesn't
+    /// affect them.
+    ///
+    /// RGBA conversion only begins once the whole image has been predicted, so the RGBA scanlines
+    /// must all be kept until then. The packed formats thus shrink the finished image, but not the
+    /// memory needed to decode it.
+    #[inline]
+    pub fn is_packed(self) -> bool {
+        match self {
+            OutputFormat::Rgb565 | OutputFormat::Rgba4444 | OutputFormat::Rgba5551 => true,
+            OutputFormat::Rgba | OutputFormat::RgbaFloat => false,
+        }
+    }
+}
+
+impl Default for OutputFormat {
+    #[inline]
+    fn default() -> OutputFormat {
+        OutputFormat::Rgba
+    }
+}
+
+/// The layout of the pixels that `parng` delivers to the data provider.
+///
+/// Only grayscale and indexed images are ever delivered as anything other than RGBA, and only if
+/// `ImageLoader::set_preserve_grayscale()` or `ImageLoader::set_preserve_indexed()` respectively
+/// was called.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PixelFormat {
+    /// Red, green, blue, and alpha samples, in the requested channel order.
+    Rgba,
+    /// A single gray sample.
+    Grayscale,
+    /// A gray sample followed by an alpha sample.
+    GrayscaleAlpha,
+    /// An 8-bit index into the palette, delivered in the scanlines for indexed data.
+    Indexed,
+}
+
+impl PixelFormat {
+    /// Returns the number of samples in each pixel.
+    #[inline]
+    pub fn samples_per_pixel(self) -> u8 {
+        match self {
+            PixelFormat::Rgba => 4,
+            PixelFormat::Grayscale | PixelFormat::Indexed => 1,
+            PixelFormat::GrayscaleAlpha => 2,
+        }
+    }
+}
+
+impl Default for PixelFormat {
+    #[inline]
+    fn default() -> PixelFormat {
+        PixelFormat::Rgba
+    }
+}
+
 /// Represents the contents of a `tRNS` chunk.
-#[derive(Debug)]
+///
+/// The magic colors of grayscale and RGB images are stored as they appear in the chunk, as samples
+/// at the bit depth of the image.
+#[derive(Clone, Debug)]
 pub enum Transparency {
+    /// The image has no `tRNS` chunk.
     None,
+    /// The alpha of each palette entry of an indexed image. Entries past the end are opaque.
     Indexed(Vec<u8>),
-    MagicColor(u8, u8, u8),
+    /// Pixels of a grayscale image with this gray sample are fully transparent.
+    Grayscale(u16),
+    /// Pixels of an RGB image with these red, green, and blue samples are fully transparent.
+    Rgb(u16, u16, u16),
+}
+
+/// Represents the contents of a `bKGD` chunk: the solid color that the image is meant to be
+/// displayed against.
+///
+/// Gray and RGB samples are stored as they appear in the chunk, at the bit depth of the image.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Background {
+    /// An index into the palette of an indexed image.
+    Indexed(u8),
+    /// A gray sample, for grayscale images.
+    Grayscale(u16),
+    /// Red, green, and blue samples, for RGB images.
+    Rgb(u16, u16, u16),
+}
+
+impl Background {
+    /// Parses the `bKGD` chunk of an image of the given color type.
+    fn load(color_type: ColorType, data: &[u8]) -> Result<Background, PngError> {
+        let expected_length = match color_type {
+            ColorType::Indexed => 1,
+            ColorType::Grayscale | ColorType::GrayscaleAlpha => 2,
+            ColorType::Rgb | ColorType::RgbAlpha => 6,
+        };
+        if data.len() != expected_length {
+            return Err(PngError::InvalidMetadata(format!(
+                "`bKGD` chunk is {} bytes long, but should be {}",
+                data.len(),
+                expected_length
+            )));
+        }
+        Ok(match color_type {
+            ColorType::Indexed => Background::Indexed(data[0]),
+            ColorType::Grayscale | ColorType::GrayscaleAlpha => {
+                Background::Grayscale(BigEndian::read_u16(&data[0..2]))
+            }
+            ColorType::Rgb | ColorType::RgbAlpha => Background::Rgb(
+                BigEndian::read_u16(&data[0..2]),
+                BigEndian::read_u16(&data[2..4]),
+                BigEndian::read_u16(&data[4..6]),
+            ),
+        })
+    }
+
+    /// Returns the red, green, and blue samples of the background color in the form that samples
+    /// of an image with the given bit depth take after prediction, as `Transparency::magic_color()`
+    /// does. Palette indices are looked up in the given palette, yielding 8-bit samples, and
+    /// `None` is returned if they are out of range.
+    pub fn color(&self, bit_depth: u8, palette: &[u8]) -> Option<[u16; 3]> {
+        match *self {
+            Background::Indexed(index) => {
+                let start = index as usize * 3;
+                if start + 3 > palette.len() {
+                    return None;
+                }
+                Some([
+                    palette[start] as u16,
+                    palette[start + 1] as u16,
+                    palette[start + 2] as u16,
+                ])
+            }
+            Background::Grayscale(y) => {
+                let y = magic_color_sample(y, bit_depth);
+                Some([y, y, y])
+            }
+            Background::Rgb(r, g, b) => Some([
+                magic_color_sample(r, bit_depth),
+                magic_color_sample(g, bit_depth),
+                magic_color_sample(b, bit_depth),
+            ]),
+        }
+    }
+}
+
+/// The color that `ImageLoader::set_alpha_flattening()` composites images onto.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FlattenBackground {
+    /// The background color from the `bKGD` chunk of the image, or the given 8-bit red, green,
+    /// and blue samples if it has none.
+    Image([u8; 3]),
+    /// The given 8-bit red, green, and blue samples, regardless of any `bKGD` chunk.
+    Color([u8; 3]),
 }
 
 impl Transparency {
+    /// Parses the magic color in the `tRNS` chunk of a grayscale or RGB image.
+    fn load_magic_color(color_type: ColorType, data: &[u8]) -> Result<Transparency, PngError> {
+        let expected_length = if color_type == ColorType::Grayscale {
+            2
+        } else {
+            6
+        };
+        if data.len() != expected_length {
+            return Err(PngError::InvalidMetadata(format!(
+                "`tRNS` chunk is {} bytes long, but should be {}",
+                data.len(),
+                expected_length
+            )));
+        }
+        if color_type == ColorType::Grayscale {
+            Ok(Transparency::Grayscale(BigEndian::read_u16(&data[0..2])))
+        } else {
+            Ok(Transparency::Rgb(
+                BigEndian::read_u16(&data[0..2]),
+                BigEndian::read_u16(&data[2..4]),
+                BigEndian::read_u16(&data[4..6]),
+            ))
+        }
+    }
+
+    /// Returns the red, green, and blue samples of the magic color in the form that samples of an
+    /// image with the given bit depth take after prediction: 16-bit for 16-bit images and 8-bit
+    /// otherwise, with samples of fewer than 8 bits scaled up to the full range. Returns `None` if
+    /// there is no magic color.
+    pub fn magic_color(&self, bit_depth: u8) -> Option<[u16; 3]> {
+        match *self {
+            Transparency::None | Transparency::Indexed(_) => None,
+            Transparency::Grayscale(y) => {
+                let y = magic_color_sample(y, bit_depth);
+                Some([y, y, y])
+            }
+            Transparency::Rgb(r, g, b) => Some([
+                magic_color_sample(r, bit_depth),
+                magic_color_sample(g, bit_depth),
+                magic_color_sample(b, bit_depth),
+            ]),
+        }
+    }
+
     fn is_none(&self) -> bool {
         match *self {
             Transparency::None => true,
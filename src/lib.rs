@@ -11,6 +11,7 @@ use flate2::DecompressError;
 use std::io;
 
 pub mod capi;
+pub mod colortransform;
 pub mod imageloader;
 pub mod metadata;
 mod prediction;
@@ -24,10 +25,10 @@ pub enum PngError {
     Decompress(DecompressError),
     /// The image loader found image data to decode, but no data provider was attached.
     ///
-    /// When `ImageLoader::add_data()` returns, if `ImageLoader::metadata()` returns a `Some`
-    /// value, a data provider must be attached to the image loader via
-    /// `ImageLoader::set_data_provider()` before `ImageLoader::add_data()` can be successfully
-    /// called again. If this is not done, this error will be returned.
+    /// When `ImageLoader::add_data()` returns `LoadProgress::NeedDataProviderAndMoreData`, a data
+    /// provider must be attached to the image loader via `ImageLoader::set_data_provider()`
+    /// before `ImageLoader::add_data()` can be successfully called again. If this is not done,
+    /// this error will be returned.
     NoDataProvider,
     /// The PNG image had an invalid header. The string contains detailed information about the
     /// error.
@@ -36,6 +37,22 @@ pub enum PngError {
     InvalidScanlinePredictor(u8),
     /// The entropy decoding (`zlib` decompression) failed. This indicates corrupt image data.
     EntropyDecodingError,
+    /// The CRC-32 checksum of a chunk didn't match its contents. This indicates corrupt image
+    /// data. The values are the type of the offending chunk and its byte offset within the stream.
+    ///
+    /// Whether this is reported as an error, a warning, or not at all is controlled by the
+    /// `CrcPolicy` of the image loader.
+    InvalidCrc([u8; 4], u64),
+    /// The buffer supplied to `Image::load_into()` or `Image::load_into_rect()` was too small for
+    /// the image. The value is the number of bytes required.
+    BufferTooSmall(usize),
+    /// The stride supplied to `Image::load_into()` or `Image::load_into_rect()` was too small for a
+    /// scanline of the image, including any horizontal offset into the surface. The value is the
+    /// smallest stride that will do.
+    StrideTooSmall(usize),
+    /// An option supplied to the image loader or in `LoadOptions` was invalid, such as a row
+    /// alignment of zero. The string contains detailed information about the error.
+    InvalidOption(String),
 }
 
 impl From<DecompressError> for PngError {
@@ -3,11 +3,19 @@
 //! This code is derived from code in the `immeta` library: https://github.com/netvl/immeta
 
 use crate::PngError;
-use byteorder::{self, BigEndian, ByteOrder, ReadBytesExt};
+use byteorder::{self, BigEndian, ByteOrder, LittleEndian, ReadBytesExt};
+use flate2::read::ZlibDecoder;
+use flate2::Crc;
 use std::io::Read;
+use std::str;
 
 // 8 for the header; 12 for the chunk info (including CRC); 13 for the header.
 const METADATA_SIZE: usize = 8 + 12 + 13;
+// The `IHDR` chunk immediately follows the 8-byte signature.
+const IHDR_OFFSET: usize = 8;
+/// The largest that compressed text or an ICC profile may become when decompressed, so that a
+/// small chunk can't expand to fill all of memory.
+const MAX_INFLATED_SIZE: u64 = 16 * 1024 * 1024;
 
 /// Represents image dimensions in pixels.
 ///
@@ -155,6 +163,53 @@ impl ChunkHeader {
             chunk_type: [buffer[4], buffer[5], buffer[6], buffer[7]],
         })
     }
+
+    /// Returns true if this chunk is critical to decoding the image, as opposed to ancillary.
+    ///
+    /// Per the PNG specification, this is determined by bit 5 of the first byte of the chunk type.
+    #[inline]
+    pub fn is_critical(&self) -> bool {
+        (self.chunk_type[0] & 0x20) == 0
+    }
+}
+
+/// What to do when the CRC-32 checksum stored after a chunk doesn't match its contents.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum CrcPolicy {
+    /// Fail with a `PngError::InvalidCrc` error.
+    Error,
+    /// Record a `PngError::InvalidCrc` warning and keep decoding.
+    Warn,
+    /// Don't verify checksums at all.
+    Ignore,
+}
+
+impl CrcPolicy {
+    /// Applies this policy to a chunk whose stored checksum is `expected` and whose type and data
+    /// have the checksum `actual`. `offset` is the byte offset of the chunk within the stream.
+    ///
+    /// If the checksums don't match and the policy is `CrcPolicy::Warn`, the warning is appended to
+    /// `warnings`.
+    pub fn check(
+        self,
+        chunk_type: [u8; 4],
+        offset: u64,
+        expected: u32,
+        actual: u32,
+        warnings: &mut Vec<PngError>,
+    ) -> Result<(), PngError> {
+        if expected == actual {
+            return Ok(());
+        }
+        match self {
+            CrcPolicy::Error => Err(PngError::InvalidCrc(chunk_type, offset)),
+            CrcPolicy::Warn => {
+                warnings.push(PngError::InvalidCrc(chunk_type, offset));
+                Ok(())
+            }
+            CrcPolicy::Ignore => Ok(()),
+        }
+    }
 }
 
 /// Metadata found in the PNG header (dimensions, bit depth, etc.)
@@ -164,6 +219,8 @@ pub struct Metadata {
     pub dimensions: Dimensions,
     /// Color type used in the image.
     pub color_type: ColorType,
+    /// Bit depth (bits per sample) used in the image.
+    pub bit_depth: u8,
     /// Color depth (bits per pixel) used in the image.
     pub color_depth: u8,
     /// Compression method used in the image.
@@ -175,7 +232,18 @@ pub struct Metadata {
 }
 
 impl Metadata {
+    /// Reads the PNG signature and the `IHDR` chunk, failing if the `IHDR` checksum doesn't match.
     pub fn load<R: ?Sized + Read>(r: &mut R) -> Result<Metadata, PngError> {
+        Metadata::load_with_crc_policy(r, CrcPolicy::Error, &mut vec![])
+    }
+
+    /// Reads the PNG signature and the `IHDR` chunk, verifying the `IHDR` checksum according to
+    /// `crc_policy`. Any warnings are appended to `warnings`.
+    pub fn load_with_crc_policy<R: ?Sized + Read>(
+        r: &mut R,
+        crc_policy: CrcPolicy,
+        warnings: &mut Vec<PngError>,
+    ) -> Result<Metadata, PngError> {
         let mut buffer = [0u8; METADATA_SIZE];
         r.read_exact(&mut buffer)
             .map_err(|_| format_eof("when reading metadata"))?;
@@ -207,6 +275,12 @@ impl Metadata {
         let height = r
             .read_u32::<BigEndian>()
             .map_byteorder_error("when reading height")?;
+        if width == 0 || height == 0 {
+            return Err(PngError::InvalidMetadata(format!(
+                "invalid image dimensions: {}x{}",
+                width, height
+            )));
+        }
         let bit_depth = r.read_u8().map_byteorder_error("when reading bit depth")?;
         let color_type = r.read_u8().map_byteorder_error("when reading color type")?;
         let compression_method = r
@@ -219,10 +293,21 @@ impl Metadata {
             .read_u8()
             .map_byteorder_error("when reading interlace method")?;
 
-        drop(
-            r.read_u32::<BigEndian>()
-                .map_byteorder_error("when reading metadata CRC")?,
-        );
+        let crc = r
+            .read_u32::<BigEndian>()
+            .map_byteorder_error("when reading metadata CRC")?;
+        if crc_policy != CrcPolicy::Ignore {
+            // The checksum covers the chunk type and data, but not the length.
+            let mut actual_crc = Crc::new();
+            actual_crc.update(&buffer[(IHDR_OFFSET + 4)..(METADATA_SIZE - 4)]);
+            crc_policy.check(
+                chunk_header.chunk_type,
+                IHDR_OFFSET as u64,
+                crc,
+                actual_crc.sum(),
+                warnings,
+            )?;
+        }
 
         Ok(Metadata {
             dimensions: Dimensions {
@@ -232,6 +317,7 @@ impl Metadata {
             color_type: ColorType::from_u8(color_type).ok_or(PngError::InvalidMetadata(
                 format!("invalid color type: {}", color_type),
             ))?,
+            bit_depth,
             color_depth: compute_color_depth(bit_depth, color_type).ok_or(
                 PngError::InvalidMetadata(format!("invalid bit depth: {}", bit_depth)),
             )?,
@@ -254,6 +340,992 @@ impl Metadata {
     }
 }
 
+/// The contents of an `acTL` chunk, which marks an image as an animated PNG.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct AnimationControl {
+    /// The number of frames in the animation.
+    pub num_frames: u32,
+    /// The number of times to play the animation. 0 means that the animation loops forever.
+    pub num_plays: u32,
+}
+
+impl AnimationControl {
+    pub fn load<R: ?Sized + Read>(r: &mut R) -> Result<AnimationControl, PngError> {
+        let num_frames = r
+            .read_u32::<BigEndian>()
+            .map_byteorder_error("when reading frame count")?;
+        let num_plays = r
+            .read_u32::<BigEndian>()
+            .map_byteorder_error("when reading play count")?;
+        if num_frames == 0 {
+            return Err(PngError::InvalidMetadata(
+                "animation has no frames".to_owned(),
+            ));
+        }
+        Ok(AnimationControl {
+            num_frames,
+            num_plays,
+        })
+    }
+}
+
+/// What happens to the region of the canvas covered by an animation frame after the frame is
+/// displayed, before the next frame is rendered.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum DisposeOp {
+    /// The region is left as is.
+    None,
+    /// The region is cleared to fully transparent black.
+    Background,
+    /// The region is reverted to what it was before the frame was rendered.
+    Previous,
+}
+
+impl DisposeOp {
+    fn from_u8(n: u8) -> Option<DisposeOp> {
+        match n {
+            0 => Some(DisposeOp::None),
+            1 => Some(DisposeOp::Background),
+            2 => Some(DisposeOp::Previous),
+            _ => None,
+        }
+    }
+}
+
+/// How an animation frame is combined with the region of the canvas that it covers.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum BlendOp {
+    /// The frame replaces the region, alpha included.
+    Source,
+    /// The frame is alpha-composited over the region.
+    Over,
+}
+
+impl BlendOp {
+    fn from_u8(n: u8) -> Option<BlendOp> {
+        match n {
+            0 => Some(BlendOp::Source),
+            1 => Some(BlendOp::Over),
+            _ => None,
+        }
+    }
+}
+
+/// The contents of an `fcTL` chunk, which describes a single frame of an animated PNG.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct FrameControl {
+    /// The position of this chunk in the sequence of animation chunks.
+    pub sequence_number: u32,
+    /// The width of the frame in pixels.
+    pub width: u32,
+    /// The height of the frame in pixels.
+    pub height: u32,
+    /// The position of the left edge of the frame on the canvas.
+    pub x_offset: u32,
+    /// The position of the top edge of the frame on the canvas.
+    pub y_offset: u32,
+    /// The numerator of the frame delay, in seconds.
+    pub delay_num: u16,
+    /// The denominator of the frame delay, in seconds. 0 is to be treated as 100.
+    pub delay_den: u16,
+    /// What to do with the frame region after displaying the frame.
+    pub dispose_op: DisposeOp,
+    /// How to combine the frame with the canvas.
+    pub blend_op: BlendOp,
+}
+
+impl FrameControl {
+    pub fn load<R: ?Sized + Read>(r: &mut R) -> Result<FrameControl, PngError> {
+        let sequence_number = r
+            .read_u32::<BigEndian>()
+            .map_byteorder_error("when reading frame sequence number")?;
+        let width = r
+            .read_u32::<BigEndian>()
+            .map_byteorder_error("when reading frame width")?;
+        let height = r
+            .read_u32::<BigEndian>()
+            .map_byteorder_error("when reading frame height")?;
+        let x_offset = r
+            .read_u32::<BigEndian>()
+            .map_byteorder_error("when reading frame x offset")?;
+        let y_offset = r
+            .read_u32::<BigEndian>()
+            .map_byteorder_error("when reading frame y offset")?;
+        let delay_num = r
+            .read_u16::<BigEndian>()
+            .map_byteorder_error("when reading frame delay numerator")?;
+        let delay_den = r
+            .read_u16::<BigEndian>()
+            .map_byteorder_error("when reading frame delay denominator")?;
+        let dispose_op = r
+            .read_u8()
+            .map_byteorder_error("when reading frame dispose op")?;
+        let blend_op = r
+            .read_u8()
+            .map_byteorder_error("when reading frame blend op")?;
+
+        Ok(FrameControl {
+            sequence_number,
+            width,
+            height,
+            x_offset,
+            y_offset,
+            delay_num,
+            delay_den,
+            dispose_op: DisposeOp::from_u8(dispose_op).ok_or(PngError::InvalidMetadata(
+                format!("invalid dispose op: {}", dispose_op),
+            ))?,
+            blend_op: BlendOp::from_u8(blend_op).ok_or(PngError::InvalidMetadata(format!(
+                "invalid blend op: {}",
+                blend_op
+            )))?,
+        })
+    }
+
+    /// Returns the time to display this frame for, in seconds.
+    pub fn delay(&self) -> f64 {
+        let delay_den = if self.delay_den == 0 {
+            100
+        } else {
+            self.delay_den
+        };
+        self.delay_num as f64 / delay_den as f64
+    }
+
+    /// Returns the dimensions of this frame.
+    pub fn dimensions(&self) -> Dimensions {
+        Dimensions {
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+/// Color-management information from the `gAMA`, `cHRM`, `sRGB`, `iCCP`, `cICP`, `mDCv`, and
+/// `cLLi` chunks. Each field is `None` if the corresponding chunk is absent.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct ColorManagement {
+    /// The image gamma, from the `gAMA` chunk.
+    pub gamma: Option<Gamma>,
+    /// The chromaticities of the primaries and white point, from the `cHRM` chunk.
+    pub chromaticities: Option<Chromaticities>,
+    /// The rendering intent, from the `sRGB` chunk. If this is present, the image is in the sRGB
+    /// color space.
+    pub srgb_rendering_intent: Option<RenderingIntent>,
+    /// The embedded ICC profile, from the `iCCP` chunk.
+    pub icc_profile: Option<IccProfile>,
+    /// The color primaries and transfer function as video code points, from the `cICP` chunk.
+    pub cicp: Option<CodingIndependentCodePoints>,
+    /// The color volume of the display that HDR content was mastered on, from the `mDCv` chunk.
+    pub mastering_display: Option<MasteringDisplayColorVolume>,
+    /// The light levels of HDR content, from the `cLLi` chunk.
+    pub content_light_level: Option<ContentLightLevel>,
+}
+
+impl ColorManagement {
+    /// Returns true if the `cICP` chunk determines the color space of the image, overriding the
+    /// `iCCP`, `sRGB`, `gAMA`, and `cHRM` chunks, as the PNG specification requires. This is the
+    /// case whenever a `cICP` chunk is present.
+    #[inline]
+    pub fn cicp_takes_precedence(&self) -> bool {
+        self.cicp.is_some()
+    }
+}
+
+/// The contents of a `cICP` chunk: the color space of the image as code points from ITU-T H.273,
+/// as used for video and HDR content.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct CodingIndependentCodePoints {
+    /// The color primaries, such as 1 for BT.709 and sRGB or 9 for BT.2020.
+    pub color_primaries: u8,
+    /// The transfer function, such as 13 for sRGB, 16 for PQ, or 18 for HLG.
+    pub transfer_function: u8,
+    /// The matrix coefficients, which are always 0 (RGB) in PNG.
+    pub matrix_coefficients: u8,
+    /// True if the samples use the full range, false if they use the narrow range of video.
+    pub video_full_range: bool,
+}
+
+impl CodingIndependentCodePoints {
+    pub fn load<R: ?Sized + Read>(r: &mut R) -> Result<CodingIndependentCodePoints, PngError> {
+        let mut data = [0; 4];
+        r.read_exact(&mut data)
+            .map_byteorder_error("when reading coding-independent code points")?;
+        if data[2] != 0 {
+            return Err(PngError::InvalidMetadata(format!(
+                "unsupported matrix coefficients: {}",
+                data[2]
+            )));
+        }
+        let video_full_range = match data[3] {
+            0 => false,
+            1 => true,
+            flag => {
+                return Err(PngError::InvalidMetadata(format!(
+                    "invalid video full range flag: {}",
+                    flag
+                )))
+            }
+        };
+        Ok(CodingIndependentCodePoints {
+            color_primaries: data[0],
+            transfer_function: data[1],
+            matrix_coefficients: data[2],
+            video_full_range,
+        })
+    }
+}
+
+/// The contents of an `mDCv` chunk: the color volume of the display that the image was mastered
+/// on. The chromaticities are CIE 1931 x,y coordinates times 50000, and the luminances are in
+/// units of 0.0001 cd/m², as in the chunk.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct MasteringDisplayColorVolume {
+    pub red: (u16, u16),
+    pub green: (u16, u16),
+    pub blue: (u16, u16),
+    pub white_point: (u16, u16),
+    pub max_luminance: u32,
+    pub min_luminance: u32,
+}
+
+impl MasteringDisplayColorVolume {
+    pub fn load<R: ?Sized + Read>(r: &mut R) -> Result<MasteringDisplayColorVolume, PngError> {
+        let mut chromaticities = [(0, 0); 4];
+        for chromaticity in &mut chromaticities {
+            *chromaticity = (
+                r.read_u16::<BigEndian>()
+                    .map_byteorder_error("when reading mastering display primaries")?,
+                r.read_u16::<BigEndian>()
+                    .map_byteorder_error("when reading mastering display primaries")?,
+            )
+        }
+        Ok(MasteringDisplayColorVolume {
+            red: chromaticities[0],
+            green: chromaticities[1],
+            blue: chromaticities[2],
+            white_point: chromaticities[3],
+            max_luminance: r
+                .read_u32::<BigEndian>()
+                .map_byteorder_error("when reading mastering display luminance")?,
+            min_luminance: r
+                .read_u32::<BigEndian>()
+                .map_byteorder_error("when reading mastering display luminance")?,
+        })
+    }
+
+    /// Returns the maximum and minimum luminance of the display in cd/m².
+    pub fn luminance(&self) -> (f64, f64) {
+        (
+            self.max_luminance as f64 / 10000.0,
+            self.min_luminance as f64 / 10000.0,
+        )
+    }
+}
+
+/// The contents of a `cLLi` chunk: the light levels of HDR content, in units of 0.0001 cd/m², as
+/// in the chunk. A value of 0 means that the level is unknown.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct ContentLightLevel {
+    /// The maximum content light level (MaxCLL): the luminance of the brightest pixel.
+    pub max_content_light_level: u32,
+    /// The maximum frame-average light level (MaxFALL).
+    pub max_frame_average_light_level: u32,
+}
+
+impl ContentLightLevel {
+    pub fn load<R: ?Sized + Read>(r: &mut R) -> Result<ContentLightLevel, PngError> {
+        Ok(ContentLightLevel {
+            max_content_light_level: r
+                .read_u32::<BigEndian>()
+                .map_byteorder_error("when reading content light level")?,
+            max_frame_average_light_level: r
+                .read_u32::<BigEndian>()
+                .map_byteorder_error("when reading content light level")?,
+        })
+    }
+}
+
+/// The contents of a `gAMA` chunk: the exponent relating image samples to the light intensity
+/// they represent.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Gamma {
+    /// The gamma times 100000, as stored in the chunk. For example, 45455 represents a gamma of
+    /// 1/2.2.
+    pub scaled: u32,
+}
+
+impl Gamma {
+    pub fn load<R: ?Sized + Read>(r: &mut R) -> Result<Gamma, PngError> {
+        let scaled = r
+            .read_u32::<BigEndian>()
+            .map_byteorder_error("when reading gamma")?;
+        if scaled == 0 {
+            return Err(PngError::InvalidMetadata("gamma is zero".to_owned()));
+        }
+        Ok(Gamma { scaled })
+    }
+
+    /// Returns the gamma as a floating-point value.
+    #[inline]
+    pub fn value(&self) -> f64 {
+        self.scaled as f64 / 100000.0
+    }
+}
+
+/// The contents of a `cHRM` chunk: the CIE 1931 x,y chromaticities of the white point and the
+/// primaries. Each coordinate is stored times 100000, as in the chunk.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Chromaticities {
+    pub white_point: (u32, u32),
+    pub red: (u32, u32),
+    pub green: (u32, u32),
+    pub blue: (u32, u32),
+}
+
+impl Chromaticities {
+    pub fn load<R: ?Sized + Read>(r: &mut R) -> Result<Chromaticities, PngError> {
+        let mut values = [0; 8];
+        for value in &mut values {
+            *value = r
+                .read_u32::<BigEndian>()
+                .map_byteorder_error("when reading chromaticities")?;
+        }
+        Ok(Chromaticities {
+            white_point: (values[0], values[1]),
+            red: (values[2], values[3]),
+            green: (values[4], values[5]),
+            blue: (values[6], values[7]),
+        })
+    }
+}
+
+/// The rendering intent from an `sRGB` chunk, as defined by the ICC.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RenderingIntent {
+    Perceptual,
+    RelativeColorimetric,
+    Saturation,
+    AbsoluteColorimetric,
+}
+
+impl RenderingIntent {
+    fn from_u8(n: u8) -> Option<RenderingIntent> {
+        match n {
+            0 => Some(RenderingIntent::Perceptual),
+            1 => Some(RenderingIntent::RelativeColorimetric),
+            2 => Some(RenderingIntent::Saturation),
+            3 => Some(RenderingIntent::AbsoluteColorimetric),
+            _ => None,
+        }
+    }
+
+    pub fn load<R: ?Sized + Read>(r: &mut R) -> Result<RenderingIntent, PngError> {
+        let rendering_intent = r
+            .read_u8()
+            .map_byteorder_error("when reading rendering intent")?;
+        RenderingIntent::from_u8(rendering_intent).ok_or(PngError::InvalidMetadata(format!(
+            "invalid rendering intent: {}",
+            rendering_intent
+        )))
+    }
+}
+
+/// The contents of an `iCCP` chunk: an embedded ICC color profile.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct IccProfile {
+    /// The name of the profile, decoded from Latin-1.
+    pub name: String,
+    /// The decompressed profile data.
+    pub profile: Vec<u8>,
+}
+
+impl IccProfile {
+    /// Parses the data of an `iCCP` chunk, decompressing the profile.
+    pub fn load(data: &[u8]) -> Result<IccProfile, PngError> {
+        let (name, data) = split_at_null(data, "ICC profile name")?;
+        if data.is_empty() {
+            return Err(format_eof("when reading ICC profile compression method"));
+        }
+        Ok(IccProfile {
+            name: latin1_to_string(name),
+            profile: inflate(data[0], &data[1..], "ICC profile")?,
+        })
+    }
+}
+
+/// The contents of an `sBIT` chunk: how many of the bits of each sample were significant in the
+/// data that the image was made from, such as 5 or 6 for images from 16-bit displays.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SignificantBits {
+    Grayscale(u8),
+    GrayscaleAlpha(u8, u8),
+    /// The red, green, and blue significant bits, which for indexed images apply to the palette.
+    Rgb(u8, u8, u8),
+    RgbAlpha(u8, u8, u8, u8),
+}
+
+impl SignificantBits {
+    /// Parses the `sBIT` chunk of an image of the given color type and bit depth.
+    pub fn load(
+        color_type: ColorType,
+        bit_depth: u8,
+        data: &[u8],
+    ) -> Result<SignificantBits, PngError> {
+        let expected_length = match color_type {
+            ColorType::Grayscale => 1,
+            ColorType::GrayscaleAlpha => 2,
+            ColorType::Rgb | ColorType::Indexed => 3,
+            ColorType::RgbAlpha => 4,
+        };
+        if data.len() != expected_length {
+            return Err(PngError::InvalidMetadata(format!(
+                "`sBIT` chunk is {} bytes long, but should be {}",
+                data.len(),
+                expected_length
+            )));
+        }
+        let sample_depth = SignificantBits::sample_depth(color_type, bit_depth);
+        if let Some(&bits) = data.iter().find(|&&bits| bits == 0 || bits > sample_depth) {
+            return Err(PngError::InvalidMetadata(format!(
+                "invalid number of significant bits for a sample depth of {}: {}",
+                sample_depth, bits
+            )));
+        }
+        Ok(match color_type {
+            ColorType::Grayscale => SignificantBits::Grayscale(data[0]),
+            ColorType::GrayscaleAlpha => SignificantBits::GrayscaleAlpha(data[0], data[1]),
+            ColorType::Rgb | ColorType::Indexed => SignificantBits::Rgb(data[0], data[1], data[2]),
+            ColorType::RgbAlpha => SignificantBits::RgbAlpha(data[0], data[1], data[2], data[3]),
+        })
+    }
+
+    /// Returns the depth of the samples that the significant bits are out of: that of the palette
+    /// for indexed images, and that of the image for all others.
+    pub fn sample_depth(color_type: ColorType, bit_depth: u8) -> u8 {
+        match color_type {
+            ColorType::Indexed => 8,
+            _ => bit_depth,
+        }
+    }
+
+    /// Returns the significant bits of the red, green, blue, and alpha samples, given the sample
+    /// depth. Grayscale is reported as red, green, and blue alike, and alpha that isn't stored in
+    /// the image has all of its bits significant.
+    pub fn rgba(&self, sample_depth: u8) -> [u8; 4] {
+        match *self {
+            SignificantBits::Grayscale(y) => [y, y, y, sample_depth],
+            SignificantBits::GrayscaleAlpha(y, a) => [y, y, y, a],
+            SignificantBits::Rgb(r, g, b) => [r, g, b, sample_depth],
+            SignificantBits::RgbAlpha(r, g, b, a) => [r, g, b, a],
+        }
+    }
+}
+
+/// The contents of a `pHYs` chunk: the intended pixel density or aspect ratio of the image.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct PhysicalDimensions {
+    pub pixels_per_unit_x: u32,
+    pub pixels_per_unit_y: u32,
+    pub unit: PhysicalUnit,
+}
+
+/// The unit of the pixel density in a `pHYs` chunk.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PhysicalUnit {
+    /// The unit is unknown, so only the aspect ratio of the pixels is given.
+    Unknown,
+    /// The density is in pixels per meter.
+    Meter,
+}
+
+/// The number of meters in an inch.
+const METERS_PER_INCH: f64 = 0.0254;
+
+impl PhysicalDimensions {
+    pub fn load<R: ?Sized + Read>(r: &mut R) -> Result<PhysicalDimensions, PngError> {
+        let pixels_per_unit_x = r
+            .read_u32::<BigEndian>()
+            .map_byteorder_error("when reading physical dimensions")?;
+        let pixels_per_unit_y = r
+            .read_u32::<BigEndian>()
+            .map_byteorder_error("when reading physical dimensions")?;
+        let unit = match r
+            .read_u8()
+            .map_byteorder_error("when reading physical dimensions")?
+        {
+            0 => PhysicalUnit::Unknown,
+            1 => PhysicalUnit::Meter,
+            unit => {
+                return Err(PngError::InvalidMetadata(format!(
+                    "invalid physical unit: {}",
+                    unit
+                )))
+            }
+        };
+        Ok(PhysicalDimensions {
+            pixels_per_unit_x,
+            pixels_per_unit_y,
+            unit,
+        })
+    }
+
+    /// Returns the horizontal and vertical pixel density in dots per inch, if the unit is known.
+    pub fn dpi(&self) -> Option<(f64, f64)> {
+        match self.unit {
+            PhysicalUnit::Unknown => None,
+            PhysicalUnit::Meter => Some((
+                self.pixels_per_unit_x as f64 * METERS_PER_INCH,
+                self.pixels_per_unit_y as f64 * METERS_PER_INCH,
+            )),
+        }
+    }
+}
+
+/// The contents of an `sCAL` chunk: the physical size of the subject that each pixel of the image
+/// covers, typically for maps and scientific images. This says nothing about the density at which
+/// the image is to be printed or displayed.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PhysicalScale {
+    /// The width of a pixel, in `unit`s.
+    pub pixel_width: f64,
+    /// The height of a pixel, in `unit`s.
+    pub pixel_height: f64,
+    pub unit: ScaleUnit,
+}
+
+/// The unit of the pixel size in an `sCAL` chunk.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ScaleUnit {
+    Meter,
+    Radian,
+}
+
+impl PhysicalScale {
+    pub fn load(data: &[u8]) -> Result<PhysicalScale, PngError> {
+        if data.is_empty() {
+            return Err(format_eof("when reading physical scale unit"));
+        }
+        let unit = match data[0] {
+            1 => ScaleUnit::Meter,
+            2 => ScaleUnit::Radian,
+            unit => {
+                return Err(PngError::InvalidMetadata(format!(
+                    "invalid physical scale unit: {}",
+                    unit
+                )))
+            }
+        };
+        let (pixel_width, pixel_height) = split_at_null(&data[1..], "physical scale width")?;
+        Ok(PhysicalScale {
+            pixel_width: parse_scale(pixel_width)?,
+            pixel_height: parse_scale(pixel_height)?,
+            unit,
+        })
+    }
+}
+
+/// Parses a pixel size from an `sCAL` chunk, which must be a positive number in ASCII.
+fn parse_scale(data: &[u8]) -> Result<f64, PngError> {
+    match str::from_utf8(data)
+        .ok()
+        .and_then(|text| text.parse::<f64>().ok())
+    {
+        Some(value) if value > 0.0 && value.is_finite() => Ok(value),
+        _ => Err(PngError::InvalidMetadata(format!(
+            "invalid physical scale: {}",
+            latin1_to_string(data)
+        ))),
+    }
+}
+
+/// A keyword/value pair from a `tEXt`, `zTXt`, or `iTXt` chunk.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct TextChunk {
+    /// The keyword, such as `Title` or `Copyright`, which indicates what the text is.
+    pub keyword: String,
+    /// The text itself, decompressed if necessary.
+    pub text: String,
+    /// The language of the text as an RFC 1766 tag, such as `en-US`. This is present only for
+    /// `iTXt` chunks, and may be empty if the language is unknown.
+    pub language_tag: Option<String>,
+    /// The keyword translated into the language of the text. This is present only for `iTXt`
+    /// chunks, and may be empty.
+    pub translated_keyword: Option<String>,
+    /// True if the text was stored compressed.
+    pub compressed: bool,
+}
+
+impl TextChunk {
+    /// Parses the data of a `tEXt`, `zTXt`, or `iTXt` chunk, as specified by `chunk_type`.
+    ///
+    /// The keyword and the text of `tEXt` and `zTXt` chunks are Latin-1, while the text and the
+    /// translated keyword of `iTXt` chunks are UTF-8. All are converted to Rust strings.
+    pub fn load(chunk_type: &[u8; 4], data: &[u8]) -> Result<TextChunk, PngError> {
+        let (keyword, data) = split_at_null(data, "text keyword")?;
+        if keyword.is_empty() || keyword.len() > 79 {
+            return Err(PngError::InvalidMetadata(format!(
+                "invalid text keyword length: {}",
+                keyword.len()
+            )));
+        }
+        let keyword = latin1_to_string(keyword);
+
+        match chunk_type {
+            b"tEXt" => Ok(TextChunk {
+                keyword,
+                text: latin1_to_string(data),
+                language_tag: None,
+                translated_keyword: None,
+                compressed: false,
+            }),
+            b"zTXt" => {
+                if data.is_empty() {
+                    return Err(format_eof("when reading text compression method"));
+                }
+                Ok(TextChunk {
+                    keyword,
+                    text: latin1_to_string(&inflate(data[0], &data[1..], "text")?),
+                    language_tag: None,
+                    translated_keyword: None,
+                    compressed: true,
+                })
+            }
+            b"iTXt" => {
+                if data.len() < 2 {
+                    return Err(format_eof("when reading text compression flag"));
+                }
+                let (compression_flag, compression_method) = (data[0], data[1]);
+                let (language_tag, data) = split_at_null(&data[2..], "text language tag")?;
+                let (translated_keyword, data) = split_at_null(data, "text translated keyword")?;
+                let compressed = match compression_flag {
+                    0 => false,
+                    1 => true,
+                    _ => {
+                        return Err(PngError::InvalidMetadata(format!(
+                            "invalid text compression flag: {}",
+                            compression_flag
+                        )))
+                    }
+                };
+                let text = if compressed {
+                    inflate(compression_method, data, "text")?
+                } else {
+                    data.to_vec()
+                };
+                Ok(TextChunk {
+                    keyword,
+                    text: utf8_to_string(text, "text")?,
+                    language_tag: Some(latin1_to_string(language_tag)),
+                    translated_keyword: Some(utf8_to_string(
+                        translated_keyword.to_vec(),
+                        "translated keyword",
+                    )?),
+                    compressed,
+                })
+            }
+            _ => Err(PngError::InvalidMetadata(format!(
+                "not a text chunk: {:?}",
+                chunk_type
+            ))),
+        }
+    }
+}
+
+/// The contents of an `eXIf` chunk: Exif metadata, as found in camera images, stored as a TIFF
+/// header followed by image file directories.
+///
+/// Only the first image file directory (IFD0), which describes the image itself, is parsed.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Exif {
+    /// The byte order that the Exif data was stored in. The values of the entries have already
+    /// been converted to native byte order.
+    pub byte_order: ExifByteOrder,
+    /// The entries of IFD0, in the order in which they appear.
+    pub entries: Vec<ExifEntry>,
+}
+
+/// The byte order of Exif data, as given by its TIFF header.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ExifByteOrder {
+    /// `II`: least significant byte first.
+    LittleEndian,
+    /// `MM`: most significant byte first.
+    BigEndian,
+}
+
+/// A tag and its value from an Exif image file directory.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ExifEntry {
+    /// The tag, such as `0x0112` for the orientation, which indicates what the value means.
+    pub tag: u16,
+    /// The value, which may consist of several components.
+    pub value: ExifValue,
+}
+
+/// The value of an Exif entry, decoded according to its TIFF field type.
+#[derive(Clone, PartialEq, Debug)]
+pub enum ExifValue {
+    Byte(Vec<u8>),
+    /// Text, decoded from Latin-1, without the null terminator.
+    Ascii(String),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    /// Fractions, as numerator and denominator.
+    Rational(Vec<(u32, u32)>),
+    SignedByte(Vec<i8>),
+    Undefined(Vec<u8>),
+    SignedShort(Vec<i16>),
+    SignedLong(Vec<i32>),
+    SignedRational(Vec<(i32, i32)>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+    /// A value of a field type that we don't know about, which is given. Since the size of such a
+    /// value is unknown, its data can't be extracted.
+    Unknown(u16),
+}
+
+/// The Exif tag of the orientation of the image.
+pub const EXIF_TAG_ORIENTATION: u16 = 0x0112;
+
+impl Exif {
+    /// Parses the data of an `eXIf` chunk.
+    pub fn load(data: &[u8]) -> Result<Exif, PngError> {
+        if data.len() < 8 {
+            return Err(format_eof("when reading Exif header"));
+        }
+        match &data[0..4] {
+            b"II*\0" => Exif::load_ifd0::<LittleEndian>(data, ExifByteOrder::LittleEndian),
+            b"MM\0*" => Exif::load_ifd0::<BigEndian>(data, ExifByteOrder::BigEndian),
+            _ => Err(PngError::InvalidMetadata("invalid Exif header".to_owned())),
+        }
+    }
+
+    fn load_ifd0<B>(data: &[u8], byte_order: ExifByteOrder) -> Result<Exif, PngError>
+    where
+        B: ByteOrder,
+    {
+        let ifd_offset = B::read_u32(&data[4..8]) as usize;
+        let entry_count = B::read_u16(exif_slice(data, ifd_offset, 2)?) as usize;
+        let mut entries = Vec::with_capacity(entry_count);
+        for index in 0..entry_count {
+            let entry = exif_slice(data, ifd_offset + 2 + 12 * index, 12)?;
+            let (tag, field_type) = (B::read_u16(&entry[0..2]), B::read_u16(&entry[2..4]));
+            let count = B::read_u32(&entry[4..8]) as usize;
+            let component_size = match field_type {
+                1 | 2 | 6 | 7 => 1,
+                3 | 8 => 2,
+                4 | 9 | 11 => 4,
+                5 | 10 | 12 => 8,
+                _ => {
+                    entries.push(ExifEntry {
+                        tag,
+                        value: ExifValue::Unknown(field_type),
+                    });
+                    continue;
+                }
+            };
+            let size = count
+                .checked_mul(component_size)
+                .ok_or_else(|| format_eof("when reading Exif value"))?;
+            // Values that fit in four bytes are stored in the entry itself. Others are stored
+            // elsewhere, at the offset that the entry gives.
+            let value = if size <= 4 {
+                &entry[8..(8 + size)]
+            } else {
+                exif_slice(data, B::read_u32(&entry[8..12]) as usize, size)?
+            };
+            entries.push(ExifEntry {
+                tag,
+                value: ExifValue::load::<B>(field_type, value),
+            })
+        }
+        Ok(Exif {
+            byte_order,
+            entries,
+        })
+    }
+
+    /// Returns the value of the first entry with the given tag, if any.
+    pub fn get(&self, tag: u16) -> Option<&ExifValue> {
+        self.entries
+            .iter()
+            .find(|entry| entry.tag == tag)
+            .map(|entry| &entry.value)
+    }
+
+    /// Returns the orientation of the image, if it has a valid Orientation tag.
+    pub fn orientation(&self) -> Option<Orientation> {
+        match self.get(EXIF_TAG_ORIENTATION) {
+            Some(ExifValue::Short(values)) if values.len() == 1 => Orientation::from_u16(values[0]),
+            _ => None,
+        }
+    }
+}
+
+impl ExifValue {
+    /// Decodes a value of the given field type, whose size has already been checked.
+    fn load<B>(field_type: u16, data: &[u8]) -> ExifValue
+    where
+        B: ByteOrder,
+    {
+        match field_type {
+            1 => ExifValue::Byte(data.to_vec()),
+            2 => {
+                let length = data
+                    .iter()
+                    .position(|&byte| byte == 0)
+                    .unwrap_or(data.len());
+                ExifValue::Ascii(latin1_to_string(&data[0..length]))
+            }
+            3 => ExifValue::Short(data.chunks(2).map(B::read_u16).collect()),
+            4 => ExifValue::Long(data.chunks(4).map(B::read_u32).collect()),
+            5 => ExifValue::Rational(
+                data.chunks(8)
+                    .map(|value| (B::read_u32(&value[0..4]), B::read_u32(&value[4..8])))
+                    .collect(),
+            ),
+            6 => ExifValue::SignedByte(data.iter().map(|&byte| byte as i8).collect()),
+            7 => ExifValue::Undefined(data.to_vec()),
+            8 => ExifValue::SignedShort(data.chunks(2).map(B::read_i16).collect()),
+            9 => ExifValue::SignedLong(data.chunks(4).map(B::read_i32).collect()),
+            10 => ExifValue::SignedRational(
+                data.chunks(8)
+                    .map(|value| (B::read_i32(&value[0..4]), B::read_i32(&value[4..8])))
+                    .collect(),
+            ),
+            11 => ExifValue::Float(data.chunks(4).map(B::read_f32).collect()),
+            12 => ExifValue::Double(data.chunks(8).map(B::read_f64).collect()),
+            _ => ExifValue::Unknown(field_type),
+        }
+    }
+}
+
+/// Returns `length` bytes of Exif data starting at `offset`, or an error if they run past the end.
+fn exif_slice(data: &[u8], offset: usize, length: usize) -> Result<&[u8], PngError> {
+    match offset.checked_add(length) {
+        Some(end) if end <= data.len() => Ok(&data[offset..end]),
+        _ => Err(format_eof("when reading Exif data")),
+    }
+}
+
+/// How the stored pixels of an image must be transformed for display, as given by the Exif
+/// Orientation tag. The rotations are clockwise.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Orientation {
+    /// The image is stored as it is to be displayed.
+    Normal,
+    /// The image must be mirrored left to right.
+    MirrorHorizontal,
+    /// The image must be rotated by 180°.
+    Rotate180,
+    /// The image must be mirrored top to bottom.
+    MirrorVertical,
+    /// The image must be mirrored left to right and then rotated by 270°, which swaps rows and
+    /// columns.
+    Transpose,
+    /// The image must be rotated by 90°.
+    Rotate90,
+    /// The image must be mirrored left to right and then rotated by 90°.
+    Transverse,
+    /// The image must be rotated by 270°.
+    Rotate270,
+}
+
+impl Orientation {
+    /// Converts a value of the Exif Orientation tag, from 1 to 8.
+    pub fn from_u16(value: u16) -> Option<Orientation> {
+        match value {
+            1 => Some(Orientation::Normal),
+            2 => Some(Orientation::MirrorHorizontal),
+            3 => Some(Orientation::Rotate180),
+            4 => Some(Orientation::MirrorVertical),
+            5 => Some(Orientation::Transpose),
+            6 => Some(Orientation::Rotate90),
+            7 => Some(Orientation::Transverse),
+            8 => Some(Orientation::Rotate270),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the width and height of the image are exchanged for display.
+    pub fn swaps_dimensions(self) -> bool {
+        matches!(
+            self,
+            Orientation::Transpose
+                | Orientation::Rotate90
+                | Orientation::Transverse
+                | Orientation::Rotate270
+        )
+    }
+
+    /// Returns the position in the stored image, which is `width` by `height` pixels, of the pixel
+    /// that is displayed at `(x, y)`.
+    pub fn source_position(self, x: u32, y: u32, width: u32, height: u32) -> (u32, u32) {
+        match self {
+            Orientation::Normal => (x, y),
+            Orientation::MirrorHorizontal => (width - 1 - x, y),
+            Orientation::Rotate180 => (width - 1 - x, height - 1 - y),
+            Orientation::MirrorVertical => (x, height - 1 - y),
+            Orientation::Transpose => (y, x),
+            Orientation::Rotate90 => (y, height - 1 - x),
+            Orientation::Transverse => (width - 1 - y, height - 1 - x),
+            Orientation::Rotate270 => (width - 1 - y, x),
+        }
+    }
+}
+
+/// Splits off the null-terminated string at the start of `data`, returning it without the
+/// terminator, along with the rest of the data.
+fn split_at_null<'a>(
+    data: &'a [u8],
+    description: &'static str,
+) -> Result<(&'a [u8], &'a [u8]), PngError> {
+    match data.iter().position(|&byte| byte == 0) {
+        Some(index) => Ok((&data[0..index], &data[(index + 1)..])),
+        None => Err(PngError::InvalidMetadata(format!(
+            "unterminated {}",
+            description
+        ))),
+    }
+}
+
+fn latin1_to_string(data: &[u8]) -> String {
+    data.iter().map(|&byte| byte as char).collect()
+}
+
+fn utf8_to_string(data: Vec<u8>, description: &'static str) -> Result<String, PngError> {
+    String::from_utf8(data)
+        .map_err(|_| PngError::InvalidMetadata(format!("invalid UTF-8 in text {}", description)))
+}
+
+fn inflate(
+    compression_method: u8,
+    data: &[u8],
+    description: &'static str,
+) -> Result<Vec<u8>, PngError> {
+    if CompressionMethod::from_u8(compression_method).is_none() {
+        return Err(PngError::InvalidMetadata(format!(
+            "invalid {} compression method: {}",
+            description, compression_method
+        )));
+    }
+    let mut result = vec![];
+    ZlibDecoder::new(data)
+        .take(MAX_INFLATED_SIZE + 1)
+        .read_to_end(&mut result)
+        .map_err(|error| {
+            PngError::InvalidMetadata(format!("invalid compressed {}: {}", description, error))
+        })?;
+    if result.len() as u64 > MAX_INFLATED_SIZE {
+        return Err(PngError::InvalidMetadata(format!(
+            "decompressed {} is larger than {} bytes",
+            description, MAX_INFLATED_SIZE
+        )));
+    }
+    Ok(result)
+}
+
 trait MapByteOrderError {
     type OkType;
     fn map_byteorder_error(self, description: &'static str) -> Result<Self::OkType, PngError>;
@@ -273,3 +1345,149 @@ impl<T> MapByteOrderError for std::io::Result<T> {
 fn format_eof(description: &'static str) -> PngError {
     PngError::InvalidMetadata(format!("unexpected end of file {}", description))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc_policy_accepts_matching_checksums() {
+        for &policy in &[CrcPolicy::Error, CrcPolicy::Warn, CrcPolicy::Ignore] {
+            let mut warnings = vec![];
+            assert!(policy
+                .check(*b"IDAT", 33, 0x1234_5678, 0x1234_5678, &mut warnings)
+                .is_ok());
+            assert!(warnings.is_empty());
+        }
+    }
+
+    #[test]
+    fn crc_policy_handles_mismatched_checksums() {
+        let mut warnings = vec![];
+        match CrcPolicy::Error.check(*b"IDAT", 33, 1, 2, &mut warnings) {
+            Err(PngError::InvalidCrc(chunk_type, 33)) => assert_eq!(&chunk_type, b"IDAT"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert!(warnings.is_empty());
+
+        assert!(CrcPolicy::Ignore
+            .check(*b"tEXt", 8, 1, 2, &mut warnings)
+            .is_ok());
+        assert!(warnings.is_empty());
+
+        assert!(CrcPolicy::Warn
+            .check(*b"tEXt", 8, 1, 2, &mut warnings)
+            .is_ok());
+        match warnings[..] {
+            [PngError::InvalidCrc(chunk_type, 8)] => assert_eq!(&chunk_type, b"tEXt"),
+            _ => panic!("unexpected warnings: {:?}", warnings),
+        }
+    }
+
+    #[test]
+    fn ihdr_checksum_follows_policy() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\0\x02\0\0\0\x03\x08\x06\0\0\0".to_vec();
+        let mut crc = Crc::new();
+        crc.update(&png[12..]);
+        png.extend_from_slice(&crc.sum().to_be_bytes());
+        let metadata = Metadata::load(&mut &png[..]).unwrap();
+        assert_eq!(metadata.dimensions.width, 2);
+        assert_eq!(metadata.dimensions.height, 3);
+
+        let last = png.len() - 1;
+        png[last] ^= 1;
+        assert!(Metadata::load(&mut &png[..]).is_err());
+        let mut warnings = vec![];
+        assert!(
+            Metadata::load_with_crc_policy(&mut &png[..], CrcPolicy::Warn, &mut warnings).is_ok()
+        );
+        assert_eq!(warnings.len(), 1);
+        let mut warnings = vec![];
+        assert!(
+            Metadata::load_with_crc_policy(&mut &png[..], CrcPolicy::Ignore, &mut warnings).is_ok()
+        );
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn exif_little_endian_orientation() {
+        let data = b"II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0\x06\0\0\0\0\0\0\0";
+        let exif = Exif::load(data).unwrap();
+        assert_eq!(exif.byte_order, ExifByteOrder::LittleEndian);
+        assert_eq!(
+            exif.get(EXIF_TAG_ORIENTATION),
+            Some(&ExifValue::Short(vec![6]))
+        );
+        assert_eq!(exif.orientation(), Some(Orientation::Rotate90));
+    }
+
+    #[test]
+    fn exif_big_endian_values_stored_elsewhere() {
+        // Two entries: an ASCII string too long to fit in the entry, and an invalid orientation.
+        let mut data = b"MM\0*\0\0\0\x08\0\x02".to_vec();
+        data.extend_from_slice(b"\x01\x0f\0\x02\0\0\0\x07\0\0\0\x26");
+        data.extend_from_slice(b"\x01\x12\0\x03\0\0\0\x01\0\x09\0\0");
+        data.extend_from_slice(b"\0\0\0\0Camera\0");
+        let exif = Exif::load(&data).unwrap();
+        assert_eq!(exif.byte_order, ExifByteOrder::BigEndian);
+        assert_eq!(
+            exif.entries[0],
+            ExifEntry {
+                tag: 0x010f,
+                value: ExifValue::Ascii("Camera".to_owned()),
+            }
+        );
+        assert_eq!(
+            exif.get(EXIF_TAG_ORIENTATION),
+            Some(&ExifValue::Short(vec![9]))
+        );
+        assert_eq!(exif.orientation(), None);
+
+        // A value that runs past the end of the data is an error.
+        data.truncate(data.len() - 2);
+        assert!(Exif::load(&data).is_err());
+        assert!(Exif::load(b"XX*\0\x08\0\0\0").is_err());
+    }
+
+    #[test]
+    fn orientation_from_exif_values() {
+        assert_eq!(Orientation::from_u16(0), None);
+        assert_eq!(Orientation::from_u16(1), Some(Orientation::Normal));
+        assert_eq!(Orientation::from_u16(3), Some(Orientation::Rotate180));
+        assert_eq!(Orientation::from_u16(5), Some(Orientation::Transpose));
+        assert_eq!(Orientation::from_u16(8), Some(Orientation::Rotate270));
+        assert_eq!(Orientation::from_u16(9), None);
+    }
+
+    #[test]
+    fn orientation_source_positions() {
+        // A 3x2 image stored as 2x3, with the top left pixel displayed at the bottom left.
+        assert_eq!(Orientation::Rotate90.source_position(0, 0, 2, 3), (0, 2));
+        assert_eq!(Orientation::Rotate90.source_position(2, 0, 2, 3), (0, 0));
+        assert_eq!(Orientation::Rotate270.source_position(0, 0, 2, 3), (1, 0));
+        assert_eq!(
+            Orientation::MirrorHorizontal.source_position(0, 1, 2, 3),
+            (1, 1)
+        );
+
+        // Every orientation maps the displayed pixels onto all of the stored ones.
+        for value in 1..9 {
+            let orientation = Orientation::from_u16(value).unwrap();
+            let (width, height) = (2, 3);
+            let (display_width, display_height) = if orientation.swaps_dimensions() {
+                (height, width)
+            } else {
+                (width, height)
+            };
+            let mut seen = vec![false; (width * height) as usize];
+            for y in 0..display_height {
+                for x in 0..display_width {
+                    let (source_x, source_y) = orientation.source_position(x, y, width, height);
+                    assert!(source_x < width && source_y < height);
+                    seen[(source_y * width + source_x) as usize] = true;
+                }
+            }
+            assert!(seen.iter().all(|&seen| seen), "{:?}", orientation);
+        }
+    }
+}
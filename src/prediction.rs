@@ -2,9 +2,12 @@
 //
 // Copyright (c) 2016 Mozilla Foundation
 
-use crate::imageloader::{DataProvider, InterlacingInfo, LevelOfDetail, ScanlinesForPrediction};
-use crate::imageloader::{ScanlinesForRgbaConversion, Transparency};
+use crate::colortransform::ColorTransform;
+use crate::imageloader::{self, ChannelOrder, DataProvider, InterlacingInfo, LevelOfDetail};
+use crate::imageloader::{OutputFormat, PixelFormat, ScanlineForPackedOutput};
+use crate::imageloader::{ScanlinesForPrediction, ScanlinesForRgbaConversion, Transparency};
 use crate::PngError;
+use std::cmp;
 use std::iter;
 use std::mem;
 use std::sync::mpsc::{self, Receiver, Sender};
@@ -33,8 +36,17 @@ pub enum MainThreadToPredictorThreadMsg {
 pub struct PredictionRequest {
     pub width: u32,
     pub height: u32,
+    pub bit_depth: u8,
     pub color_depth: u8,
     pub indexed_color: bool,
+    /// True if the RGBA conversion pass will process the scanlines afterward, in which case they
+    /// are not final after prediction.
+    pub rgba_conversion_pending: bool,
+    /// True if the color samples are to be premultiplied by alpha as soon as each scanline is
+    /// predicted, which is only done if the RGBA conversion pass won't run.
+    pub premultiply_alpha: bool,
+    pub channel_order: ChannelOrder,
+    pub pixel_format: PixelFormat,
     pub scanlines: Vec<ScanlineToPredict>,
 }
 
@@ -43,8 +55,28 @@ pub struct PerformRgbaConversionRequest {
     pub transparency: Transparency,
     pub width: u32,
     pub height: u32,
+    pub bit_depth: u8,
     pub color_depth: u8,
     pub interlaced: bool,
+    /// The exponent to raise color samples to for gamma correction, if any.
+    pub gamma_exponent: Option<f64>,
+    /// True if the color samples are to be premultiplied by alpha.
+    pub premultiply_alpha: bool,
+    pub channel_order: ChannelOrder,
+    pub pixel_format: PixelFormat,
+    pub output_format: OutputFormat,
+    /// True if samples are to be dithered when reduced to a packed output format.
+    pub dither: bool,
+    /// The red, green, and blue samples of the color to composite the image onto, if alpha is to
+    /// be flattened.
+    pub background: Option<[u16; 3]>,
+    /// The significant bits of the red, green, blue, and alpha samples, if samples are to be
+    /// rescaled to the full output range.
+    pub significant_bits: Option<[u8; 4]>,
+    /// The conversion of the color samples to the target color space, if any.
+    pub color_transform: Option<Box<ColorTransform>>,
+    /// The transfer function to decode color samples with to obtain linear light, if any.
+    pub linearization: Option<TransferFunction>,
 }
 
 pub struct ScanlineToPredict {
@@ -94,13 +126,22 @@ fn predictor_thread(
     let mut data_provider: Option<Box<dyn DataProvider>> = None;
     let mut palette: Option<Vec<u8>> = None;
     let mut blank = vec![];
+    // The predictors of each scanline refer to the previous one as it was before premultiplication,
+    // so if we premultiply as we go, we keep a copy of it here.
+    let mut unpremultiplied_reference = vec![];
+    let mut packed_scanlines = PackedScanlines::new();
     while let Ok(msg) = receiver.recv() {
         match msg {
             MainThreadToPredictorThreadMsg::Predict(PredictionRequest {
                 width,
                 height: _,
+                bit_depth,
                 color_depth,
                 indexed_color,
+                rgba_conversion_pending,
+                premultiply_alpha,
+                channel_order,
+                pixel_format,
                 scanlines,
             }) => {
                 let data_provider = match data_provider {
@@ -117,22 +158,48 @@ fn predictor_thread(
                     palette = None
                 }
 
-                let dest_width_in_bytes = width as usize * 4;
+                let dest_color_depth = dest_color_depth(bit_depth, indexed_color, pixel_format);
+                let positions = channel_order.sample_positions();
+                let (color_positions, alpha_position) =
+                    color_and_alpha_positions(pixel_format, &positions);
+
+                // Images that aren't RGBA are put in the requested channel order by the RGBA
+                // conversion pass. RGBA images don't go through that pass unless postprocessing
+                // was requested, so we reorder the channels of the filtered data instead. This is
+                // equivalent to reordering the channels afterward, because all of the PNG
+                // predictors operate on each channel independently, and it lets the predictors
+                // (accelerated or not) write the final layout directly.
+                let reorder_channels = !indexed_color
+                    && color_depth == bit_depth * 4
+                    && channel_order != ChannelOrder::Rgba;
 
                 for ScanlineToPredict {
                     predictor,
-                    data: src,
+                    data: mut src,
                     offset: scanline_offset,
                     lod: scanline_lod,
                     y: scanline_y,
                 } in scanlines
                 {
-                    let prev_scanline_y = if scanline_y == 0 {
+                    // Images with fewer than 8 bits per pixel are predicted against the packed
+                    // copy of the previous scanline that we keep ourselves, so don't bother
+                    // asking the data provider for a reference scanline.
+                    let prev_scanline_y = if scanline_y == 0 || color_depth < 8 {
                         None
                     } else {
                         Some(scanline_y - 1)
                     };
 
+                    let scanline_width = InterlacingInfo::width_of_lod(width, scanline_lod);
+                    let src_width_in_bytes = scanline_width as usize * (color_depth / 8) as usize;
+                    if reorder_channels {
+                        reorder_rgba_channels(
+                            &mut src[scanline_offset..(scanline_offset + src_width_in_bytes)],
+                            channel_order,
+                            (bit_depth / 8) as usize,
+                        )
+                    }
+
                     {
                         let ScanlinesForPrediction {
                             reference_scanline: mut prev,
@@ -144,8 +211,25 @@ fn predictor_thread(
                             scanline_lod,
                             indexed_color,
                         );
+                        // Pixels may be spaced further apart than their size in the destination,
+                        // either because of interlacing or because the data provider has left
+                        // room for a wider output format.
+                        let dest_width_in_bytes = (scanline_width as usize - 1) * stride as usize
+                            + (dest_color_depth / 8) as usize;
+                        // The accelerated implementations work on whole 16-byte blocks, so they
+                        // may only be used if there's room for those in both scanlines. This
+                        // isn't the case if the data provider only gives us the pixels that
+                        // belong to the image, as when decoding into part of a larger surface.
+                        let accelerated_length =
+                            imageloader::align(scanline_width as usize * stride as usize);
                         let mut properly_aligned = true;
                         let prev = match prev {
+                            Some(_) if premultiply_alpha => {
+                                if !slice_is_properly_aligned(&unpremultiplied_reference) {
+                                    properly_aligned = false;
+                                }
+                                &mut unpremultiplied_reference[..]
+                            }
                             Some(ref mut prev) => {
                                 if !slice_is_properly_aligned(prev) {
                                     properly_aligned = false;
@@ -153,7 +237,12 @@ fn predictor_thread(
                                 &mut prev[..]
                             }
                             None => {
-                                blank.extend(iter::repeat(0).take(dest_width_in_bytes as usize));
+                                if blank.len() < accelerated_length {
+                                    blank.extend(iter::repeat_n(
+                                        0,
+                                        accelerated_length - blank.len(),
+                                    ));
+                                }
                                 &mut blank[..]
                             }
                         };
@@ -161,25 +250,64 @@ fn predictor_thread(
                             properly_aligned = false;
                         }
 
-                        if properly_aligned {
+                        let layout = ScanlineLayout {
+                            width: scanline_width,
+                            color_depth,
+                            dest_color_depth,
+                            stride,
+                        };
+
+                        // The accelerated implementations only know about 8-bit samples.
+                        if color_depth < 8 {
+                            packed_scanlines.predict(
+                                predictor,
+                                dest,
+                                &src[scanline_offset..],
+                                scanline_y,
+                                &layout,
+                                indexed_color,
+                            )
+                        } else if properly_aligned
+                            && bit_depth == 8
+                            && dest_color_depth == 32
+                            && dest.len() >= accelerated_length
+                            && prev.len() >= accelerated_length
+                        {
                             predictor.accelerated_predict(
                                 &mut dest[..],
                                 &src[scanline_offset..],
                                 &prev[..],
-                                width,
-                                color_depth,
-                                stride,
+                                &layout,
                             )
                         } else {
                             predictor.predict(
                                 &mut dest[0..dest_width_in_bytes],
-                                &src[scanline_offset..],
+                                &src[scanline_offset..(scanline_offset + src_width_in_bytes)],
                                 &prev[0..dest_width_in_bytes],
                                 color_depth,
+                                dest_color_depth,
                                 stride,
                             );
                         }
 
+                        if premultiply_alpha {
+                            let reference_length = cmp::min(
+                                cmp::max(dest_width_in_bytes, accelerated_length),
+                                dest.len(),
+                            );
+                            unpremultiplied_reference.clear();
+                            unpremultiplied_reference.extend_from_slice(&dest[0..reference_length]);
+                            unpremultiplied_reference
+                                .resize(cmp::max(reference_length, accelerated_length), 0);
+                            premultiply_scanline(
+                                &mut dest[0..dest_width_in_bytes],
+                                stride,
+                                bit_depth,
+                                color_positions,
+                                alpha_position.expect("Premultiplying an image without alpha?!"),
+                            )
+                        }
+
                         sender
                             .send(PredictorThreadToMainThreadMsg::ScanlinePredictionComplete(
                                 scanline_y,
@@ -190,6 +318,9 @@ fn predictor_thread(
                     }
 
                     data_provider.prediction_complete_for_scanline(scanline_y, scanline_lod);
+                    if !rgba_conversion_pending {
+                        data_provider.scanline_complete(scanline_y, scanline_lod)
+                    }
                 }
             }
             MainThreadToPredictorThreadMsg::SetDataProvider(new_data_provider) => {
@@ -201,8 +332,19 @@ fn predictor_thread(
                     transparency,
                     width,
                     height,
+                    bit_depth,
                     color_depth,
                     interlaced,
+                    gamma_exponent,
+                    premultiply_alpha,
+                    channel_order,
+                    pixel_format,
+                    output_format,
+                    dither,
+                    background,
+                    significant_bits,
+                    color_transform,
+                    linearization,
                 },
             ) => {
                 let data_provider = match data_provider {
@@ -220,9 +362,33 @@ fn predictor_thread(
                     &ADAM7_LEVELS_OF_DETAIL[..]
                 };
                 let indexed = rgb_palette.is_some();
+                let gamma_table =
+                    gamma_exponent.map(|gamma_exponent| GammaTable::new(gamma_exponent, bit_depth));
+                let color_transform_table = color_transform
+                    .map(|color_transform| ColorTransformTable::new(&color_transform, bit_depth));
+                let positions = channel_order.sample_positions();
+                let (color_positions, alpha_position) =
+                    color_and_alpha_positions(pixel_format, &positions);
+                let magic_color = transparency.magic_color(bit_depth);
+                let rescaled_samples = significant_bits.map(|significant_bits| {
+                    samples_to_rescale(significant_bits, bit_depth, pixel_format, &positions)
+                });
+                let float_table = match output_format {
+                    OutputFormat::RgbaFloat => Some(FloatTable::new(linearization, bit_depth)),
+                    _ => None,
+                };
+                let output_bytes_per_pixel = match output_format {
+                    OutputFormat::RgbaFloat => 16,
+                    _ => (dest_color_depth(bit_depth, false, pixel_format) / 8) as usize,
+                };
+                let mut packed_scanline = vec![];
 
                 for lod in levels_of_detail {
-                    for scanline_y in 0..height {
+                    let scanline_width = InterlacingInfo::width_of_lod(width, *lod) as usize;
+                    if scanline_width == 0 {
+                        continue;
+                    }
+                    for scanline_y in 0..InterlacingInfo::height_of_lod(height, *lod) {
                         {
                             let ScanlinesForRgbaConversion {
                                 rgba_scanline: dest,
@@ -231,13 +397,25 @@ fn predictor_thread(
                                 indexed_stride: src_stride,
                             } = data_provider
                                 .fetch_scanlines_for_rgba_conversion(scanline_y, *lod, indexed);
-                            let scanline_width = InterlacingInfo::new(scanline_y, color_depth, *lod)
-                                .scanline_width(width, color_depth)
-                                as usize;
-                            let dest_line_stride = (dest_stride as usize) * scanline_width;
-                            let src_line_stride =
-                                src_stride.map(|src_stride| (src_stride as usize) * scanline_width);
+                            let dest_line_stride = (dest_stride as usize) * (scanline_width - 1)
+                                + output_bytes_per_pixel;
+                            let src_line_stride = src_stride
+                                .map(|src_stride| (src_stride as usize) * (scanline_width - 1) + 1);
                             match (&rgb_palette, color_depth) {
+                                // Grayscale images that are delivered as grayscale stay as they
+                                // are, except that magic-color transparency becomes alpha.
+                                (&None, _) if pixel_format != PixelFormat::Rgba => {
+                                    if pixel_format == PixelFormat::GrayscaleAlpha
+                                        && color_depth == bit_depth
+                                    {
+                                        convert_grayscale_to_grayscale_alpha(
+                                            &mut dest[0..dest_line_stride],
+                                            magic_color,
+                                            dest_stride,
+                                            bit_depth,
+                                        )
+                                    }
+                                }
                                 (&Some(ref rgb_palette), _) => {
                                     let src_line_stride = src_line_stride.unwrap();
                                     convert_indexed_to_rgba(
@@ -245,27 +423,135 @@ fn predictor_thread(
                                         &src.as_ref().unwrap()[0..src_line_stride],
                                         &rgb_palette[..],
                                         &transparency,
-                                        color_depth,
                                         dest_stride,
                                         src_stride.unwrap(),
+                                        &positions,
                                     )
                                 }
-                                (&None, 24) => convert_rgb_to_rgba(
+                                (&None, 48) => convert_48bpp_rgb_to_rgba(
                                     &mut dest[0..dest_line_stride],
-                                    &transparency,
+                                    magic_color,
+                                    dest_stride,
+                                    &positions,
                                 ),
-                                (&None, 16) => {
-                                    convert_grayscale_alpha_to_rgba(&mut dest[0..dest_line_stride])
+                                (&None, 32) if bit_depth == 16 => {
+                                    convert_32bpp_grayscale_alpha_to_rgba(
+                                        &mut dest[0..dest_line_stride],
+                                        dest_stride,
+                                        &positions,
+                                    )
                                 }
-                                (&None, 8) => convert_8bpp_grayscale_to_rgba(
+                                // The image is already RGBA. We're only here for
+                                // postprocessing.
+                                (&None, 32) | (&None, 64) => {}
+                                (&None, 24) => convert_rgb_to_rgba(
                                     &mut dest[0..dest_line_stride],
-                                    &transparency,
+                                    magic_color,
+                                    dest_stride,
+                                    &positions,
                                 ),
+                                (&None, 16) if bit_depth == 16 => convert_16bpp_grayscale_to_rgba(
+                                    &mut dest[0..dest_line_stride],
+                                    magic_color,
+                                    dest_stride,
+                                    &positions,
+                                ),
+                                (&None, 16) => convert_grayscale_alpha_to_rgba(
+                                    &mut dest[0..dest_line_stride],
+                                    dest_stride,
+                                    &positions,
+                                ),
+                                (&None, 1) | (&None, 2) | (&None, 4) | (&None, 8) => {
+                                    convert_8bpp_grayscale_to_rgba(
+                                        &mut dest[0..dest_line_stride],
+                                        magic_color,
+                                        dest_stride,
+                                        &positions,
+                                    )
+                                }
                                 (&None, _) => panic!("Unsupported color depth!"),
                             }
+
+                            if let Some(ref rescaled_samples) = rescaled_samples {
+                                rescale_scanline(
+                                    &mut dest[0..dest_line_stride],
+                                    dest_stride,
+                                    bit_depth,
+                                    rescaled_samples,
+                                )
+                            }
+                            if let Some(background) = background {
+                                flatten_scanline(
+                                    &mut dest[0..dest_line_stride],
+                                    dest_stride,
+                                    bit_depth,
+                                    &positions,
+                                    background,
+                                )
+                            }
+                            if let Some(ref color_transform_table) = color_transform_table {
+                                color_transform_table.apply(
+                                    &mut dest[0..dest_line_stride],
+                                    dest_stride,
+                                    bit_depth,
+                                    color_positions,
+                                )
+                            }
+                            if let Some(ref gamma_table) = gamma_table {
+                                gamma_table.apply(
+                                    &mut dest[0..dest_line_stride],
+                                    dest_stride,
+                                    color_positions,
+                                )
+                            }
+                            match (&float_table, alpha_position) {
+                                (&None, Some(alpha_position)) if premultiply_alpha => {
+                                    premultiply_scanline(
+                                        &mut dest[0..dest_line_stride],
+                                        dest_stride,
+                                        bit_depth,
+                                        color_positions,
+                                        alpha_position,
+                                    )
+                                }
+                                (&None, _) => {}
+                                (Some(float_table), _) => float_table.apply(
+                                    &mut dest[0..dest_line_stride],
+                                    dest_stride,
+                                    bit_depth,
+                                    &positions,
+                                    premultiply_alpha,
+                                ),
+                            }
+                            if output_format.is_packed() {
+                                let dither_origin = if dither {
+                                    Some(InterlacingInfo::new(scanline_y, 8, *lod))
+                                } else {
+                                    None
+                                };
+                                pack_scanline(
+                                    &mut packed_scanline,
+                                    &dest[0..dest_line_stride],
+                                    dest_stride,
+                                    bit_depth,
+                                    &positions,
+                                    output_format,
+                                    dither_origin,
+                                )
+                            }
                         }
 
+                        if output_format.is_packed() {
+                            store_packed_scanline(
+                                &mut **data_provider,
+                                &packed_scanline,
+                                scanline_y,
+                                *lod,
+                                indexed,
+                            )
+                        }
                         data_provider.rgba_conversion_complete_for_scanline(scanline_y, *lod);
+                        data_provider.scanline_complete(scanline_y, *lod);
                     }
                 }
 
@@ -282,6 +568,20 @@ fn predictor_thread(
     }
 }
 
+/// Returns the positions of the color samples and of the alpha sample, if any, within each pixel
+/// of the given format, where `positions` are those of the RGBA channels.
+fn color_and_alpha_positions(
+    pixel_format: PixelFormat,
+    positions: &[usize; 4],
+) -> (&[usize], Option<usize>) {
+    match pixel_format {
+        PixelFormat::Rgba => (&positions[0..3], Some(positions[3])),
+        PixelFormat::Grayscale => (&[0], None),
+        PixelFormat::GrayscaleAlpha => (&[0], Some(1)),
+        PixelFormat::Indexed => (&[], None),
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[repr(u8)]
 pub enum Predictor {
@@ -304,20 +604,29 @@ impl Predictor {
         }
     }
 
-    fn predict(self, dest: &mut [u8], src: &[u8], prev: &[u8], color_depth: u8, stride: u8) {
+    fn predict(
+        self,
+        dest: &mut [u8],
+        src: &[u8],
+        prev: &[u8],
+        color_depth: u8,
+        dest_color_depth: u8,
+        stride: u8,
+    ) {
         let color_depth = (color_depth / 8) as usize;
-        let mut a: [u8; 4] = [0; 4];
-        let mut c: [u8; 4] = [0; 4];
+        let dest_color_depth = (dest_color_depth / 8) as usize;
+        let mut a: [u8; 8] = [0; 8];
+        let mut c: [u8; 8] = [0; 8];
         let stride = stride as usize;
 
         // We use iterators here to avoid bounds checks, as this is performance-critical code.
         match self {
             Predictor::None => {
                 for (dest, src) in dest.chunks_mut(stride).zip(src.chunks(color_depth)) {
-                    for (dest, src) in dest.iter_mut().take(4).zip(src.iter()) {
+                    for (dest, src) in dest.iter_mut().take(8).zip(src.iter()) {
                         *dest = *src
                     }
-                    for dest in &mut dest[color_depth..] {
+                    for dest in &mut dest[color_depth..dest_color_depth] {
                         *dest = 0xff
                     }
                 }
@@ -325,12 +634,12 @@ impl Predictor {
             Predictor::Left => {
                 for (dest, src) in dest.chunks_mut(stride).zip(src.chunks(color_depth)) {
                     for (dest, (src, a)) in
-                        dest.iter_mut().take(4).zip(src.iter().zip(a.iter_mut()))
+                        dest.iter_mut().take(8).zip(src.iter().zip(a.iter_mut()))
                     {
                         *a = src.wrapping_add(*a);
                         *dest = *a
                     }
-                    for dest in &mut dest[color_depth..] {
+                    for dest in &mut dest[color_depth..dest_color_depth] {
                         *dest = 0xff
                     }
                 }
@@ -342,12 +651,12 @@ impl Predictor {
                 {
                     for (dest, (src, b)) in dest
                         .iter_mut()
-                        .take(4)
-                        .zip(src.iter().zip(b.iter().take(4)))
+                        .take(8)
+                        .zip(src.iter().zip(b.iter().take(8)))
                     {
                         *dest = src.wrapping_add(*b)
                     }
-                    for dest in &mut dest[color_depth..] {
+                    for dest in &mut dest[color_depth..dest_color_depth] {
                         *dest = 0xff
                     }
                 }
@@ -359,13 +668,13 @@ impl Predictor {
                 {
                     for (dest, (src, (b, a))) in dest
                         .iter_mut()
-                        .take(4)
-                        .zip(src.iter().zip(b.iter().take(4).zip(a.iter_mut())))
+                        .take(8)
+                        .zip(src.iter().zip(b.iter().take(8).zip(a.iter_mut())))
                     {
                         *a = src.wrapping_add((((*a as u16) + (*b as u16)) / 2) as u8);
                         *dest = *a
                     }
-                    for dest in &mut dest[color_depth..] {
+                    for dest in &mut dest[color_depth..dest_color_depth] {
                         *dest = 0xff
                     }
                 }
@@ -377,15 +686,15 @@ impl Predictor {
                 {
                     for (a, (b, (c, (dest, src)))) in a.iter_mut().zip(
                         b.iter()
-                            .take(4)
-                            .zip(c.iter_mut().zip(dest.iter_mut().take(4).zip(src.iter()))),
+                            .take(8)
+                            .zip(c.iter_mut().zip(dest.iter_mut().take(8).zip(src.iter()))),
                     ) {
                         let paeth = paeth(*a, *b, *c);
                         *a = src.wrapping_add(paeth);
                         *c = *b;
                         *dest = *a;
                     }
-                    for dest in &mut dest[color_depth..] {
+                    for dest in &mut dest[color_depth..dest_color_depth] {
                         *dest = 0xff
                     }
                 }
@@ -413,10 +722,14 @@ impl Predictor {
         dest: &mut [u8],
         src: &[u8],
         prev: &[u8],
-        width: u32,
-        color_depth: u8,
-        stride: u8,
+        layout: &ScanlineLayout,
     ) {
+        let ScanlineLayout {
+            width,
+            color_depth,
+            dest_color_depth: color_depth_of_dest,
+            stride,
+        } = *layout;
         // debug_assert!(slice_is_properly_aligned(dest));
         // debug_assert!(slice_is_properly_aligned(src));
         // debug_assert!(slice_is_properly_aligned(prev));
@@ -500,12 +813,89 @@ impl Predictor {
                     dest.as_mut_ptr(),
                     src.as_ptr(),
                     prev.as_ptr(),
-                    (width as u64) * 4,
+                    (width as u64) * (stride as u64),
                     stride as u64,
                 )
             },
-            None => self.predict(dest, src, prev, color_depth, stride),
+            None => self.predict(dest, src, prev, color_depth, color_depth_of_dest, stride),
+        }
+    }
+}
+
+/// The size and spacing of the pixels in a scanline being predicted.
+#[derive(Copy, Clone, Debug)]
+struct ScanlineLayout {
+    /// The width of the scanline in pixels.
+    width: u32,
+    /// The number of bits per pixel in the filtered data.
+    color_depth: u8,
+    /// The number of bits per pixel that the predictor writes.
+    dest_color_depth: u8,
+    /// The distance in bytes between the starts of adjacent pixels in the destination.
+    stride: u8,
+}
+
+/// Storage for prediction of images with fewer than 8 bits per pixel.
+///
+/// Prediction for these images operates on whole bytes, each of which contains several pixels, so
+/// we can't predict directly into the unpacked scanlines that the data provider gives us. Instead
+/// we predict into a packed scanline and keep it around to serve as the reference for the next one.
+struct PackedScanlines {
+    reference: Vec<u8>,
+    current: Vec<u8>,
+}
+
+impl PackedScanlines {
+    fn new() -> PackedScanlines {
+        PackedScanlines {
+            reference: vec![],
+            current: vec![],
+        }
+    }
+
+    /// Predicts a scanline and unpacks it into `dest`, writing each value into the first byte of
+    /// each pixel. Grayscale values are scaled up to the full 8-bit range, while palette indices
+    /// are written as is.
+    fn predict(
+        &mut self,
+        predictor: Predictor,
+        dest: &mut [u8],
+        src: &[u8],
+        y: u32,
+        layout: &ScanlineLayout,
+        indexed: bool,
+    ) {
+        // These images have only one sample per pixel, so the bit depth is the color depth.
+        let (width, bit_depth, stride) = (layout.width, layout.color_depth, layout.stride);
+        let packed_width_in_bytes = (width as usize * bit_depth as usize).div_ceil(8);
+        if y == 0 {
+            self.reference.clear();
+        }
+        self.reference.resize(packed_width_in_bytes, 0);
+        self.current.resize(packed_width_in_bytes, 0);
+
+        predictor.predict(
+            &mut self.current[..],
+            &src[0..packed_width_in_bytes],
+            &self.reference[..],
+            8,
+            8,
+            1,
+        );
+
+        let pixels_per_byte = (8 / bit_depth) as usize;
+        let mask = (1 << bit_depth) - 1;
+        let scale = if indexed { 1 } else { 0xff / mask };
+        for (x, dest) in dest
+            .chunks_mut(stride as usize)
+            .take(width as usize)
+            .enumerate()
+        {
+            let shift = 8 - bit_depth * ((x % pixels_per_byte) as u8 + 1);
+            dest[0] = ((self.current[x / pixels_per_byte] >> shift) & mask) * scale
         }
+
+        mem::swap(&mut self.reference, &mut self.current)
     }
 }
 
@@ -516,18 +906,25 @@ fn convert_indexed_to_rgba(
     src: &[u8],
     rgb_palette: &[u8],
     transparency: &Transparency,
-    _: u8,
     dest_stride: u8,
     src_stride: u8,
+    positions: &[usize; 4],
 ) {
-    // TODO(pcwalton): Support 1bpp, 2bpp, and 4bpp indexed color.
     for (dest, src) in dest
         .chunks_mut(dest_stride as usize)
         .zip(src.chunks(src_stride as usize))
     {
+        // Like libpng, treat indices past the end of the palette as opaque black rather than
+        // failing.
         let start = 3 * (src[0] as usize);
-        dest[0..3].clone_from_slice(&rgb_palette[start..(start + 3)]);
-        dest[3] = match *transparency {
+        let (r, g, b) = match rgb_palette.get(start..(start + 3)) {
+            Some(rgb) => (rgb[0], rgb[1], rgb[2]),
+            None => {
+                store_32bpp_pixel(dest, [0, 0, 0, 0xff], positions);
+                continue;
+            }
+        };
+        let a = match *transparency {
             Transparency::None => 0xff,
             Transparency::Indexed(ref palette) => {
                 let index = src[0] as usize;
@@ -537,58 +934,731 @@ fn convert_indexed_to_rgba(
                     0xff
                 }
             }
-            Transparency::MagicColor(..) => {
+            Transparency::Grayscale(_) | Transparency::Rgb(..) => {
                 panic!("Can't have magic color transparency in indexed color images!")
             }
-        }
+        };
+        store_32bpp_pixel(dest, [r, g, b, a], positions)
     }
 }
 
 /// TODO(pcwalton): Use SIMD for this.
 #[inline(never)]
-fn convert_rgb_to_rgba(scanline: &mut [u8], transparency: &Transparency) {
-    match *transparency {
-        Transparency::None => {}
-        Transparency::MagicColor(r, g, b) => {
-            for color in scanline.chunks_mut(4) {
-                color[3] = if color[0] == r && color[1] == g && color[2] == b {
-                    0
-                } else {
-                    0xff
-                };
-            }
-        }
-        Transparency::Indexed(_) => panic!("Can't have indexed transparency in an RGB image!"),
-    };
+fn convert_rgb_to_rgba(
+    scanline: &mut [u8],
+    magic_color: Option<[u16; 3]>,
+    stride: u8,
+    positions: &[usize; 4],
+) {
+    if magic_color.is_none() && *positions == [0, 1, 2, 3] {
+        return;
+    }
+    for color in scanline.chunks_mut(stride as usize) {
+        let (r, g, b) = (color[0], color[1], color[2]);
+        let a = match magic_color {
+            Some(magic_color) if magic_color == [r as u16, g as u16, b as u16] => 0,
+            _ => 0xff,
+        };
+        store_32bpp_pixel(color, [r, g, b, a], positions)
+    }
 }
 
 /// TODO(pcwalton): Use SIMD for this. Greyscale images are pretty rare, so it's not a priority,
 /// but it would be nice.
 #[inline(never)]
-fn convert_grayscale_alpha_to_rgba(scanline: &mut [u8]) {
-    for color in scanline.chunks_mut(4) {
+fn convert_grayscale_alpha_to_rgba(scanline: &mut [u8], stride: u8, positions: &[usize; 4]) {
+    for color in scanline.chunks_mut(stride as usize) {
         let (y, a) = (color[0], color[1]);
-        color[1] = y;
-        color[2] = y;
-        color[3] = a
+        store_32bpp_pixel(color, [y, y, y, a], positions)
     }
 }
 
+/// Grayscale images with fewer than 8 bits per sample have already been scaled up to 8 bits
+/// during prediction, so this handles them too.
+///
 /// TODO(pcwalton): Use SIMD for this too.
 #[inline(never)]
-fn convert_8bpp_grayscale_to_rgba(scanline: &mut [u8], transparency: &Transparency) {
-    for color in scanline.chunks_mut(4) {
+fn convert_8bpp_grayscale_to_rgba(
+    scanline: &mut [u8],
+    magic_color: Option<[u16; 3]>,
+    stride: u8,
+    positions: &[usize; 4],
+) {
+    for color in scanline.chunks_mut(stride as usize) {
+        let y = color[0];
+        let a = match magic_color {
+            Some(magic_color) if magic_color == [y as u16; 3] => 0,
+            _ => 0xff,
+        };
+        store_32bpp_pixel(color, [y, y, y, a], positions)
+    }
+}
+
+/// Sets the alpha of 16-bit RGB pixels to fully opaque, or fully transparent for pixels of the
+/// magic color.
+#[inline(never)]
+fn convert_48bpp_rgb_to_rgba(
+    scanline: &mut [u8],
+    magic_color: Option<[u16; 3]>,
+    stride: u8,
+    positions: &[usize; 4],
+) {
+    for color in scanline.chunks_mut(stride as usize) {
+        let (r, g, b) = (
+            (color[0], color[1]),
+            (color[2], color[3]),
+            (color[4], color[5]),
+        );
+        let a = match magic_color {
+            Some(magic_color) if magic_color == [sample_16(r), sample_16(g), sample_16(b)] => {
+                (0, 0)
+            }
+            _ => (0xff, 0xff),
+        };
+        store_64bpp_pixel(color, [r, g, b, a], positions)
+    }
+}
+
+#[inline(never)]
+fn convert_32bpp_grayscale_alpha_to_rgba(scanline: &mut [u8], stride: u8, positions: &[usize; 4]) {
+    for color in scanline.chunks_mut(stride as usize) {
+        let (y, a) = ((color[0], color[1]), (color[2], color[3]));
+        store_64bpp_pixel(color, [y, y, y, a], positions)
+    }
+}
+
+#[inline(never)]
+fn convert_16bpp_grayscale_to_rgba(
+    scanline: &mut [u8],
+    magic_color: Option<[u16; 3]>,
+    stride: u8,
+    positions: &[usize; 4],
+) {
+    for color in scanline.chunks_mut(stride as usize) {
+        let y = (color[0], color[1]);
+        let a = match magic_color {
+            Some(magic_color) if magic_color == [sample_16(y); 3] => (0, 0),
+            _ => (0xff, 0xff),
+        };
+        store_64bpp_pixel(color, [y, y, y, a], positions)
+    }
+}
+
+/// Fills in the alpha samples of a scanline of grayscale pixels that are being delivered as
+/// grayscale with alpha, according to the magic color.
+#[inline(never)]
+fn convert_grayscale_to_grayscale_alpha(
+    scanline: &mut [u8],
+    magic_color: Option<[u16; 3]>,
+    stride: u8,
+    bit_depth: u8,
+) {
+    for color in scanline.chunks_mut(stride as usize) {
+        if bit_depth == 16 {
+            let y = sample_16((color[0], color[1]));
+            let a = match magic_color {
+                Some(magic_color) if magic_color == [y; 3] => 0,
+                _ => 0xff,
+            };
+            color[2] = a;
+            color[3] = a;
+            continue;
+        }
         let y = color[0];
-        color[1] = y;
-        color[2] = y;
-        color[3] = y;
-        match *transparency {
-            Transparency::MagicColor(r, g, b) if r == y && g == y && b == y => color[4] = 0,
-            _ => {}
+        color[1] = match magic_color {
+            Some(magic_color) if magic_color == [y as u16; 3] => 0,
+            _ => 0xff,
         }
     }
 }
 
+/// Composites a scanline of RGBA pixels onto a solid background color, leaving them fully opaque.
+#[inline(never)]
+fn flatten_scanline(
+    scanline: &mut [u8],
+    stride: u8,
+    bit_depth: u8,
+    positions: &[usize; 4],
+    background: [u16; 3],
+) {
+    if bit_depth == 16 {
+        for color in scanline.chunks_mut(stride as usize) {
+            let alpha_position = positions[3] * 2;
+            let alpha = sample_16((color[alpha_position], color[alpha_position + 1])) as u32;
+            for (&position, &background) in positions[0..3].iter().zip(background.iter()) {
+                let sample = &mut color[(position * 2)..(position * 2 + 2)];
+                let value = sample_16((sample[0], sample[1])) as u32;
+                let value = composite_sample(value, background as u32, alpha, 0xffff);
+                sample[0] = (value >> 8) as u8;
+                sample[1] = value as u8
+            }
+            color[alpha_position] = 0xff;
+            color[alpha_position + 1] = 0xff
+        }
+        return;
+    }
+
+    for color in scanline.chunks_mut(stride as usize) {
+        let alpha = color[positions[3]] as u32;
+        for (&position, &background) in positions[0..3].iter().zip(background.iter()) {
+            color[position] =
+                composite_sample(color[position] as u32, background as u32, alpha, 0xff) as u8
+        }
+        color[positions[3]] = 0xff
+    }
+}
+
+/// Returns the position of each sample of a converted pixel that has fewer significant bits than
+/// the output has bits per sample, along with its number of significant bits.
+fn samples_to_rescale(
+    significant_bits: [u8; 4],
+    bit_depth: u8,
+    pixel_format: PixelFormat,
+    positions: &[usize; 4],
+) -> Vec<(usize, u8)> {
+    let samples = match pixel_format {
+        PixelFormat::Rgba => positions
+            .iter()
+            .cloned()
+            .zip(significant_bits.iter().cloned())
+            .collect(),
+        PixelFormat::Grayscale => vec![(0, significant_bits[0])],
+        PixelFormat::GrayscaleAlpha => vec![(0, significant_bits[0]), (1, significant_bits[3])],
+        PixelFormat::Indexed => panic!("Preserved indexed images aren't converted!"),
+    };
+    let output_depth = if bit_depth == 16 { 16 } else { 8 };
+    samples
+        .into_iter()
+        .filter(|&(_, bits)| bits < output_depth)
+        .collect()
+}
+
+/// Rescales the given samples of a scanline from their significant bits to the full range.
+///
+/// Samples of less than 8 bits have already been scaled up by bit replication, which leaves their
+/// significant bits at the top, just as encoders store samples with fewer significant bits.
+#[inline(never)]
+fn rescale_scanline(
+    scanline: &mut [u8],
+    stride: u8,
+    bit_depth: u8,
+    rescaled_samples: &[(usize, u8)],
+) {
+    if bit_depth == 16 {
+        for color in scanline.chunks_mut(stride as usize) {
+            for &(position, bits) in rescaled_samples {
+                let sample = &mut color[(position * 2)..(position * 2 + 2)];
+                let value = rescale_sample(sample_16((sample[0], sample[1])) as u32, bits, 16);
+                sample[0] = (value >> 8) as u8;
+                sample[1] = value as u8
+            }
+        }
+        return;
+    }
+
+    for color in scanline.chunks_mut(stride as usize) {
+        for &(position, bits) in rescaled_samples {
+            color[position] = rescale_sample(color[position] as u32, bits, 8) as u8
+        }
+    }
+}
+
+/// Keeps the top `significant_bits` bits of a sample and scales them to the full range of
+/// `bit_depth` bits, rounding to nearest.
+#[inline]
+fn rescale_sample(sample: u32, significant_bits: u8, bit_depth: u8) -> u32 {
+    let max = (1 << significant_bits) - 1;
+    let value = sample >> (bit_depth - significant_bits);
+    (value * ((1 << bit_depth) - 1) + max / 2) / max
+}
+
+/// Blends a sample over a background sample with the given alpha, rounding to nearest.
+#[inline]
+fn composite_sample(sample: u32, background: u32, alpha: u32, max: u32) -> u32 {
+    (sample * alpha + background * (max - alpha) + max / 2) / max
+}
+
+/// Combines the high and low bytes of a big-endian 16-bit sample.
+#[inline]
+fn sample_16((high, low): (u8, u8)) -> u16 {
+    ((high as u16) << 8) | (low as u16)
+}
+
+/// Writes the red, green, blue, and alpha samples of an 8-bit pixel to the given positions.
+#[inline]
+fn store_32bpp_pixel(color: &mut [u8], samples: [u8; 4], positions: &[usize; 4]) {
+    for (&sample, &position) in samples.iter().zip(positions.iter()) {
+        color[position] = sample
+    }
+}
+
+/// Writes the red, green, blue, and alpha samples of a 16-bit pixel, as big-endian byte pairs, to
+/// the given positions.
+#[inline]
+fn store_64bpp_pixel(color: &mut [u8], samples: [(u8, u8); 4], positions: &[usize; 4]) {
+    for (&(high, low), &position) in samples.iter().zip(positions.iter()) {
+        color[position * 2] = high;
+        color[position * 2 + 1] = low
+    }
+}
+
+/// Moves the samples of a scanline of tightly packed RGBA pixels to the positions that the given
+/// channel order calls for.
+#[inline(never)]
+fn reorder_rgba_channels(
+    scanline: &mut [u8],
+    channel_order: ChannelOrder,
+    bytes_per_sample: usize,
+) {
+    let positions = channel_order.sample_positions();
+    let mut pixel = [0; 8];
+    for color in scanline.chunks_mut(bytes_per_sample * 4) {
+        pixel[0..color.len()].copy_from_slice(color);
+        for (channel, &position) in positions.iter().enumerate() {
+            let (from, to) = (channel * bytes_per_sample, position * bytes_per_sample);
+            color[to..(to + bytes_per_sample)]
+                .copy_from_slice(&pixel[from..(from + bytes_per_sample)])
+        }
+    }
+}
+
+/// A lookup table that raises color samples to a power, for gamma correction.
+enum GammaTable {
+    EightBit(Vec<u8>),
+    SixteenBit(Vec<u16>),
+}
+
+impl GammaTable {
+    fn new(exponent: f64, bit_depth: u8) -> GammaTable {
+        if bit_depth == 16 {
+            GammaTable::SixteenBit(
+                (0..0x10000)
+                    .map(|sample| ((sample as f64 / 65535.0).powf(exponent) * 65535.0 + 0.5) as u16)
+                    .collect(),
+            )
+        } else {
+            GammaTable::EightBit(
+                (0..0x100)
+                    .map(|sample| ((sample as f64 / 255.0).powf(exponent) * 255.0 + 0.5) as u8)
+                    .collect(),
+            )
+        }
+    }
+
+    /// Corrects the color samples, but not the alpha, of a scanline of pixels whose color samples
+    /// are at the given positions.
+    #[inline(never)]
+    fn apply(&self, scanline: &mut [u8], stride: u8, color_positions: &[usize]) {
+        match *self {
+            GammaTable::EightBit(ref table) => {
+                for color in scanline.chunks_mut(stride as usize) {
+                    for &position in color_positions {
+                        color[position] = table[color[position] as usize]
+                    }
+                }
+            }
+            GammaTable::SixteenBit(ref table) => {
+                for color in scanline.chunks_mut(stride as usize) {
+                    for &position in color_positions {
+                        let sample = &mut color[(position * 2)..(position * 2 + 2)];
+                        let value = table[((sample[0] as usize) << 8) | (sample[1] as usize)];
+                        sample[0] = (value >> 8) as u8;
+                        sample[1] = value as u8
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The number of intervals in the lookup table that encodes linear light with the sRGB transfer
+/// function. The table is linearly interpolated, and this keeps the error to about half a step of
+/// a 16-bit sample even where the function is steepest.
+const ENCODING_TABLE_INTERVALS: usize = 0x10000;
+
+/// Lookup tables that convert color samples to another color space: one per channel that decodes
+/// samples to linear light, followed by a matrix and a table that encodes linear light with the
+/// sRGB transfer function.
+struct ColorTransformTable {
+    decoding: [Vec<f32>; 3],
+    matrix: [[f32; 3]; 3],
+    encoding: Vec<f32>,
+    max: f32,
+}
+
+impl ColorTransformTable {
+    fn new(color_transform: &ColorTransform, bit_depth: u8) -> ColorTransformTable {
+        let max = if bit_depth == 16 { 65535 } else { 255 };
+        let decoding_table = |channel: usize| {
+            (0..(max + 1))
+                .map(|sample| {
+                    color_transform.curves[channel].to_linear(sample as f64 / max as f64) as f32
+                })
+                .collect()
+        };
+        let mut matrix = [[0.0; 3]; 3];
+        for (row, source_row) in matrix.iter_mut().zip(color_transform.matrix.iter()) {
+            for (value, &source_value) in row.iter_mut().zip(source_row.iter()) {
+                *value = source_value as f32
+            }
+        }
+        ColorTransformTable {
+            decoding: [decoding_table(0), decoding_table(1), decoding_table(2)],
+            matrix,
+            encoding: (0..(ENCODING_TABLE_INTERVALS + 1))
+                .map(|index| {
+                    let value = index as f64 / ENCODING_TABLE_INTERVALS as f64;
+                    let encoded = if value <= 0.0031308 {
+                        value * 12.92
+                    } else {
+                        1.055 * value.powf(1.0 / 2.4) - 0.055
+                    };
+                    encoded as f32
+                })
+                .collect(),
+            max: max as f32,
+        }
+    }
+
+    /// Converts the color samples, but not the alpha, of a scanline of pixels whose color samples
+    /// are at the given positions. Colors outside the target color space are clipped to it.
+    ///
+    /// Grayscale pixels, which have only one color sample, only have their tone curve converted,
+    /// as gray stays gray in every color space.
+    #[inline(never)]
+    fn apply(&self, scanline: &mut [u8], stride: u8, bit_depth: u8, color_positions: &[usize]) {
+        for color in scanline.chunks_mut(stride as usize) {
+            let mut linear = [0.0; 3];
+            for (channel, &position) in color_positions.iter().enumerate() {
+                let sample = if bit_depth == 16 {
+                    ((color[position * 2] as usize) << 8) | (color[position * 2 + 1] as usize)
+                } else {
+                    color[position] as usize
+                };
+                linear[channel] = self.decoding[channel][sample]
+            }
+            if color_positions.len() == 3 {
+                let source = linear;
+                for (value, row) in linear.iter_mut().zip(self.matrix.iter()) {
+                    *value = row[0] * source[0] + row[1] * source[1] + row[2] * source[2]
+                }
+            }
+            for (channel, &position) in color_positions.iter().enumerate() {
+                let value = (self.encode(linear[channel]) * self.max + 0.5) as u32;
+                if bit_depth == 16 {
+                    color[position * 2] = (value >> 8) as u8;
+                    color[position * 2 + 1] = value as u8
+                } else {
+                    color[position] = value as u8
+                }
+            }
+        }
+    }
+
+    /// Encodes linear light, clipped to the range 0.0 to 1.0, with the sRGB transfer function.
+    #[inline]
+    fn encode(&self, value: f32) -> f32 {
+        let position = value.clamp(0.0, 1.0) * ENCODING_TABLE_INTERVALS as f32;
+        let index = (position as usize).min(ENCODING_TABLE_INTERVALS - 1);
+        let fraction = position - index as f32;
+        let (start, end) = (self.encoding[index], self.encoding[index + 1]);
+        start + (end - start) * fraction
+    }
+}
+
+/// A function that maps encoded color samples to linear light.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TransferFunction {
+    /// The piecewise sRGB transfer function.
+    Srgb,
+    /// A simple power function, as declared by a `gAMA` chunk with the given gamma (for example,
+    /// 0.45455).
+    Gamma(f64),
+    /// The transfer function of BT.709 and BT.2020 video.
+    Bt709,
+    /// The perceptual quantizer of SMPTE ST 2084, for HDR content.
+    Pq,
+    /// Hybrid log-gamma, from ITU-R BT.2100, for HDR content.
+    Hlg,
+}
+
+impl TransferFunction {
+    /// Returns the transfer function with the given code point from a `cICP` chunk, if it's one
+    /// that we support.
+    pub fn from_cicp(code_point: u8) -> Option<TransferFunction> {
+        match code_point {
+            1 | 6 | 14 | 15 => Some(TransferFunction::Bt709),
+            8 => Some(TransferFunction::Gamma(1.0)),
+            13 => Some(TransferFunction::Srgb),
+            16 => Some(TransferFunction::Pq),
+            18 => Some(TransferFunction::Hlg),
+            _ => None,
+        }
+    }
+
+    /// Decodes a sample in the range 0.0 to 1.0 to linear light.
+    fn to_linear(self, value: f64) -> f64 {
+        match self {
+            TransferFunction::Srgb if value <= 0.04045 => value / 12.92,
+            TransferFunction::Srgb => ((value + 0.055) / 1.055).powf(2.4),
+            TransferFunction::Gamma(gamma) => value.powf(1.0 / gamma),
+            TransferFunction::Bt709 if value < 0.081 => value / 4.5,
+            TransferFunction::Bt709 => ((value + 0.099) / 1.099).powf(1.0 / 0.45),
+            TransferFunction::Pq => {
+                let power = value.powf(1.0 / PQ_M2);
+                let numerator = (power - PQ_C1).max(0.0);
+                (numerator / (PQ_C2 - PQ_C3 * power)).powf(1.0 / PQ_M1)
+            }
+            TransferFunction::Hlg if value <= 0.5 => value * value / 3.0,
+            TransferFunction::Hlg => (((value - HLG_C) / HLG_A).exp() + HLG_B) / 12.0,
+        }
+    }
+}
+
+// The constants of the PQ and HLG transfer functions, from ITU-R BT.2100.
+const PQ_M1: f64 = 2610.0 / 16384.0;
+const PQ_M2: f64 = 2523.0 / 4096.0 * 128.0;
+const PQ_C1: f64 = 3424.0 / 4096.0;
+const PQ_C2: f64 = 2413.0 / 4096.0 * 32.0;
+const PQ_C3: f64 = 2392.0 / 4096.0 * 32.0;
+const HLG_A: f64 = 0.17883277;
+const HLG_B: f64 = 0.28466892;
+const HLG_C: f64 = 0.55991073;
+
+/// A lookup table that converts integer color samples to floating point, decoding them to linear
+/// light if requested.
+struct FloatTable {
+    colors: Vec<f32>,
+    max: f32,
+}
+
+impl FloatTable {
+    fn new(linearization: Option<TransferFunction>, bit_depth: u8) -> FloatTable {
+        let max = if bit_depth == 16 { 65535 } else { 255 };
+        FloatTable {
+            colors: (0..(max + 1))
+                .map(|sample| {
+                    let value = sample as f64 / max as f64;
+                    match linearization {
+                        None => value as f32,
+                        Some(transfer_function) => transfer_function.to_linear(value) as f32,
+                    }
+                })
+                .collect(),
+            max: max as f32,
+        }
+    }
+
+    /// Expands a scanline of integer RGBA pixels whose samples are at the given positions to
+    /// floating point in place, optionally premultiplying the color samples by alpha.
+    ///
+    /// Each pixel must have 16 bytes of room.
+    #[inline(never)]
+    fn apply(
+        &self,
+        scanline: &mut [u8],
+        stride: u8,
+        bit_depth: u8,
+        positions: &[usize; 4],
+        premultiply_alpha: bool,
+    ) {
+        let alpha_position = positions[3];
+        for color in scanline.chunks_mut(stride as usize) {
+            let mut samples = [0; 4];
+            for (position, sample) in samples.iter_mut().enumerate() {
+                *sample = if bit_depth == 16 {
+                    ((color[position * 2] as usize) << 8) | (color[position * 2 + 1] as usize)
+                } else {
+                    color[position] as usize
+                }
+            }
+
+            let alpha = samples[alpha_position] as f32 / self.max;
+            for (position, &sample) in samples.iter().enumerate() {
+                let value = if position == alpha_position {
+                    alpha
+                } else if premultiply_alpha {
+                    self.colors[sample] * alpha
+                } else {
+                    self.colors[sample]
+                };
+                color[(position * 4)..(position * 4 + 4)].copy_from_slice(&value.to_ne_bytes())
+            }
+        }
+    }
+}
+
+/// Multiplies the color samples of a scanline of pixels whose color and alpha samples are at the
+/// given positions by their alpha.
+#[inline(never)]
+fn premultiply_scanline(
+    scanline: &mut [u8],
+    stride: u8,
+    bit_depth: u8,
+    color_positions: &[usize],
+    alpha_position: usize,
+) {
+    if bit_depth == 16 {
+        for color in scanline.chunks_mut(stride as usize) {
+            let alpha =
+                ((color[alpha_position * 2] as u32) << 8) | (color[alpha_position * 2 + 1] as u32);
+            for &position in color_positions {
+                let sample = &mut color[(position * 2)..(position * 2 + 2)];
+                let value = ((sample[0] as u32) << 8) | (sample[1] as u32);
+                let value = premultiply_sample(value, alpha, 16);
+                sample[0] = (value >> 8) as u8;
+                sample[1] = value as u8
+            }
+        }
+        return;
+    }
+
+    // The accelerated implementations expect RGBA pixels with alpha last. The packed one works on
+    // four pixels at a time, so we finish off any pixels left over afterward.
+    type FnPremultiplyType = unsafe extern "C" fn(*mut u8, *const u8, *const u8, u64, u64);
+    let accelerated_implementation = match (alpha_position, stride) {
+        _ if color_positions.len() != 3 => None,
+        (3, 4) if address_is_properly_aligned(scanline.as_ptr() as usize) => Some((
+            parng_premultiply_scanline_packed_32bpp as FnPremultiplyType,
+            scanline.len() & !0xf,
+        )),
+        (3, 4) => None,
+        (3, _) => Some((
+            parng_premultiply_scanline_strided_32bpp as FnPremultiplyType,
+            scanline.len(),
+        )),
+        _ => None,
+    };
+    let accelerated_length = match accelerated_implementation {
+        Some((accelerated_implementation, accelerated_length)) if accelerated_length > 0 => {
+            unsafe {
+                accelerated_implementation(
+                    scanline.as_mut_ptr(),
+                    scanline.as_ptr(),
+                    scanline.as_ptr(),
+                    accelerated_length as u64,
+                    stride as u64,
+                )
+            }
+            accelerated_length
+        }
+        _ => 0,
+    };
+
+    for color in scanline[accelerated_length..].chunks_mut(stride as usize) {
+        let alpha = color[alpha_position] as u32;
+        for &position in color_positions {
+            color[position] = premultiply_sample(color[position] as u32, alpha, 8) as u8
+        }
+    }
+}
+
+/// Computes `sample * alpha / max`, rounded to the nearest integer, where `max` is the largest
+/// value that a sample of the given number of bits can hold.
+///
+/// This is the same division-free approximation that the accelerated implementations use, which
+/// is exact for all 8- and 16-bit inputs.
+#[inline]
+fn premultiply_sample(sample: u32, alpha: u32, bits: u32) -> u32 {
+    let product = sample * alpha + (1 << (bits - 1));
+    (product + (product >> bits)) >> bits
+}
+
+/// The 4×4 Bayer matrix used for ordered dithering.
+static DITHER_MATRIX: [[u32; 4]; 4] =
+    [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Converts a scanline of integer RGBA pixels whose samples are at the given positions to the
+/// given packed 16-bit format, storing the native-endian results tightly packed in `packed`.
+///
+/// If `dither_origin` is present, it describes the placement of the scanline in the deinterlaced
+/// image, and the color samples are dithered according to the position of each pixel.
+#[inline(never)]
+fn pack_scanline(
+    packed: &mut Vec<u8>,
+    scanline: &[u8],
+    stride: u8,
+    bit_depth: u8,
+    positions: &[usize; 4],
+    output_format: OutputFormat,
+    dither_origin: Option<InterlacingInfo>,
+) {
+    let bits: [u32; 4] = match output_format {
+        OutputFormat::Rgb565 => [5, 6, 5, 0],
+        OutputFormat::Rgba4444 => [4, 4, 4, 4],
+        OutputFormat::Rgba5551 => [5, 5, 5, 1],
+        OutputFormat::Rgba | OutputFormat::RgbaFloat => panic!("Not a packed output format!"),
+    };
+    let max = if bit_depth == 16 { 0xffff } else { 0xff };
+
+    packed.clear();
+    for (x, color) in scanline.chunks(stride as usize).enumerate() {
+        // Each sample is reduced to `floor(sample * new_max / max + threshold)`, where the
+        // threshold is 1/2 unless dithering, in which case it comes from the dither matrix.
+        // Everything is scaled up by 32 to stay in integers.
+        let dither_bias = dither_origin.map(|dither_origin| {
+            let x = dither_origin.offset as usize + x * dither_origin.stride as usize;
+            DITHER_MATRIX[dither_origin.y as usize % 4][x % 4] * 2 + 1
+        });
+
+        let mut pixel = 0;
+        for (channel, (&bits, &position)) in bits.iter().zip(positions.iter()).enumerate() {
+            let sample = if bit_depth == 16 {
+                ((color[position * 2] as u32) << 8) | (color[position * 2 + 1] as u32)
+            } else {
+                color[position] as u32
+            };
+            let bias = match dither_bias {
+                Some(dither_bias) if channel < 3 => dither_bias,
+                _ => 16,
+            };
+            let new_max = (1 << bits) - 1;
+            pixel = (pixel << bits) | ((sample * new_max * 32 + bias * max) / (max * 32))
+        }
+        packed.extend_from_slice(&(pixel as u16).to_ne_bytes())
+    }
+}
+
+/// Writes a scanline of tightly packed 16-bit pixels to the scanline that the data provider
+/// supplies for packed output, or to the start of each pixel of its RGBA scanline if it doesn't
+/// supply one.
+#[inline(never)]
+fn store_packed_scanline(
+    data_provider: &mut dyn DataProvider,
+    packed: &[u8],
+    scanline_y: u32,
+    lod: LevelOfDetail,
+    indexed: bool,
+) {
+    let (dest, dest_stride) = match data_provider.fetch_scanline_for_packed_output(scanline_y, lod)
+    {
+        Some(ScanlineForPackedOutput { scanline, stride }) => (scanline, stride),
+        None => {
+            let ScanlinesForRgbaConversion {
+                rgba_scanline,
+                rgba_stride,
+                ..
+            } = data_provider.fetch_scanlines_for_rgba_conversion(scanline_y, lod, indexed);
+            (rgba_scanline, rgba_stride)
+        }
+    };
+    for (dest, src) in dest.chunks_mut(dest_stride as usize).zip(packed.chunks(2)) {
+        dest[0..2].copy_from_slice(src)
+    }
+}
+
+/// Returns the number of bits that each pixel occupies in the scanlines that the data provider
+/// supplies for prediction.
+fn dest_color_depth(bit_depth: u8, indexed: bool, pixel_format: PixelFormat) -> u8 {
+    if indexed {
+        8
+    } else if bit_depth == 16 {
+        16 * pixel_format.samples_per_pixel()
+    } else {
+        8 * pixel_format.samples_per_pixel()
+    }
+}
+
 fn slice_is_properly_aligned(buffer: &[u8]) -> bool {
     address_is_properly_aligned(buffer.as_ptr() as usize)
         && address_is_properly_aligned(buffer.len())
@@ -760,4 +1830,285 @@ extern "C" {
         length: u64,
         stride: u64,
     );
+    fn parng_premultiply_scanline_packed_32bpp(
+        dest: *mut u8,
+        src: *const u8,
+        prev: *const u8,
+        length: u64,
+        stride: u64,
+    );
+    fn parng_premultiply_scanline_strided_32bpp(
+        dest: *mut u8,
+        src: *const u8,
+        prev: *const u8,
+        length: u64,
+        stride: u64,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn premultiply_sample_rounds_to_nearest() {
+        // The exact result, rounded to the nearest integer. There are no ties, since the maximum
+        // is odd.
+        fn expected(sample: u32, alpha: u32, bits: u32) -> u32 {
+            let (sample, alpha, max) = (sample as u64, alpha as u64, (1u64 << bits) - 1);
+            ((2 * sample * alpha + max) / (2 * max)) as u32
+        }
+
+        for bits in &[1, 2, 4, 8] {
+            for sample in 0..(1 << bits) {
+                for alpha in 0..(1 << bits) {
+                    assert_eq!(
+                        premultiply_sample(sample, alpha, *bits),
+                        expected(sample, alpha, *bits)
+                    );
+                }
+            }
+        }
+        for sample in (0..65536).step_by(97) {
+            for alpha in (0..65536).step_by(89) {
+                assert_eq!(
+                    premultiply_sample(sample, alpha, 16),
+                    expected(sample, alpha, 16)
+                );
+            }
+        }
+        assert_eq!(premultiply_sample(65535, 65535, 16), 65535);
+        assert_eq!(premultiply_sample(65535, 0, 16), 0);
+    }
+
+    /// Returns RGBA pixels covering every combination of an 8-bit sample and alpha value, each
+    /// followed by `padding` bytes.
+    fn premultiplication_test_pixels(padding: usize) -> Vec<u8> {
+        let mut pixels = vec![];
+        for alpha in 0..256 {
+            for sample in 0..256 {
+                let sample = sample as u8;
+                pixels.extend_from_slice(&[sample, !sample, sample ^ 0x55, alpha as u8]);
+                pixels.extend(iter::repeat_n(0xaa, padding))
+            }
+        }
+        pixels
+    }
+
+    /// Premultiplies the pixels with `premultiply_sample()` alone.
+    fn premultiply_with_scalar_code(pixels: &mut [u8], stride: usize) {
+        for pixel in pixels.chunks_mut(stride) {
+            for sample in 0..3 {
+                pixel[sample] = premultiply_sample(pixel[sample] as u32, pixel[3] as u32, 8) as u8
+            }
+        }
+    }
+
+    #[test]
+    fn accelerated_premultiplication_matches_scalar() {
+        // The packed implementation requires 16-byte alignment.
+        let pixels = premultiplication_test_pixels(0);
+        let mut buffer = vec![0; pixels.len() + 16];
+        let offset = buffer.as_ptr().align_offset(16);
+        let accelerated = &mut buffer[offset..(offset + pixels.len())];
+        accelerated.copy_from_slice(&pixels);
+        let mut expected = pixels.clone();
+        premultiply_with_scalar_code(&mut expected, 4);
+        unsafe {
+            parng_premultiply_scanline_packed_32bpp(
+                accelerated.as_mut_ptr(),
+                accelerated.as_ptr(),
+                accelerated.as_ptr(),
+                accelerated.len() as u64,
+                4,
+            )
+        }
+        assert!(accelerated == &expected[..]);
+
+        // The strided implementation is used for levels of detail, so the bytes between the pixels
+        // must be left alone.
+        for &stride in &[8, 16, 32] {
+            let mut accelerated = premultiplication_test_pixels(stride - 4);
+            let mut expected = accelerated.clone();
+            premultiply_with_scalar_code(&mut expected, stride);
+            unsafe {
+                parng_premultiply_scanline_strided_32bpp(
+                    accelerated.as_mut_ptr(),
+                    accelerated.as_ptr(),
+                    accelerated.as_ptr(),
+                    accelerated.len() as u64,
+                    stride as u64,
+                )
+            }
+            assert!(accelerated == expected, "stride {}", stride);
+        }
+    }
+
+    const PACKED_FORMATS: [(OutputFormat, [u32; 4]); 3] = [
+        (OutputFormat::Rgb565, [5, 6, 5, 0]),
+        (OutputFormat::Rgba4444, [4, 4, 4, 4]),
+        (OutputFormat::Rgba5551, [5, 5, 5, 1]),
+    ];
+
+    /// Packs a scanline of 8-bit RGBA pixels and splits the results into their channels.
+    fn pack(
+        pixels: &[[u8; 4]],
+        output_format: OutputFormat,
+        dither_origin: Option<InterlacingInfo>,
+    ) -> Vec<[u32; 4]> {
+        let bits = PACKED_FORMATS
+            .iter()
+            .find(|&&(format, _)| format == output_format)
+            .unwrap()
+            .1;
+        let scanline: Vec<u8> = pixels
+            .iter()
+            .flat_map(|pixel| pixel.iter().cloned())
+            .collect();
+        let mut packed = vec![];
+        pack_scanline(
+            &mut packed,
+            &scanline,
+            4,
+            8,
+            &[0, 1, 2, 3],
+            output_format,
+            dither_origin,
+        );
+        packed
+            .chunks(2)
+            .map(|pixel| {
+                let mut pixel = u16::from_ne_bytes([pixel[0], pixel[1]]) as u32;
+                let mut channels = [0; 4];
+                for channel in (0..4).rev() {
+                    channels[channel] = pixel & ((1 << bits[channel]) - 1);
+                    pixel >>= bits[channel];
+                }
+                channels
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pack_scanline_rounds_to_nearest() {
+        let pixels: Vec<[u8; 4]> = (0..256)
+            .map(|value| {
+                [
+                    value as u8,
+                    255 - value as u8,
+                    (value / 2) as u8,
+                    (value * 7) as u8,
+                ]
+            })
+            .collect();
+        for &(output_format, bits) in &PACKED_FORMATS {
+            for (pixel, packed) in pixels.iter().zip(pack(&pixels, output_format, None)) {
+                for channel in 0..4 {
+                    let max = (1 << bits[channel]) - 1;
+                    let expected = (2 * pixel[channel] as u32 * max + 255) / (2 * 255);
+                    assert_eq!(packed[channel], expected, "{:?} {:?}", output_format, pixel);
+                }
+            }
+        }
+
+        // 16-bit samples are big-endian.
+        let mut packed = vec![];
+        let scanline = [0x80, 0x00, 0xff, 0xff, 0x00, 0x00, 0x12, 0x34];
+        pack_scanline(
+            &mut packed,
+            &scanline,
+            8,
+            16,
+            &[0, 1, 2, 3],
+            OutputFormat::Rgb565,
+            None,
+        );
+        assert_eq!(
+            u16::from_ne_bytes([packed[0], packed[1]]),
+            (16 << 11) | (63 << 5)
+        );
+    }
+
+    #[test]
+    fn pack_scanline_dithers_color_but_not_alpha() {
+        for &(output_format, bits) in &PACKED_FORMATS {
+            for &value in &[0, 1, 64, 100, 128, 200, 254, 255] {
+                // Dither a 4×4 block of a flat color, which covers the whole dither matrix.
+                let row = [[value; 4]; 4];
+                let undithered = pack(&row, output_format, None);
+                let mut sums = [0; 4];
+                for y in 0..4 {
+                    let dither_origin = InterlacingInfo::new(y, 8, LevelOfDetail::None);
+                    for packed in pack(&row, output_format, Some(dither_origin)) {
+                        for channel in 0..4 {
+                            let max = (1 << bits[channel]) - 1;
+                            let exact = value as u32 * max;
+                            if channel == 3 {
+                                assert_eq!(packed[channel], undithered[0][channel]);
+                            } else {
+                                // Each sample is the exact value rounded either down or up.
+                                assert!(packed[channel] * 255 + 255 > exact);
+                                assert!(packed[channel] * 255 < exact + 255);
+                            }
+                            sums[channel] += packed[channel];
+                        }
+                    }
+                }
+                // Across the block, the color samples average out to within a sixteenth of a step
+                // of the exact value.
+                for channel in 0..3 {
+                    let max = (1 << bits[channel]) - 1;
+                    let error = sums[channel] as f64 / 16.0 - value as f64 * max as f64 / 255.0;
+                    assert!(error.abs() <= 1.0 / 16.0, "{:?} {}", output_format, value);
+                }
+            }
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "expected {}, got {}",
+            expected,
+            actual
+        )
+    }
+
+    #[test]
+    fn pq_decodes_to_linear_light() {
+        // The PQ encoding from ITU-R BT.2100, where 1.0 is 10000 cd/m².
+        fn encode(value: f64) -> f64 {
+            let power = value.powf(PQ_M1);
+            ((PQ_C1 + PQ_C2 * power) / (1.0 + PQ_C3 * power)).powf(PQ_M2)
+        }
+
+        assert_eq!(TransferFunction::from_cicp(16), Some(TransferFunction::Pq));
+        assert_close(TransferFunction::Pq.to_linear(0.0), 0.0);
+        assert_close(TransferFunction::Pq.to_linear(1.0), 1.0);
+        // 100 cd/m².
+        assert_close(TransferFunction::Pq.to_linear(0.508078421517399), 0.01);
+        for &value in &[0.0001, 0.001, 0.01, 0.1, 0.25, 0.5, 0.75] {
+            assert_close(TransferFunction::Pq.to_linear(encode(value)), value)
+        }
+    }
+
+    #[test]
+    fn hlg_decodes_to_linear_light() {
+        // The HLG OETF from ITU-R BT.2100.
+        fn encode(value: f64) -> f64 {
+            if value <= 1.0 / 12.0 {
+                (3.0 * value).sqrt()
+            } else {
+                HLG_A * (12.0 * value - HLG_B).ln() + HLG_C
+            }
+        }
+
+        assert_eq!(TransferFunction::from_cicp(18), Some(TransferFunction::Hlg));
+        assert_close(TransferFunction::Hlg.to_linear(0.0), 0.0);
+        assert_close(TransferFunction::Hlg.to_linear(0.5), 1.0 / 12.0);
+        assert_close(TransferFunction::Hlg.to_linear(1.0), 1.0);
+        for &value in &[0.001, 0.05, 1.0 / 12.0, 0.1, 0.25, 0.5, 0.75] {
+            assert_close(TransferFunction::Hlg.to_linear(encode(value)), value)
+        }
+    }
 }
@@ -2,59 +2,271 @@
 //
 // Copyright (c) 2016 Mozilla Foundation
 
-//! A simple API that allocates an in-memory buffer and decodes into it.
+//! A simple API that allocates an in-memory buffer and decodes into it, or decodes into a buffer
+//! supplied by the caller.
 
+use crate::colortransform::TargetColorSpace;
 use crate::imageloader::{
-    self, DataProvider, ImageLoader, InterlacingInfo, LevelOfDetail, LoadProgress,
+    self, ChannelOrder, DataProvider, FlattenBackground, ImageLoader, InterlacingInfo,
+    LevelOfDetail, LoadProgress, OutputFormat, PixelFormat, Transparency,
 };
 use crate::imageloader::{
-    ScanlinesForPrediction, ScanlinesForRgbaConversion, UninitializedExtension,
+    ScanlineForPackedOutput, ScanlinesForPrediction, ScanlinesForRgbaConversion,
+    UninitializedExtension,
 };
-use crate::metadata::ColorType;
+use crate::metadata::Orientation;
+use crate::metadata::{AnimationControl, BlendOp, ColorType, DisposeOp, FrameControl, Metadata};
 use crate::PngError;
-use std::io::{Read, Seek};
+use byteorder::{BigEndian, ByteOrder};
+use std::cmp;
+use std::io::{self, Read, Seek};
 use std::mem;
+use std::slice;
 use std::sync::mpsc::{self, Receiver, Sender};
 
 struct MemoryDataProvider {
-    rgba_pixels: Vec<u8>,
-    indexed_pixels: Vec<u8>,
-    rgba_aligned_stride: usize,
-    indexed_aligned_stride: usize,
+    rgba_pixels: PixelBuffer,
+    indexed_pixels: PixelBuffer,
+    packed_pixels: PixelBuffer,
+    rgba_color_depth: u8,
+    final_pixels: FinalPixels,
     data_sender: Sender<Vec<u8>>,
 }
 
+// The only buffers that aren't owned are the caller's, which `Image::load_into_rect()` guarantees
+// will outlive the data provider.
+unsafe impl Send for MemoryDataProvider {}
+
+/// How a `MemoryDataProvider` lays out the image that it decodes.
+#[derive(Copy, Clone, Debug)]
+struct MemoryLayout {
+    width: u32,
+    height: u32,
+    bit_depth: u8,
+    pixel_format: PixelFormat,
+    /// True if the finished image is in a packed 16-bit output format.
+    packed: bool,
+    /// True if the image has a palette, in which case the indices are decoded into a buffer of
+    /// their own.
+    indexed: bool,
+    /// True if the image is stored bottom-up.
+    flip: bool,
+    /// The number of bytes that the stride of the buffer for the finished image is a multiple of,
+    /// if the data provider allocates it.
+    row_alignment: usize,
+}
+
+/// Which of the buffers of a `MemoryDataProvider` holds the finished image.
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum FinalPixels {
+    Rgba,
+    Indexed,
+    Packed,
+}
+
+/// One of the buffers that a `MemoryDataProvider` decodes into, along with the number of bytes
+/// between successive scanlines in it.
+struct PixelBuffer {
+    storage: PixelStorage,
+    /// The offset of the first pixel of the image within the storage, which is nonzero if the
+    /// image is placed inside a larger surface.
+    origin: usize,
+    stride: usize,
+    /// The number of bytes of each scanline that belong to the image, if the rest of the stride
+    /// belongs to someone else and must not be touched.
+    row_length: Option<usize>,
+    /// The number of scanlines in the image.
+    height: u32,
+    /// If true, scanline `y` of the image is stored in row `height - 1 - y` of the buffer.
+    flipped: bool,
+}
+
+enum PixelStorage {
+    Owned(Vec<u8>),
+    /// Memory belonging to the caller of `Image::load_into()` or `Image::load_into_rect()`, as a
+    /// pointer and a length.
+    Borrowed(*mut u8, usize),
+}
+
+impl PixelBuffer {
+    /// Allocates a buffer of the given number of scanlines, with room for eight pixels past the
+    /// end in case the final scanline consists of a level of detail with a nonzero offset. Tricky!
+    fn new(stride: usize, height: u32, bytes_per_pixel: usize) -> PixelBuffer {
+        let mut pixels = vec![];
+        unsafe {
+            pixels.extend_with_uninitialized(stride * (height as usize) + 8 * bytes_per_pixel)
+        }
+        PixelBuffer {
+            storage: PixelStorage::Owned(pixels),
+            origin: 0,
+            stride,
+            row_length: None,
+            height,
+            flipped: false,
+        }
+    }
+
+    fn empty() -> PixelBuffer {
+        PixelBuffer {
+            storage: PixelStorage::Owned(vec![]),
+            origin: 0,
+            stride: 0,
+            row_length: None,
+            height: 0,
+            flipped: false,
+        }
+    }
+
+    /// Wraps a surface with `rect.stride` bytes between scanlines, of which the image occupies the
+    /// rectangle described by `rect`. The surface must already have been checked to be large
+    /// enough with `SurfaceRect::check()`.
+    fn surface(storage: PixelStorage, rect: SurfaceRect) -> PixelBuffer {
+        PixelBuffer {
+            storage,
+            origin: rect.origin,
+            stride: rect.stride,
+            row_length: Some(rect.row_length),
+            height: rect.height,
+            flipped: false,
+        }
+    }
+
+    fn pixels(&mut self) -> &mut [u8] {
+        match self.storage {
+            PixelStorage::Owned(ref mut pixels) => &mut pixels[..],
+            PixelStorage::Borrowed(pixels, length) => unsafe {
+                slice::from_raw_parts_mut(pixels, length)
+            },
+        }
+    }
+
+    /// Returns the given scanline of the given level of detail and the number of bytes between its
+    /// pixels, for pixels of the given color depth.
+    fn scanline(&mut self, y: u32, color_depth: u8, lod: LevelOfDetail) -> (&mut [u8], u8) {
+        let scanline = InterlacingInfo::new(y, color_depth, lod);
+        let (start, end) = self.scanline_bounds(&scanline);
+        (&mut self.pixels()[start..end], scanline.stride)
+    }
+
+    /// Returns the range of bytes that the given scanline may occupy.
+    ///
+    /// If the image has the buffer to itself, this is a whole stride's worth of bytes starting at
+    /// the first pixel, which lets the accelerated routines write past the last pixel. Otherwise,
+    /// it ends with the image's portion of the scanline. Levels of detail start some pixels into
+    /// the scanline, so in that case it may be empty if the image is narrower than that.
+    fn scanline_bounds(&self, scanline: &InterlacingInfo) -> (usize, usize) {
+        let row = if self.flipped {
+            self.height - 1 - scanline.y
+        } else {
+            scanline.y
+        };
+        let row_start = self.origin + self.stride * row as usize;
+        match self.row_length {
+            None => {
+                let start = row_start + scanline.offset as usize;
+                (start, start + self.stride)
+            }
+            Some(row_length) => (
+                row_start + cmp::min(scanline.offset as usize, row_length),
+                row_start + row_length,
+            ),
+        }
+    }
+
+    /// Takes the pixels out of the buffer, if they're owned by it.
+    fn take(&mut self) -> Vec<u8> {
+        match self.storage {
+            PixelStorage::Owned(ref mut pixels) => mem::take(pixels),
+            PixelStorage::Borrowed(..) => vec![],
+        }
+    }
+}
+
 impl MemoryDataProvider {
+    /// Creates a data provider that decodes into buffers with the given layout that it allocates
+    /// itself, unless `destination` supplies the buffer for the finished image.
     #[inline(never)]
-    pub fn new(width: u32, height: u32, indexed: bool) -> (MemoryDataProvider, Receiver<Vec<u8>>) {
-        let rgba_aligned_stride = imageloader::align(width as usize * 4);
-        let indexed_aligned_stride = imageloader::align(width as usize * 4);
+    pub fn new(
+        layout: MemoryLayout,
+        mut destination: Option<PixelBuffer>,
+    ) -> (MemoryDataProvider, Receiver<Vec<u8>>) {
+        let MemoryLayout {
+            width,
+            height,
+            bit_depth,
+            pixel_format,
+            packed,
+            indexed,
+            flip,
+            row_alignment,
+        } = layout;
+        let rgba_color_depth = rgba_color_depth(bit_depth, pixel_format);
+        let rgba_bytes_per_pixel = rgba_color_depth as usize / 8;
         let (data_sender, data_receiver) = mpsc::channel();
 
-        // We make room for eight pixels past the end in case the final scanline consists of a
-        // level of detail with a nonzero offset. Tricky!
-        let rgba_length = rgba_aligned_stride * (height as usize) + 8 * 4;
-        let indexed_length = if indexed {
-            indexed_aligned_stride * (height as usize) + 8 + 1
+        let final_pixels = if pixel_format == PixelFormat::Indexed {
+            FinalPixels::Indexed
+        } else if packed {
+            FinalPixels::Packed
         } else {
-            0
+            FinalPixels::Rgba
         };
 
-        let (mut rgba_pixels, mut indexed_pixels) = (vec![], vec![]);
-        unsafe {
-            rgba_pixels.extend_with_uninitialized(rgba_length);
-            indexed_pixels.extend_with_uninitialized(indexed_length)
-        }
+        let mut buffer = |role: FinalPixels, bytes_per_pixel: usize| {
+            let mut buffer = if role == final_pixels && destination.is_some() {
+                destination.take().unwrap()
+            } else {
+                // Intermediate buffers are aligned as usual for the benefit of the accelerated
+                // routines.
+                let alignment = if role == final_pixels {
+                    row_alignment
+                } else {
+                    16
+                };
+                let stride = imageloader::align_to(width as usize * bytes_per_pixel, alignment);
+                PixelBuffer::new(stride, height, bytes_per_pixel)
+            };
+            buffer.flipped = flip;
+            buffer
+        };
+        let rgba_pixels = if final_pixels != FinalPixels::Indexed {
+            buffer(FinalPixels::Rgba, rgba_bytes_per_pixel)
+        } else {
+            PixelBuffer::empty()
+        };
+        let indexed_pixels = if indexed {
+            buffer(FinalPixels::Indexed, 1)
+        } else {
+            PixelBuffer::empty()
+        };
+        // The packed pixels are made from the RGBA ones, which are only converted once the
+        // whole image has been predicted, so packed output needs both buffers: 2 bytes per pixel
+        // on top of the usual RGBA ones.
+        let packed_pixels = if final_pixels == FinalPixels::Packed {
+            buffer(FinalPixels::Packed, 2)
+        } else {
+            PixelBuffer::empty()
+        };
 
         let data_provider = MemoryDataProvider {
             rgba_pixels: rgba_pixels,
             indexed_pixels: indexed_pixels,
-            rgba_aligned_stride: rgba_aligned_stride,
-            indexed_aligned_stride: indexed_aligned_stride,
+            packed_pixels,
+            rgba_color_depth,
+            final_pixels,
             data_sender: data_sender,
         };
         (data_provider, data_receiver)
     }
+
+    /// Returns the buffer that holds the finished image.
+    fn final_buffer(&mut self) -> &mut PixelBuffer {
+        match self.final_pixels {
+            FinalPixels::Rgba => &mut self.rgba_pixels,
+            FinalPixels::Indexed => &mut self.indexed_pixels,
+            FinalPixels::Packed => &mut self.packed_pixels,
+        }
+    }
 }
 
 impl DataProvider for MemoryDataProvider {
@@ -65,42 +277,40 @@ impl DataProvider for MemoryDataProvider {
         lod: LevelOfDetail,
         indexed: bool,
     ) -> ScanlinesForPrediction {
-        let buffer_color_depth = buffer_color_depth(indexed);
+        let buffer_color_depth = if indexed { 8 } else { self.rgba_color_depth };
         let reference_scanline = reference_scanline.map(|reference_scanline| {
             InterlacingInfo::new(reference_scanline, buffer_color_depth, lod)
         });
         let current_scanline = InterlacingInfo::new(current_scanline, buffer_color_depth, lod);
 
-        let aligned_stride = if indexed {
-            self.indexed_aligned_stride
-        } else {
-            self.rgba_aligned_stride
-        };
-
-        let split_point = aligned_stride * (current_scanline.y as usize);
-        let dest_pixels = if indexed {
+        let buffer = if indexed {
             &mut self.indexed_pixels
         } else {
             &mut self.rgba_pixels
         };
-        let (head, tail) = dest_pixels.split_at_mut(split_point);
-        let head_length = head.len();
-        let reference_scanline_data = match reference_scanline {
-            None => None,
+        let (current_start, current_end) = buffer.scanline_bounds(&current_scanline);
+        let (reference_scanline_data, current_scanline_data) = match reference_scanline {
+            None => (None, &mut buffer.pixels()[current_start..current_end]),
             Some(reference_scanline) => {
                 debug_assert!(current_scanline.stride == reference_scanline.stride);
-                let start = (reference_scanline.y as usize) * aligned_stride
-                    + (reference_scanline.offset as usize);
-                let end = start + aligned_stride;
-                let slice = &mut head[start..end];
-                Some(slice)
-            }
-        };
-        let start = (current_scanline.y as usize) * aligned_stride
-            + (current_scanline.offset as usize)
-            - head_length;
-        let end = start + aligned_stride;
-        let current_scanline_data = &mut tail[start..end];
+                let (reference_start, reference_end) = buffer.scanline_bounds(&reference_scanline);
+                // The reference scanline precedes the current one in memory, unless the image is
+                // stored bottom-up, in which case it follows it.
+                if reference_start < current_start {
+                    let (head, tail) = buffer.pixels().split_at_mut(current_start);
+                    (
+                        Some(&mut head[reference_start..reference_end]),
+                        &mut tail[0..(current_end - current_start)],
+                    )
+                } else {
+                    let (head, tail) = buffer.pixels().split_at_mut(reference_start);
+                    (
+                        Some(&mut tail[0..(reference_end - reference_start)]),
+                        &mut head[current_start..current_end],
+                    )
+                }
+            }
+        };
         ScanlinesForPrediction {
             reference_scanline: reference_scanline_data,
             current_scanline: current_scanline_data,
@@ -116,47 +326,75 @@ impl DataProvider for MemoryDataProvider {
         lod: LevelOfDetail,
         indexed: bool,
     ) -> ScanlinesForRgbaConversion<'a> {
-        let rgba_scanline = InterlacingInfo::new(scanline, 32, lod);
-        let indexed_scanline = if indexed {
-            Some(InterlacingInfo::new(scanline, 8, lod))
+        let (rgba_scanline, rgba_stride) =
+            self.rgba_pixels
+                .scanline(scanline, self.rgba_color_depth, lod);
+        let (indexed_scanline, indexed_stride) = if indexed {
+            let (indexed_scanline, indexed_stride) = self.indexed_pixels.scanline(scanline, 8, lod);
+            (Some(&*indexed_scanline), Some(indexed_stride))
         } else {
-            None
+            (None, None)
         };
-        let rgba_aligned_stride = self.rgba_aligned_stride;
-        let indexed_aligned_stride = self.indexed_aligned_stride;
         ScanlinesForRgbaConversion {
-            rgba_scanline: &mut self.rgba_pixels[(rgba_aligned_stride * scanline as usize)..],
-            indexed_scanline: if indexed_scanline.is_some() {
-                Some(&self.indexed_pixels[(indexed_aligned_stride * scanline as usize)..])
-            } else {
-                None
-            },
-            rgba_stride: rgba_scanline.stride,
-            indexed_stride: indexed_scanline.map(|indexed_scanline| indexed_scanline.stride),
+            rgba_scanline,
+            indexed_scanline,
+            rgba_stride,
+            indexed_stride,
         }
     }
 
     fn rgba_conversion_complete_for_scanline(&mut self, _: u32, _: LevelOfDetail) {}
 
+    fn fetch_scanline_for_packed_output<'a>(
+        &'a mut self,
+        scanline: u32,
+        lod: LevelOfDetail,
+    ) -> Option<ScanlineForPackedOutput<'a>> {
+        if self.final_pixels != FinalPixels::Packed {
+            return None;
+        }
+        let (scanline, stride) = self.packed_pixels.scanline(scanline, 16, lod);
+        Some(ScanlineForPackedOutput { scanline, stride })
+    }
+
     fn finished(&mut self) {
-        self.data_sender
-            .send(mem::replace(&mut self.rgba_pixels, vec![]))
-            .unwrap()
+        let pixels = self.final_buffer().take();
+        self.data_sender.send(pixels).unwrap()
     }
 }
 
-/// An in-memory decoded image in big-endian RGBA format, 32 bits per pixel.
+/// An in-memory decoded image in big-endian RGBA format (or another channel order, if requested),
+/// 32 bits per pixel (or 64 bits per pixel for images with 16 bits per sample, or 128 bits per
+/// pixel for floating-point output). Grayscale images may instead have one or two samples per
+/// pixel, and indexed images one palette index per pixel, as given by `pixel_format`, and images
+/// in the packed 16-bit formats are as given by `output_format`.
 pub struct Image {
     /// The width of the image, in pixels.
     pub width: u32,
     /// The height of the image, in pixels.
     pub height: u32,
+    /// The number of bits per sample: 8, or 16 if the PNG image had 16 bits per sample, or 32 if
+    /// the samples are native-endian `f32` values because `OutputFormat::RgbaFloat` was requested.
+    /// For the packed 16-bit output formats, this is 16, the size of a whole pixel.
+    pub bit_depth: u8,
+    /// The format of the pixels.
+    pub output_format: OutputFormat,
+    /// The samples that each pixel consists of.
+    pub pixel_format: PixelFormat,
+    /// If `pixel_format` is `PixelFormat::Indexed`, the palette from the `PLTE` chunk, as
+    /// consecutive red, green, and blue samples.
+    pub palette: Option<Vec<u8>>,
+    /// If `pixel_format` is `PixelFormat::Indexed` and the image has a `tRNS` chunk, the alpha of
+    /// each palette entry. Entries past the end of it are opaque.
+    pub palette_alpha: Option<Vec<u8>>,
     /// The number of bytes between successive scanlines. This may be any value greater than or
-    /// equal to `4 * width`.
+    /// equal to the width times the number of bytes per pixel.
     ///
     /// Because of SIMD alignment restrictions, `parng` may well choose a value greater than `4 *
-    /// width` here.
+    /// width` here, unless told otherwise with `LoadOptions::row_alignment`.
     pub stride: usize,
+    /// The order in which the samples of each pixel are stored.
+    pub channel_order: ChannelOrder,
     /// The actual pixels.
     pub pixels: Vec<u8>,
 }
@@ -164,7 +402,9 @@ pub struct Image {
 impl Image {
     /// Allocates space for and loads a PNG image stream from a reader into memory.
     ///
-    /// The returned image is big-endian, 32 bits per pixel RGBA.
+    /// The returned image is big-endian, 32 bits per pixel RGBA. If the PNG image has 16 bits per
+    /// sample, the returned image is instead 64 bits per pixel RGBA, with 16 bits per sample, so
+    /// that no precision is lost.
     ///
     /// This method does not return until the image is fully loaded. If you need a different
     /// in-memory representation, or you need to display the image before it's fully loaded,
@@ -173,44 +413,894 @@ impl Image {
     where
         I: Read + Seek,
     {
+        Image::load_with_options(input, &LoadOptions::default())
+    }
+
+    /// Allocates space for and loads a PNG image stream from a reader into memory, as
+    /// `Image::load()` does, but with the given options.
+    pub fn load_with_options<I>(input: &mut I, options: &LoadOptions) -> Result<Image, PngError>
+    where
+        I: Read + Seek,
+    {
+        Image::decode(input, options, None)
+    }
+
+    /// Loads a PNG image stream from a reader into the given buffer, in the format given by
+    /// `options`, with `stride` bytes between successive scanlines.
+    ///
+    /// The buffer must hold `stride * (height - 1) + width * bytes_per_pixel` bytes, and `stride`
+    /// must be at least `width * bytes_per_pixel`, where `bytes_per_pixel` is the size of a pixel
+    /// in the format that `Image::load_with_options()` would produce. Otherwise,
+    /// `PngError::BufferTooSmall` or `PngError::StrideTooSmall` is returned before any decoding
+    /// takes place. Any intermediate storage that decoding requires, such as for the palette
+    /// indices of indexed images, is allocated separately.
+    ///
+    /// Making `stride` and the address of the buffer multiples of the value that
+    /// `imageloader::align()` aligns to lets `parng` use its accelerated routines.
+    pub fn load_into<I>(
+        input: &mut I,
+        pixels: &mut [u8],
+        stride: usize,
+        options: &LoadOptions,
+    ) -> Result<ImageInfo, PngError>
+    where
+        I: Read + Seek,
+    {
+        Image::load_into_rect(input, pixels, stride, 0, 0, options)
+    }
+
+    /// Loads a PNG image stream from a reader into a rectangle of a larger surface, such as a
+    /// texture atlas, whose top left corner is at pixel `(x, y)`. Otherwise, this is like
+    /// `Image::load_into()`, with `stride` being the number of bytes between successive scanlines
+    /// of the surface.
+    ///
+    /// Only the pixels inside the rectangle are written to, so other images may already occupy
+    /// the rest of the surface. The surface must hold `stride * (y + height - 1) + (x + width) *
+    /// bytes_per_pixel` bytes, and `stride` must be at least `(x + width) * bytes_per_pixel`.
+    pub fn load_into_rect<I>(
+        input: &mut I,
+        surface: &mut [u8],
+        stride: usize,
+        x: u32,
+        y: u32,
+        options: &LoadOptions,
+    ) -> Result<ImageInfo, PngError>
+    where
+        I: Read + Seek,
+    {
+        let image = Image::decode(input, options, Some((surface, stride, x, y)))?;
+        Ok(ImageInfo {
+            width: image.width,
+            height: image.height,
+            bit_depth: image.bit_depth,
+            output_format: image.output_format,
+            pixel_format: image.pixel_format,
+            palette: image.palette,
+            palette_alpha: image.palette_alpha,
+            channel_order: image.channel_order,
+        })
+    }
+
+    /// Decodes an image, either into memory that the data provider allocates or, if
+    /// `destination` is present, into the given surface with the given stride at the given pixel
+    /// position, in which case the returned image has no pixels of its own.
+    fn decode<I>(
+        input: &mut I,
+        options: &LoadOptions,
+        destination: Option<(&mut [u8], usize, u32, u32)>,
+    ) -> Result<Image, PngError>
+    where
+        I: Read + Seek,
+    {
+        if options.row_alignment == Some(0) {
+            return Err(PngError::InvalidOption(
+                "the row alignment must be nonzero".to_owned(),
+            ));
+        }
         let mut image = ImageLoader::new();
-        loop {
-            match image.add_data(input)? {
-                LoadProgress::NeedDataProviderAndMoreData => break,
-                LoadProgress::NeedMoreData => {}
-                LoadProgress::Finished => panic!("Image ended before metadata was read!"),
-            }
+        image.set_gamma_correction(options.display_gamma);
+        image.set_premultiplied_alpha(options.premultiply_alpha);
+        image.set_channel_order(options.channel_order);
+        image.set_output_format(options.output_format);
+        image.set_linear_light_output(options.linear_light);
+        image.set_preserve_grayscale(options.preserve_grayscale);
+        image.set_preserve_indexed(options.preserve_indexed);
+        image.set_dithering(options.dither);
+        image.set_alpha_flattening(options.flatten_alpha);
+        image.set_significant_bits_rescaling(options.rescale_significant_bits);
+        image.set_color_transform(options.color_transform)?;
+        match image.add_data(input)? {
+            LoadProgress::NeedDataProviderAndMoreData => {}
+            LoadProgress::NeedMoreData => return Err(truncated_image_error()),
+            LoadProgress::Finished => panic!("Image ended before metadata was read!"),
         }
 
-        let (dimensions, indexed) = {
+        let (dimensions, bit_depth, indexed) = {
             let metadata = image.metadata().as_ref().unwrap();
             (
                 metadata.dimensions,
+                match options.output_format {
+                    OutputFormat::RgbaFloat => 32,
+                    _ => output_bit_depth(metadata.bit_depth),
+                },
                 metadata.color_type == ColorType::Indexed,
             )
         };
-        let (data_provider, data_receiver) =
-            MemoryDataProvider::new(dimensions.width, dimensions.height, indexed);
-        let aligned_stride = data_provider.rgba_aligned_stride;
-        image.set_data_provider(Box::new(data_provider));
+        let pixel_format = image.pixel_format().unwrap();
+        let (palette, palette_alpha) = if pixel_format == PixelFormat::Indexed {
+            let palette_alpha = match *image.transparency() {
+                Transparency::Indexed(ref palette_alpha) => Some(palette_alpha.clone()),
+                _ => None,
+            };
+            (Some(image.palette().to_vec()), palette_alpha)
+        } else {
+            (None, None)
+        };
 
-        while let LoadProgress::NeedMoreData = image.add_data(input)? {}
-        image.wait_until_finished()?;
+        // The orientation may only be known once the whole image has been read, so images are
+        // oriented, and flipped along with that, afterward.
+        let auto_orient = options.auto_orient && destination.is_none();
+        let destination = match destination {
+            None => None,
+            Some((surface, stride, x, y)) => {
+                let bytes_per_pixel =
+                    final_bytes_per_pixel(bit_depth, pixel_format, options.output_format);
+                let rect = SurfaceRect::check(
+                    surface.len(),
+                    stride,
+                    x,
+                    y,
+                    dimensions.width,
+                    dimensions.height,
+                    bytes_per_pixel,
+                )?;
+                let storage = PixelStorage::Borrowed(surface.as_mut_ptr(), surface.len());
+                Some(PixelBuffer::surface(storage, rect))
+            }
+        };
 
-        let pixels = data_receiver.recv().unwrap();
-        Ok(Image {
+        let layout = MemoryLayout {
             width: dimensions.width,
             height: dimensions.height,
-            stride: aligned_stride,
+            bit_depth,
+            pixel_format,
+            packed: options.output_format.is_packed(),
+            indexed,
+            flip: options.flip_vertically && !auto_orient,
+            row_alignment: options.row_alignment.unwrap_or(16),
+        };
+        let (mut data_provider, data_receiver) = MemoryDataProvider::new(layout, destination);
+        let mut stride = data_provider.final_buffer().stride;
+        image.set_data_provider(Box::new(data_provider));
+
+        let result = (|| {
+            if image.add_data(input)? == LoadProgress::NeedMoreData {
+                return Err(truncated_image_error());
+            }
+            image.wait_until_finished()
+        })();
+        let orientation = if auto_orient {
+            image
+                .exif()
+                .and_then(|exif| exif.orientation())
+                .unwrap_or(Orientation::Normal)
+        } else {
+            Orientation::Normal
+        };
+
+        // Wait for the predictor thread to drop the data provider, even if decoding failed, so
+        // that it can't touch the caller's buffer after we return.
+        drop(image);
+        let mut pixels = vec![];
+        while let Ok(data) = data_receiver.recv() {
+            pixels = data
+        }
+        result?;
+
+        let (mut width, mut height) = (dimensions.width, dimensions.height);
+        if auto_orient && (orientation != Orientation::Normal || options.flip_vertically) {
+            let bytes_per_pixel =
+                final_bytes_per_pixel(bit_depth, pixel_format, options.output_format);
+            let orient_options = OrientOptions {
+                orientation,
+                flip: options.flip_vertically,
+                row_alignment: options.row_alignment.unwrap_or(16),
+            };
+            let (oriented_pixels, oriented_stride) = orient_pixels(
+                &pixels,
+                stride,
+                width,
+                height,
+                bytes_per_pixel,
+                &orient_options,
+            );
+            pixels = oriented_pixels;
+            stride = oriented_stride;
+            if orientation.swaps_dimensions() {
+                mem::swap(&mut width, &mut height)
+            }
+        }
+
+        Ok(Image {
+            width,
+            height,
+            bit_depth: if options.output_format.is_packed() {
+                16
+            } else {
+                bit_depth
+            },
+            output_format: options.output_format,
+            pixel_format,
+            palette,
+            palette_alpha,
+            stride,
+            channel_order: options.channel_order,
             pixels: pixels,
         })
     }
 }
 
-fn buffer_color_depth(indexed: bool) -> u8 {
-    if indexed {
+/// A description of an image that `Image::load_into()` decoded into a buffer supplied by the
+/// caller. The fields have the same meanings as those of `Image`.
+pub struct ImageInfo {
+    /// The width of the image, in pixels.
+    pub width: u32,
+    /// The height of the image, in pixels.
+    pub height: u32,
+    /// The number of bits per sample.
+    pub bit_depth: u8,
+    /// The format of the pixels.
+    pub output_format: OutputFormat,
+    /// The samples that each pixel consists of.
+    pub pixel_format: PixelFormat,
+    /// The palette, for indexed images delivered as palette indices.
+    pub palette: Option<Vec<u8>>,
+    /// The alpha of each palette entry, for indexed images delivered as palette indices.
+    pub palette_alpha: Option<Vec<u8>>,
+    /// The order in which the samples of each pixel are stored.
+    pub channel_order: ChannelOrder,
+}
+
+/// Options that control how `Image::load_with_options()` decodes an image.
+#[derive(Clone, Debug, Default)]
+pub struct LoadOptions {
+    /// If set, the image is gamma-corrected for a display with this exponent, typically 2.2. See
+    /// `ImageLoader::set_gamma_correction()`.
+    pub display_gamma: Option<f64>,
+    /// If true, the color samples are premultiplied by alpha. See
+    /// `ImageLoader::set_premultiplied_alpha()`.
+    pub premultiply_alpha: bool,
+    /// The order in which the samples of each pixel are stored. See
+    /// `ImageLoader::set_channel_order()`.
+    pub channel_order: ChannelOrder,
+    /// The format of the pixels. See `ImageLoader::set_output_format()`.
+    pub output_format: OutputFormat,
+    /// If true and the output format is `OutputFormat::RgbaFloat`, the color samples are decoded
+    /// to linear light. See `ImageLoader::set_linear_light_output()`.
+    pub linear_light: bool,
+    /// If true, grayscale images are delivered with one or two samples per pixel instead of being
+    /// expanded to RGBA. See `ImageLoader::set_preserve_grayscale()`.
+    pub preserve_grayscale: bool,
+    /// If true, indexed images are delivered as palette indices, along with the palette, instead
+    /// of being expanded to RGBA. See `ImageLoader::set_preserve_indexed()`.
+    pub preserve_indexed: bool,
+    /// If true, samples are dithered when reduced to one of the packed 16-bit output formats. See
+    /// `ImageLoader::set_dithering()`.
+    pub dither: bool,
+    /// If true, the image is stored bottom-up, with the last scanline first, as TGA files and
+    /// OpenGL texture uploads expect. With `Image::load_into_rect()`, this flips the image within
+    /// its rectangle.
+    pub flip_vertically: bool,
+    /// The number of bytes that the stride of the returned image is a multiple of, which must be
+    /// nonzero. 1 packs the scanlines tightly, and APIs such as Vulkan and Direct3D 12 want 256.
+    /// Defaults to 16, as given by `imageloader::align()`. Alignments that aren't multiples of 16
+    /// prevent `parng` from using its accelerated routines. This has no effect on
+    /// `Image::load_into()` and `Image::load_into_rect()`, which use the caller's stride.
+    pub row_alignment: Option<usize>,
+    /// If present, images with alpha are composited onto this background color, producing fully
+    /// opaque pixels. See `ImageLoader::set_alpha_flattening()`.
+    pub flatten_alpha: Option<FlattenBackground>,
+    /// If true, samples are rescaled to the full output range according to the `sBIT` chunk of
+    /// the image. See `ImageLoader::set_significant_bits_rescaling()`.
+    pub rescale_significant_bits: bool,
+    /// If present, the color samples are converted to this color space. See
+    /// `ImageLoader::set_color_transform()`.
+    pub color_transform: Option<TargetColorSpace>,
+    /// If true, the image is rotated and mirrored as the Orientation tag of its `eXIf` chunk
+    /// specifies, so that it's returned as it is meant to be displayed, with `width` and `height`
+    /// exchanged if necessary. Any vertical flip happens afterward. This has no effect on
+    /// `Image::load_into()` and `Image::load_into_rect()`, which decode the image as stored.
+    pub auto_orient: bool,
+}
+
+/// A data provider that decodes an image into a rectangle of a larger surface that it takes
+/// ownership of, such as a texture atlas, and hands the surface back once the image is finished.
+///
+/// This is the `ImageLoader` counterpart of `Image::load_into_rect()`. Only the pixels inside the
+/// rectangle are written to. The pixels are in the format that `Image::load_with_options()` would
+/// produce with the options that have been set on the image loader.
+pub struct AtlasDataProvider {
+    data_provider: MemoryDataProvider,
+}
+
+impl AtlasDataProvider {
+    /// Creates a data provider that decodes the image that `image` is loading into `surface`,
+    /// with `stride` bytes between successive scanlines, such that its top left corner is at
+    /// pixel `(x, y)`. The surface is sent to the returned receiver when the image is finished.
+    ///
+    /// This must be called once `ImageLoader::add_data()` has returned
+    /// `LoadProgress::NeedDataProviderAndMoreData`. If the surface or the stride is too small for
+    /// the rectangle, `PngError::BufferTooSmall` or `PngError::StrideTooSmall` is returned, as
+    /// with `Image::load_into_rect()`.
+    pub fn new(
+        image: &ImageLoader,
+        surface: Vec<u8>,
+        stride: usize,
+        x: u32,
+        y: u32,
+    ) -> Result<(AtlasDataProvider, Receiver<Vec<u8>>), PngError> {
+        let metadata = image
+            .metadata()
+            .as_ref()
+            .expect("The image header hasn't been read yet!");
+        let output_format = image.output_format();
+        let bit_depth = match output_format {
+            OutputFormat::RgbaFloat => 32,
+            _ => output_bit_depth(metadata.bit_depth),
+        };
+        let pixel_format = image.pixel_format().unwrap();
+        let rect = SurfaceRect::check(
+            surface.len(),
+            stride,
+            x,
+            y,
+            metadata.dimensions.width,
+            metadata.dimensions.height,
+            final_bytes_per_pixel(bit_depth, pixel_format, output_format),
+        )?;
+        let layout = MemoryLayout {
+            width: metadata.dimensions.width,
+            height: metadata.dimensions.height,
+            bit_depth,
+            pixel_format,
+            packed: output_format.is_packed(),
+            indexed: metadata.color_type == ColorType::Indexed,
+            flip: false,
+            row_alignment: 16,
+        };
+        let (data_provider, data_receiver) = MemoryDataProvider::new(
+            layout,
+            Some(PixelBuffer::surface(PixelStorage::Owned(surface), rect)),
+        );
+        let data_provider = AtlasDataProvider { data_provider };
+        Ok((data_provider, data_receiver))
+    }
+}
+
+impl DataProvider for AtlasDataProvider {
+    fn fetch_scanlines_for_prediction(
+        &mut self,
+        reference_scanline: Option<u32>,
+        current_scanline: u32,
+        lod: LevelOfDetail,
+        indexed: bool,
+    ) -> ScanlinesForPrediction<'_> {
+        self.data_provider.fetch_scanlines_for_prediction(
+            reference_scanline,
+            current_scanline,
+            lod,
+            indexed,
+        )
+    }
+
+    fn prediction_complete_for_scanline(&mut self, scanline: u32, lod: LevelOfDetail) {
+        self.data_provider
+            .prediction_complete_for_scanline(scanline, lod)
+    }
+
+    fn fetch_scanlines_for_rgba_conversion(
+        &mut self,
+        scanline: u32,
+        lod: LevelOfDetail,
+        indexed: bool,
+    ) -> ScanlinesForRgbaConversion<'_> {
+        self.data_provider
+            .fetch_scanlines_for_rgba_conversion(scanline, lod, indexed)
+    }
+
+    fn rgba_conversion_complete_for_scanline(&mut self, scanline: u32, lod: LevelOfDetail) {
+        self.data_provider
+            .rgba_conversion_complete_for_scanline(scanline, lod)
+    }
+
+    fn fetch_scanline_for_packed_output<'a>(
+        &'a mut self,
+        scanline: u32,
+        lod: LevelOfDetail,
+    ) -> Option<ScanlineForPackedOutput<'a>> {
+        self.data_provider
+            .fetch_scanline_for_packed_output(scanline, lod)
+    }
+
+    fn finished(&mut self) {
+        self.data_provider.finished()
+    }
+}
+
+/// The placement of an image inside a larger surface.
+#[derive(Copy, Clone, Debug)]
+struct SurfaceRect {
+    /// The offset of the top left pixel of the image, in bytes.
+    origin: usize,
+    /// The number of bytes between successive scanlines of the surface.
+    stride: usize,
+    /// The number of bytes in each scanline of the image.
+    row_length: usize,
+    /// The number of scanlines in the image.
+    height: u32,
+}
+
+impl SurfaceRect {
+    /// Places an image of the given size at pixel `(x, y)` of a surface of the given length and
+    /// stride, or returns an error if it doesn't fit.
+    fn check(
+        surface_length: usize,
+        stride: usize,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        bytes_per_pixel: usize,
+    ) -> Result<SurfaceRect, PngError> {
+        // All of these are supplied by the caller, so guard against overflow. A size that
+        // overflows can't fit, so report it as the largest one.
+        let required_stride = (x as usize)
+            .checked_add(width as usize)
+            .and_then(|pixels| pixels.checked_mul(bytes_per_pixel))
+            .unwrap_or(usize::MAX);
+        if stride < required_stride {
+            return Err(PngError::StrideTooSmall(required_stride));
+        }
+        let x_offset = x as usize * bytes_per_pixel;
+        let row_length = width as usize * bytes_per_pixel;
+        let required_length = (y as usize)
+            .checked_add(height as usize - 1)
+            .and_then(|rows| rows.checked_mul(stride))
+            .and_then(|length| length.checked_add(x_offset + row_length))
+            .unwrap_or(usize::MAX);
+        if surface_length < required_length {
+            return Err(PngError::BufferTooSmall(required_length));
+        }
+        let origin = stride * y as usize + x_offset;
+        Ok(SurfaceRect {
+            origin,
+            stride,
+            row_length,
+            height,
+        })
+    }
+}
+
+/// Returns the size of a pixel of the finished image.
+fn final_bytes_per_pixel(
+    bit_depth: u8,
+    pixel_format: PixelFormat,
+    output_format: OutputFormat,
+) -> usize {
+    if pixel_format == PixelFormat::Indexed {
+        1
+    } else if output_format.is_packed() {
+        2
+    } else {
+        rgba_color_depth(bit_depth, pixel_format) as usize / 8
+    }
+}
+
+/// How `orient_pixels()` transforms a decoded image.
+#[derive(Copy, Clone, Debug)]
+struct OrientOptions {
+    orientation: Orientation,
+    /// True if the image is flipped vertically after it has been oriented.
+    flip: bool,
+    /// The number of bytes that the stride of the new buffer is a multiple of.
+    row_alignment: usize,
+}
+
+/// Copies the pixels of a decoded image into a new buffer, transformed for display as `options`
+/// specify. Returns the new pixels and their stride.
+fn orient_pixels(
+    pixels: &[u8],
+    stride: usize,
+    width: u32,
+    height: u32,
+    bytes_per_pixel: usize,
+    options: &OrientOptions,
+) -> (Vec<u8>, usize) {
+    let OrientOptions {
+        orientation,
+        flip,
+        row_alignment,
+    } = *options;
+    let (oriented_width, oriented_height) = if orientation.swaps_dimensions() {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    let oriented_stride =
+        imageloader::align_to(oriented_width as usize * bytes_per_pixel, row_alignment);
+    let mut oriented_pixels = vec![0; oriented_stride * oriented_height as usize];
+    for y in 0..oriented_height {
+        let row = if flip { oriented_height - 1 - y } else { y };
+        let row_start = oriented_stride * row as usize;
+        for x in 0..oriented_width {
+            let (source_x, source_y) = orientation.source_position(x, y, width, height);
+            let source_start = stride * source_y as usize + bytes_per_pixel * source_x as usize;
+            let dest_start = row_start + bytes_per_pixel * x as usize;
+            oriented_pixels[dest_start..(dest_start + bytes_per_pixel)]
+                .copy_from_slice(&pixels[source_start..(source_start + bytes_per_pixel)])
+        }
+    }
+    (oriented_pixels, oriented_stride)
+}
+
+fn output_bit_depth(bit_depth: u8) -> u8 {
+    if bit_depth == 16 {
+        16
+    } else {
         8
+    }
+}
+
+fn rgba_color_depth(bit_depth: u8, pixel_format: PixelFormat) -> u8 {
+    bit_depth * pixel_format.samples_per_pixel()
+}
+
+/// The whole image is available to the loaders here, so running out of data means that it was
+/// truncated, not that more is on the way.
+fn truncated_image_error() -> PngError {
+    PngError::Io(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "image ended early",
+    ))
+}
+
+/// How an `Animation` delivers the frames of an animated PNG.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AnimationMode {
+    /// Each frame is delivered as it was decoded, covering only its own region of the canvas as
+    /// described by its `FrameControl`. Disposing and blending frames is up to the caller.
+    Frames,
+    /// Each frame is composited onto a canvas the size of the whole image according to its
+    /// dispose and blend ops, and the fully rendered canvas is delivered.
+    Composited,
+}
+
+/// A single frame of an animated PNG.
+pub struct Frame {
+    /// Information about the frame: its region of the canvas, delay, and dispose and blend ops.
+    pub control: FrameControl,
+    /// The pixels of the frame. In `AnimationMode::Composited` mode this is the whole rendered
+    /// canvas; otherwise it covers only the region of the canvas given in `control`.
+    pub image: Image,
+}
+
+/// Decodes the frames of an animated PNG into memory, one at a time.
+///
+/// Images that aren't animated are treated as animations with a single frame.
+pub struct Animation<'a, I>
+where
+    I: Read + Seek + 'a,
+{
+    loader: ImageLoader,
+    input: &'a mut I,
+    mode: AnimationMode,
+    metadata: Metadata,
+    animation_control: Option<AnimationControl>,
+    decoding_default_image: bool,
+    finished: bool,
+    canvas: Option<Image>,
+    /// The previous frame, along with the contents of its region before it was rendered if it is
+    /// to be disposed of with `DisposeOp::Previous`.
+    previous_frame: Option<(FrameControl, Option<Vec<u8>>)>,
+}
+
+impl<'a, I> Animation<'a, I>
+where
+    I: Read + Seek + 'a,
+{
+    /// Reads the metadata of a PNG image stream in preparation for decoding its frames.
+    pub fn new(input: &'a mut I, mode: AnimationMode) -> Result<Animation<'a, I>, PngError> {
+        let mut loader = ImageLoader::new();
+        loader.set_animation_decoding(true);
+        match loader.add_data(input)? {
+            LoadProgress::NeedDataProviderAndMoreData => {}
+            LoadProgress::NeedMoreData => return Err(truncated_image_error()),
+            LoadProgress::Finished => panic!("Image ended before metadata was read!"),
+        }
+
+        let metadata = loader.metadata().as_ref().unwrap().clone();
+        let animation_control = *loader.animation_control();
+        Ok(Animation {
+            loader,
+            input,
+            mode,
+            metadata,
+            animation_control,
+            decoding_default_image: true,
+            finished: false,
+            canvas: None,
+            previous_frame: None,
+        })
+    }
+
+    /// Returns the metadata of the image.
+    #[inline]
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Returns the number of frames in the animation.
+    pub fn frame_count(&self) -> u32 {
+        match self.animation_control {
+            Some(ref animation_control) => animation_control.num_frames,
+            None => 1,
+        }
+    }
+
+    /// Returns the number of times to play the animation. 0 means that it loops forever.
+    pub fn loop_count(&self) -> u32 {
+        match self.animation_control {
+            Some(ref animation_control) => animation_control.num_plays,
+            None => 1,
+        }
+    }
+
+    /// Decodes the next frame of the animation, or returns `None` if all frames have been decoded.
+    ///
+    /// Frames are decoded in the same format that `Image::load` uses.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, PngError> {
+        loop {
+            if self.finished {
+                return Ok(None);
+            }
+
+            let frame_control = *self.loader.frame_control();
+            let dimensions = match frame_control {
+                Some(ref frame_control) if !self.decoding_default_image => {
+                    frame_control.dimensions()
+                }
+                _ => self.metadata.dimensions,
+            };
+            let bit_depth = output_bit_depth(self.metadata.bit_depth);
+            let indexed = self.metadata.color_type == ColorType::Indexed;
+            let layout = MemoryLayout {
+                width: dimensions.width,
+                height: dimensions.height,
+                bit_depth,
+                pixel_format: PixelFormat::Rgba,
+                packed: false,
+                indexed,
+                flip: false,
+                row_alignment: 16,
+            };
+            let (mut data_provider, data_receiver) = MemoryDataProvider::new(layout, None);
+            let aligned_stride = data_provider.final_buffer().stride;
+            self.loader.set_data_provider(Box::new(data_provider));
+
+            // Decode until the next frame starts or the image ends.
+            match self.loader.add_data(self.input)? {
+                LoadProgress::NeedMoreData => return Err(truncated_image_error()),
+                LoadProgress::NeedDataProviderAndMoreData => {}
+                LoadProgress::Finished => {
+                    self.loader.wait_until_finished()?;
+                    self.finished = true;
+                }
+            }
+
+            let image = Image {
+                width: dimensions.width,
+                height: dimensions.height,
+                bit_depth,
+                output_format: OutputFormat::Rgba,
+                pixel_format: PixelFormat::Rgba,
+                palette: None,
+                palette_alpha: None,
+                stride: aligned_stride,
+                channel_order: ChannelOrder::Rgba,
+                pixels: data_receiver.recv().unwrap(),
+            };
+            self.decoding_default_image = false;
+
+            let frame_control = match frame_control {
+                Some(frame_control) => frame_control,
+                // The default image isn't part of the animation, so skip it.
+                None if self.animation_control.is_some() => continue,
+                None => FrameControl {
+                    sequence_number: 0,
+                    width: dimensions.width,
+                    height: dimensions.height,
+                    x_offset: 0,
+                    y_offset: 0,
+                    delay_num: 0,
+                    delay_den: 0,
+                    dispose_op: DisposeOp::None,
+                    blend_op: BlendOp::Source,
+                },
+            };
+
+            return Ok(Some(match self.mode {
+                AnimationMode::Frames => Frame {
+                    control: frame_control,
+                    image,
+                },
+                AnimationMode::Composited => self.composite(frame_control, image),
+            }));
+        }
+    }
+
+    fn composite(&mut self, frame_control: FrameControl, frame: Image) -> Frame {
+        let bytes_per_pixel = frame.bit_depth as usize / 2;
+        let dimensions = self.metadata.dimensions;
+        let canvas = self.canvas.get_or_insert_with(|| {
+            let stride = imageloader::align(dimensions.width as usize * bytes_per_pixel);
+            Image {
+                width: dimensions.width,
+                height: dimensions.height,
+                bit_depth: frame.bit_depth,
+                output_format: frame.output_format,
+                pixel_format: frame.pixel_format,
+                palette: None,
+                palette_alpha: None,
+                stride,
+                channel_order: frame.channel_order,
+                pixels: vec![0; stride * dimensions.height as usize],
+            }
+        });
+
+        // Dispose of the previous frame. Note that if the first frame is disposed of with
+        // `DisposeOp::Previous`, its region reverts to the transparent black it started as, as
+        // the specification requires.
+        if let Some((previous_frame_control, previous_contents)) = self.previous_frame.take() {
+            match previous_frame_control.dispose_op {
+                DisposeOp::None => {}
+                DisposeOp::Background => {
+                    for row in canvas_region(canvas, &previous_frame_control) {
+                        for byte in row {
+                            *byte = 0
+                        }
+                    }
+                }
+                DisposeOp::Previous => {
+                    let previous_contents = previous_contents.unwrap();
+                    let row_length = previous_frame_control.width as usize * bytes_per_pixel;
+                    for (row, previous_row) in canvas_region(canvas, &previous_frame_control)
+                        .zip(previous_contents.chunks(row_length))
+                    {
+                        row.copy_from_slice(previous_row)
+                    }
+                }
+            }
+        }
+
+        let contents = if frame_control.dispose_op == DisposeOp::Previous {
+            let mut contents = vec![];
+            for row in canvas_region(canvas, &frame_control) {
+                contents.extend_from_slice(row)
+            }
+            Some(contents)
+        } else {
+            None
+        };
+
+        let row_length = frame.width as usize * bytes_per_pixel;
+        for (row, frame_row) in
+            canvas_region(canvas, &frame_control).zip(frame.pixels.chunks(frame.stride))
+        {
+            let frame_row = &frame_row[0..row_length];
+            match frame_control.blend_op {
+                BlendOp::Source => row.copy_from_slice(frame_row),
+                BlendOp::Over => blend_over(row, frame_row, frame.bit_depth),
+            }
+        }
+
+        self.previous_frame = Some((frame_control, contents));
+        Frame {
+            control: frame_control,
+            image: Image {
+                width: canvas.width,
+                height: canvas.height,
+                bit_depth: canvas.bit_depth,
+                output_format: canvas.output_format,
+                pixel_format: canvas.pixel_format,
+                palette: None,
+                palette_alpha: None,
+                stride: canvas.stride,
+                channel_order: canvas.channel_order,
+                pixels: canvas.pixels.clone(),
+            },
+        }
+    }
+}
+
+impl<'a, I> Iterator for Animation<'a, I>
+where
+    I: Read + Seek + 'a,
+{
+    type Item = Result<Frame, PngError>;
+
+    fn next(&mut self) -> Option<Result<Frame, PngError>> {
+        match self.next_frame() {
+            Ok(Some(frame)) => Some(Ok(frame)),
+            Ok(None) => None,
+            Err(error) => {
+                self.finished = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+/// Returns the rows of the canvas covered by the given frame.
+fn canvas_region<'a>(
+    canvas: &'a mut Image,
+    frame_control: &FrameControl,
+) -> impl Iterator<Item = &'a mut [u8]> {
+    let bytes_per_pixel = canvas.bit_depth as usize / 2;
+    let start = frame_control.x_offset as usize * bytes_per_pixel;
+    let end = start + frame_control.width as usize * bytes_per_pixel;
+    canvas
+        .pixels
+        .chunks_mut(canvas.stride)
+        .skip(frame_control.y_offset as usize)
+        .take(frame_control.height as usize)
+        .map(move |row| &mut row[start..end])
+}
+
+/// Composites a row of RGBA pixels with straight alpha over another.
+fn blend_over(dest: &mut [u8], src: &[u8], bit_depth: u8) {
+    if bit_depth == 16 {
+        for (dest, src) in dest.chunks_mut(8).zip(src.chunks(8)) {
+            let mut dest_color = [0; 4];
+            let mut src_color = [0; 4];
+            for channel in 0..4 {
+                dest_color[channel] = BigEndian::read_u16(&dest[(channel * 2)..]) as u64;
+                src_color[channel] = BigEndian::read_u16(&src[(channel * 2)..]) as u64;
+            }
+            let color = blend_pixel_over(dest_color, src_color, 0xffff);
+            for channel in 0..4 {
+                BigEndian::write_u16(&mut dest[(channel * 2)..], color[channel] as u16)
+            }
+        }
     } else {
-        32
+        for (dest, src) in dest.chunks_mut(4).zip(src.chunks(4)) {
+            let mut dest_color = [0; 4];
+            let mut src_color = [0; 4];
+            for channel in 0..4 {
+                dest_color[channel] = dest[channel] as u64;
+                src_color[channel] = src[channel] as u64;
+            }
+            let color = blend_pixel_over(dest_color, src_color, 0xff);
+            for channel in 0..4 {
+                dest[channel] = color[channel] as u8
+            }
+        }
+    }
+}
+
+fn blend_pixel_over(dest: [u64; 4], src: [u64; 4], max: u64) -> [u64; 4] {
+    let src_alpha = src[3];
+    if src_alpha == max {
+        return src;
+    }
+    if src_alpha == 0 {
+        return dest;
+    }
+    let dest_alpha = dest[3] * (max - src_alpha) / max;
+    let alpha = src_alpha + dest_alpha;
+    let mut color = [0; 4];
+    for channel in 0..3 {
+        color[channel] = (src[channel] * src_alpha + dest[channel] * dest_alpha) / alpha
     }
+    color[3] = alpha;
+    color
 }
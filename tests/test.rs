@@ -5,6 +5,21 @@
 // Any copyright is dedicated to the Public Domain.
 // http://creativecommons.org/publicdomain/zero/1.0/
 
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use parng::colortransform::TargetColorSpace;
+use parng::imageloader::{
+    Background, ChannelOrder, FlattenBackground, ImageLoader, InterlacingInfo, LevelOfDetail,
+    LoadProgress, OutputFormat, PixelFormat,
+};
+use parng::metadata::{
+    BlendOp, Chromaticities, CodingIndependentCodePoints, ContentLightLevel, CrcPolicy, DisposeOp,
+    Orientation, PhysicalDimensions, PhysicalUnit, RenderingIntent, SignificantBits, TextChunk,
+};
+use parng::simple::{Animation, AnimationMode, Image, LoadOptions};
+use parng::PngError;
+use std::cmp;
+use std::io::{Cursor, Write};
 use std::process::Command;
 
 #[test]
@@ -28,3 +43,1564 @@ fn verify_asm() {
         .unwrap()
         .success());
 }
+
+const GRAYSCALE: u8 = 0;
+const RGB: u8 = 2;
+const INDEXED: u8 = 3;
+const GRAYSCALE_ALPHA: u8 = 4;
+const RGB_ALPHA: u8 = 6;
+
+/// The first pixel and the spacing between pixels of each Adam7 pass, horizontally and vertically.
+const ADAM7_PASSES: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// A PNG image to encode, given as its samples.
+struct TestImage {
+    width: u32,
+    height: u32,
+    color_type: u8,
+    bit_depth: u8,
+    interlaced: bool,
+    /// The samples of each pixel in turn, left to right and top to bottom.
+    samples: Vec<u16>,
+    /// Chunks to write between `IHDR` and `IDAT`.
+    chunks: Vec<([u8; 4], Vec<u8>)>,
+}
+
+impl TestImage {
+    /// Creates an image with arbitrary samples.
+    fn new(width: u32, height: u32, color_type: u8, bit_depth: u8) -> TestImage {
+        let count = (width * height) as usize * channels(color_type);
+        let max = ((1u32 << bit_depth) - 1) as u16;
+        TestImage {
+            width,
+            height,
+            color_type,
+            bit_depth,
+            interlaced: false,
+            samples: (0..count as u32)
+                .map(|i| (i.wrapping_mul(2_654_435_761) >> 11) as u16 & max)
+                .collect(),
+            chunks: vec![],
+        }
+    }
+
+    fn interlaced(mut self) -> TestImage {
+        self.interlaced = true;
+        self
+    }
+
+    fn with_chunk(mut self, chunk_type: &[u8; 4], data: &[u8]) -> TestImage {
+        self.chunks.push((*chunk_type, data.to_vec()));
+        self
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> &[u16] {
+        let channels = channels(self.color_type);
+        let start = (y * self.width + x) as usize * channels;
+        &self.samples[start..start + channels]
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut header = vec![];
+        header.extend_from_slice(&self.width.to_be_bytes());
+        header.extend_from_slice(&self.height.to_be_bytes());
+        header.extend_from_slice(&[self.bit_depth, self.color_type, 0, 0, self.interlaced as u8]);
+        write_chunk(&mut png, b"IHDR", &header);
+        for (chunk_type, data) in &self.chunks {
+            write_chunk(&mut png, chunk_type, data)
+        }
+        write_chunk(&mut png, b"IDAT", &self.image_data());
+        write_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    /// Returns the compressed scanlines, using every filter type in turn.
+    fn image_data(&self) -> Vec<u8> {
+        let passes = if self.interlaced {
+            ADAM7_PASSES.to_vec()
+        } else {
+            vec![(0, 0, 1, 1)]
+        };
+        let bits_per_pixel = channels(self.color_type) * self.bit_depth as usize;
+        let bytes_per_pixel = cmp::max(bits_per_pixel / 8, 1);
+        let mut data = vec![];
+        for (x_offset, y_offset, x_spacing, y_spacing) in passes {
+            let xs: Vec<u32> = (x_offset..self.width).step_by(x_spacing as usize).collect();
+            if xs.is_empty() {
+                continue;
+            }
+            let mut previous = vec![0; (xs.len() * bits_per_pixel + 7) / 8];
+            for y in (y_offset..self.height).step_by(y_spacing as usize) {
+                let scanline = self.pack_scanline(y, &xs);
+                let filter = (y % 5) as u8;
+                data.push(filter);
+                for i in 0..scanline.len() {
+                    let a = if i >= bytes_per_pixel {
+                        scanline[i - bytes_per_pixel]
+                    } else {
+                        0
+                    };
+                    let b = previous[i];
+                    let c = if i >= bytes_per_pixel {
+                        previous[i - bytes_per_pixel]
+                    } else {
+                        0
+                    };
+                    let prediction = match filter {
+                        0 => 0,
+                        1 => a,
+                        2 => b,
+                        3 => ((a as u16 + b as u16) / 2) as u8,
+                        _ => paeth(a, b, c),
+                    };
+                    data.push(scanline[i].wrapping_sub(prediction))
+                }
+                previous = scanline;
+            }
+        }
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(&data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn pack_scanline(&self, y: u32, xs: &[u32]) -> Vec<u8> {
+        let mut scanline = vec![];
+        let (mut bits, mut bit_count) = (0, 0);
+        for &x in xs {
+            for &sample in self.pixel(x, y) {
+                match self.bit_depth {
+                    16 => scanline.extend_from_slice(&sample.to_be_bytes()),
+                    8 => scanline.push(sample as u8),
+                    _ => {
+                        bits = (bits << self.bit_depth) | sample;
+                        bit_count += self.bit_depth;
+                        if bit_count == 8 {
+                            scanline.push(bits as u8);
+                            bits = 0;
+                            bit_count = 0
+                        }
+                    }
+                }
+            }
+        }
+        if bit_count != 0 {
+            scanline.push((bits << (8 - bit_count)) as u8)
+        }
+        scanline
+    }
+
+    /// Returns the pixel that `Image::load()` should produce, with 16-bit samples for 16-bit images
+    /// and 8-bit samples otherwise.
+    fn expected_rgba(&self, x: u32, y: u32) -> [u16; 4] {
+        let pixel = self.pixel(x, y);
+        let max = (1u32 << self.bit_depth) - 1;
+        let scale = |sample: u16| {
+            if self.bit_depth == 16 {
+                sample
+            } else {
+                (sample as u32 * 255 / max) as u16
+            }
+        };
+        let opaque = if self.bit_depth == 16 { 0xffff } else { 0xff };
+        let transparency = self.chunk(b"tRNS");
+        match self.color_type {
+            GRAYSCALE => {
+                let magic = transparency.map(|data| u16::from_be_bytes([data[0], data[1]]));
+                let alpha = if magic == Some(pixel[0]) { 0 } else { opaque };
+                [scale(pixel[0]), scale(pixel[0]), scale(pixel[0]), alpha]
+            }
+            RGB => {
+                let magic: Option<Vec<u16>> = transparency.map(|data| {
+                    data.chunks(2)
+                        .map(|sample| u16::from_be_bytes([sample[0], sample[1]]))
+                        .collect()
+                });
+                let alpha = if magic.as_deref() == Some(pixel) {
+                    0
+                } else {
+                    opaque
+                };
+                [scale(pixel[0]), scale(pixel[1]), scale(pixel[2]), alpha]
+            }
+            INDEXED => {
+                let index = pixel[0] as usize;
+                let palette = self.chunk(b"PLTE").unwrap();
+                if index * 3 >= palette.len() {
+                    return [0, 0, 0, 0xff];
+                }
+                let alpha = transparency
+                    .and_then(|data| data.get(index))
+                    .unwrap_or(&0xff);
+                let color = &palette[index * 3..index * 3 + 3];
+                [
+                    color[0] as u16,
+                    color[1] as u16,
+                    color[2] as u16,
+                    *alpha as u16,
+                ]
+            }
+            GRAYSCALE_ALPHA => {
+                let gray = scale(pixel[0]);
+                [gray, gray, gray, scale(pixel[1])]
+            }
+            _ => [
+                scale(pixel[0]),
+                scale(pixel[1]),
+                scale(pixel[2]),
+                scale(pixel[3]),
+            ],
+        }
+    }
+
+    fn chunk(&self, chunk_type: &[u8; 4]) -> Option<&[u8]> {
+        self.chunks
+            .iter()
+            .find(|chunk| &chunk.0 == chunk_type)
+            .map(|chunk| &chunk.1[..])
+    }
+}
+
+fn channels(color_type: u8) -> usize {
+    match color_type {
+        RGB => 3,
+        GRAYSCALE_ALPHA => 2,
+        RGB_ALPHA => 4,
+        _ => 1,
+    }
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+fn write_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    let mut crc = Crc::new();
+    crc.update(chunk_type);
+    crc.update(data);
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+    png.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+fn load(png: &[u8]) -> Image {
+    Image::load(&mut Cursor::new(png)).unwrap()
+}
+
+/// Returns the samples of a pixel of a decoded image, which must have 8 or 16 bits per sample.
+fn rgba_at(image: &Image, x: u32, y: u32) -> [u16; 4] {
+    let bytes_per_sample = image.bit_depth as usize / 8;
+    let start = y as usize * image.stride + x as usize * 4 * bytes_per_sample;
+    let mut rgba = [0; 4];
+    for (channel, sample) in rgba.iter_mut().enumerate() {
+        let offset = start + channel * bytes_per_sample;
+        *sample = if bytes_per_sample == 2 {
+            u16::from_be_bytes([image.pixels[offset], image.pixels[offset + 1]])
+        } else {
+            image.pixels[offset] as u16
+        }
+    }
+    rgba
+}
+
+/// Decodes the image and checks every pixel of the result.
+fn assert_decodes(test_image: &TestImage) {
+    let image = load(&test_image.encode());
+    assert_eq!(
+        (image.width, image.height),
+        (test_image.width, test_image.height)
+    );
+    let expected_bit_depth = if test_image.bit_depth == 16 { 16 } else { 8 };
+    assert_eq!(image.bit_depth, expected_bit_depth);
+    for y in 0..image.height {
+        for x in 0..image.width {
+            assert_eq!(
+                rgba_at(&image, x, y),
+                test_image.expected_rgba(x, y),
+                "pixel ({}, {}) of a {}x{} image",
+                x,
+                y,
+                image.width,
+                image.height
+            );
+        }
+    }
+}
+
+#[test]
+fn decode_16_bit_images_to_64_bit_rgba() {
+    for &color_type in &[GRAYSCALE, RGB, GRAYSCALE_ALPHA, RGB_ALPHA] {
+        for &(width, height) in &[(1, 1), (5, 3), (17, 9)] {
+            let test_image = TestImage::new(width, height, color_type, 16);
+            assert_decodes(&test_image);
+            assert_decodes(&test_image.interlaced());
+        }
+    }
+}
+
+#[test]
+fn keep_8_bit_images_at_32_bit_rgba() {
+    let image = load(&TestImage::new(3, 2, RGB_ALPHA, 8).encode());
+    assert_eq!(image.bit_depth, 8);
+    assert!(image.stride >= 3 * 4);
+}
+
+/// Creates an indexed image with a palette of `palette_size` arbitrary colors, and indices that
+/// may run past the end of it.
+fn indexed_image(width: u32, height: u32, bit_depth: u8, palette_size: usize) -> TestImage {
+    let palette: Vec<u8> = (0..palette_size * 3).map(|i| (i * 37 + 11) as u8).collect();
+    TestImage::new(width, height, INDEXED, bit_depth).with_chunk(b"PLTE", &palette)
+}
+
+#[test]
+fn decode_low_bit_indexed_images() {
+    for &bit_depth in &[1, 2, 4] {
+        for width in 1..10 {
+            let test_image = indexed_image(width, 3, bit_depth, 1 << bit_depth);
+            assert_decodes(&test_image);
+            assert_decodes(&test_image.interlaced());
+        }
+        let test_image = indexed_image(19, 11, bit_depth, 1 << bit_depth).interlaced();
+        let alpha: Vec<u8> = (0..1 << bit_depth).map(|i| i as u8 * 17).collect();
+        assert_decodes(&test_image.with_chunk(b"tRNS", &alpha));
+    }
+}
+
+#[test]
+fn decode_out_of_range_palette_indices_as_opaque_black() {
+    for &bit_depth in &[2, 4, 8] {
+        let test_image = indexed_image(13, 7, bit_depth, 3);
+        assert!(test_image.samples.iter().any(|&index| index >= 3));
+        assert_decodes(&test_image);
+        assert_decodes(&test_image.interlaced());
+    }
+}
+
+#[test]
+fn decode_low_bit_grayscale_images() {
+    for &bit_depth in &[1, 2, 4] {
+        for width in 1..10 {
+            let test_image = TestImage::new(width, 3, GRAYSCALE, bit_depth);
+            assert_decodes(&test_image);
+            assert_decodes(&test_image.interlaced());
+        }
+        let test_image = TestImage::new(21, 9, GRAYSCALE, bit_depth).interlaced();
+        let magic = test_image.pixel(5, 4)[0].to_be_bytes();
+        assert_decodes(&test_image.with_chunk(b"tRNS", &magic));
+    }
+}
+
+#[test]
+fn scale_low_bit_gray_to_the_full_range() {
+    let mut test_image = TestImage::new(4, 1, GRAYSCALE, 2);
+    test_image.samples = vec![0, 1, 2, 3];
+    let image = load(&test_image.encode());
+    let grays: Vec<u16> = (0..4).map(|x| rgba_at(&image, x, 0)[0]).collect();
+    assert_eq!(grays, [0, 85, 170, 255]);
+}
+
+#[test]
+fn lay_out_low_bit_pixels_a_byte_apart() {
+    for &color_depth in &[1, 2, 4, 8] {
+        let scanline = InterlacingInfo::new(1, color_depth, LevelOfDetail::Adam7(1));
+        assert_eq!((scanline.y, scanline.stride, scanline.offset), (8, 8, 4));
+        assert_eq!(scanline.scanline_width(13, color_depth), 2);
+        assert_eq!(scanline.scanline_width(4, color_depth), 0);
+    }
+    let scanline = InterlacingInfo::new(0, 64, LevelOfDetail::Adam7(5));
+    assert_eq!((scanline.stride, scanline.offset), (16, 8));
+    assert_eq!(scanline.scanline_width(6, 64), 3);
+}
+
+/// Spoils the stored checksum of the first chunk of the given type.
+fn corrupt_crc(png: &mut [u8], chunk_type: &[u8; 4]) {
+    let mut offset = 8;
+    while &png[offset + 4..offset + 8] != chunk_type {
+        let length = u32::from_be_bytes([
+            png[offset],
+            png[offset + 1],
+            png[offset + 2],
+            png[offset + 3],
+        ]);
+        offset += length as usize + 12;
+    }
+    let length = u32::from_be_bytes([
+        png[offset],
+        png[offset + 1],
+        png[offset + 2],
+        png[offset + 3],
+    ]);
+    png[offset + length as usize + 11] ^= 1
+}
+
+/// Feeds the image to the loader until it asks for a data provider.
+fn add_metadata(loader: &mut ImageLoader, png: &[u8]) -> Result<(), PngError> {
+    let progress = loader.add_data(&mut Cursor::new(png))?;
+    assert!(progress == LoadProgress::NeedDataProviderAndMoreData);
+    Ok(())
+}
+
+#[test]
+fn reject_critical_chunks_with_bad_checksums() {
+    for &chunk_type in &[b"IHDR", b"IDAT"] {
+        let mut png = TestImage::new(4, 4, RGB, 8).encode();
+        corrupt_crc(&mut png, chunk_type);
+        match Image::load(&mut Cursor::new(&png)) {
+            Err(PngError::InvalidCrc(found_chunk_type, _)) => {
+                assert_eq!(&found_chunk_type, chunk_type)
+            }
+            Err(error) => panic!("unexpected error: {:?}", error),
+            Ok(_) => panic!("decoded an image with a bad {:?} checksum", chunk_type),
+        }
+    }
+}
+
+#[test]
+fn apply_crc_policies() {
+    let mut png = TestImage::new(4, 4, RGB, 8).encode();
+    corrupt_crc(&mut png, b"IHDR");
+
+    let mut loader = ImageLoader::new();
+    loader.set_crc_policy(CrcPolicy::Warn);
+    add_metadata(&mut loader, &png).unwrap();
+    assert_eq!(loader.warnings().len(), 1);
+    assert!(
+        matches!(loader.warnings()[0], PngError::InvalidCrc(ref chunk_type, 8) if chunk_type == b"IHDR")
+    );
+
+    let mut loader = ImageLoader::new();
+    loader.set_crc_policy(CrcPolicy::Ignore);
+    add_metadata(&mut loader, &png).unwrap();
+    assert!(loader.warnings().is_empty());
+
+    let mut loader = ImageLoader::new();
+    assert!(add_metadata(&mut loader, &png).is_err());
+}
+
+const GREEN: [u16; 4] = [0, 255, 0, 255];
+const BLUE: [u16; 4] = [0, 0, 255, 255];
+const TRANSPARENT: [u16; 4] = [0, 0, 0, 0];
+
+/// Creates an RGBA image with every pixel the given color.
+fn solid_image(width: u32, height: u32, color: [u16; 4]) -> TestImage {
+    let mut test_image = TestImage::new(width, height, RGB_ALPHA, 8);
+    test_image.samples = color
+        .iter()
+        .cloned()
+        .cycle()
+        .take(test_image.samples.len())
+        .collect();
+    test_image
+}
+
+/// Encodes a 4x4 animation whose default image is red and not part of the animation. Each frame
+/// is given as its position, dispose op, blend op, and image.
+fn encode_animation(frames: &[((u32, u32), u8, u8, TestImage)]) -> Vec<u8> {
+    let default_image = solid_image(4, 4, [255, 0, 0, 255]);
+    let mut png = default_image.encode();
+    png.truncate(png.len() - 12);
+    let header_end = 8 + 25;
+    let mut chunks = vec![];
+    let mut animation_control = (frames.len() as u32).to_be_bytes().to_vec();
+    animation_control.extend_from_slice(&0u32.to_be_bytes());
+    write_chunk(&mut chunks, b"acTL", &animation_control);
+    let mut sequence_number = 0u32;
+    for &((x, y), dispose_op, blend_op, ref image) in frames {
+        let mut frame_control = sequence_number.to_be_bytes().to_vec();
+        for value in &[image.width, image.height, x, y] {
+            frame_control.extend_from_slice(&value.to_be_bytes())
+        }
+        frame_control.extend_from_slice(&[0, 1, 0, 10, dispose_op, blend_op]);
+        write_chunk(&mut png, b"fcTL", &frame_control);
+        let mut frame_data = (sequence_number + 1).to_be_bytes().to_vec();
+        frame_data.extend_from_slice(&image.image_data());
+        write_chunk(&mut png, b"fdAT", &frame_data);
+        sequence_number += 2;
+    }
+    write_chunk(&mut png, b"IEND", &[]);
+    png.splice(header_end..header_end, chunks);
+    png
+}
+
+fn test_animation() -> Vec<u8> {
+    encode_animation(&[
+        ((0, 0), 0, 0, solid_image(4, 4, GREEN)),
+        ((1, 1), 2, 1, solid_image(2, 2, [255, 0, 0, 128])),
+        ((2, 2), 1, 0, solid_image(2, 2, BLUE)),
+        ((0, 0), 0, 1, solid_image(1, 1, TRANSPARENT)),
+    ])
+}
+
+fn assert_pixels<F>(image: &Image, expected: F)
+where
+    F: Fn(u32, u32) -> [u16; 4],
+{
+    for y in 0..image.height {
+        for x in 0..image.width {
+            assert_eq!(rgba_at(image, x, y), expected(x, y), "pixel ({}, {})", x, y);
+        }
+    }
+}
+
+#[test]
+fn decode_animation_frames() {
+    let png = test_animation();
+    let mut input = Cursor::new(&png);
+    let mut animation = Animation::new(&mut input, AnimationMode::Frames).unwrap();
+    assert_eq!((animation.frame_count(), animation.loop_count()), (4, 0));
+    let mut frames = vec![];
+    while let Some(frame) = animation.next_frame().unwrap() {
+        frames.push(frame)
+    }
+    assert_eq!(frames.len(), 4);
+
+    let control = &frames[1].control;
+    assert_eq!(
+        (
+            control.width,
+            control.height,
+            control.x_offset,
+            control.y_offset
+        ),
+        (2, 2, 1, 1)
+    );
+    assert_eq!(
+        (control.dispose_op, control.blend_op),
+        (DisposeOp::Previous, BlendOp::Over)
+    );
+    assert_eq!(control.delay(), 0.1);
+    assert_eq!((frames[1].image.width, frames[1].image.height), (2, 2));
+    assert_pixels(&frames[0].image, |_, _| GREEN);
+    assert_pixels(&frames[1].image, |_, _| [255, 0, 0, 128]);
+    assert_pixels(&frames[2].image, |_, _| BLUE);
+    assert_pixels(&frames[3].image, |_, _| TRANSPARENT);
+}
+
+#[test]
+fn composite_animation_frames() {
+    let png = test_animation();
+    let mut input = Cursor::new(&png);
+    let mut animation = Animation::new(&mut input, AnimationMode::Composited).unwrap();
+    let inside =
+        |x: u32, y: u32, start: u32| x >= start && x < start + 2 && y >= start && y < start + 2;
+
+    let canvas = animation.next_frame().unwrap().unwrap().image;
+    assert_pixels(&canvas, |_, _| GREEN);
+    let canvas = animation.next_frame().unwrap().unwrap().image;
+    assert_pixels(&canvas, |x, y| {
+        if inside(x, y, 1) {
+            [128, 127, 0, 255]
+        } else {
+            GREEN
+        }
+    });
+    // The previous frame is disposed of by restoring what was under it.
+    let canvas = animation.next_frame().unwrap().unwrap().image;
+    assert_pixels(&canvas, |x, y| if inside(x, y, 2) { BLUE } else { GREEN });
+    // The previous frame is disposed of by clearing it, and a transparent pixel blended over the
+    // canvas leaves it alone.
+    let canvas = animation.next_frame().unwrap().unwrap().image;
+    assert_eq!((canvas.width, canvas.height), (4, 4));
+    assert_pixels(
+        &canvas,
+        |x, y| if inside(x, y, 2) { TRANSPARENT } else { GREEN },
+    );
+    assert!(animation.next_frame().unwrap().is_none());
+}
+
+#[test]
+fn wait_for_incomplete_chunks() {
+    let png = test_animation();
+    let split = 8 + 25 + 10;
+    let mut input = Cursor::new(png[..split].to_vec());
+    let mut loader = ImageLoader::new();
+    loader.set_animation_decoding(true);
+    assert!(loader.add_data(&mut input).unwrap() == LoadProgress::NeedMoreData);
+    assert!(loader.animation_control().is_none());
+
+    input.get_mut().extend_from_slice(&png[split..]);
+    assert!(loader.add_data(&mut input).unwrap() == LoadProgress::NeedDataProviderAndMoreData);
+    assert_eq!(loader.animation_control().unwrap().num_frames, 4);
+}
+
+#[test]
+fn report_truncated_images() {
+    let png = TestImage::new(16, 16, RGB, 8).encode();
+    for &length in &[8 + 25 + 4, png.len() - 20] {
+        assert!(Image::load(&mut Cursor::new(&png[..length])).is_err());
+    }
+}
+
+#[test]
+fn apply_crc_policies_to_chunks_before_the_image_data() {
+    let mut png = TestImage::new(4, 4, RGB, 8)
+        .with_chunk(b"teSt", b"data")
+        .encode();
+    corrupt_crc(&mut png, b"IHDR");
+    corrupt_crc(&mut png, b"teSt");
+
+    let mut loader = ImageLoader::new();
+    loader.set_crc_policy(CrcPolicy::Ignore);
+    loader.set_ancillary_crc_policy(CrcPolicy::Warn);
+    add_metadata(&mut loader, &png).unwrap();
+    assert_eq!(loader.warnings().len(), 1);
+    assert!(
+        matches!(loader.warnings()[0], PngError::InvalidCrc(ref chunk_type, _) if chunk_type == b"teSt")
+    );
+
+    let mut loader = ImageLoader::new();
+    loader.set_crc_policy(CrcPolicy::Ignore);
+    loader.set_ancillary_crc_policy(CrcPolicy::Error);
+    assert!(add_metadata(&mut loader, &png).is_err());
+}
+
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[test]
+fn read_text_chunks() {
+    let mut compressed_text = b"Comment\0\0".to_vec();
+    compressed_text.extend_from_slice(&compress(b"caf\xe9"));
+    let mut international_text = b"Description\0\x01\0de\0Beschreibung\0".to_vec();
+    international_text.extend_from_slice(&compress("Gr\u{fc}\u{df}e".as_bytes()));
+    let png = TestImage::new(2, 2, RGB, 8)
+        .with_chunk(b"tEXt", b"Title\0A title")
+        .with_chunk(b"zTXt", &compressed_text)
+        .with_chunk(b"iTXt", b"Author\0\0\0\0\0Someone")
+        .with_chunk(b"iTXt", &international_text)
+        .encode();
+
+    let mut loader = ImageLoader::new();
+    add_metadata(&mut loader, &png).unwrap();
+    let text_chunk = |keyword: &str, text: &str, compressed| TextChunk {
+        keyword: keyword.to_owned(),
+        text: text.to_owned(),
+        language_tag: None,
+        translated_keyword: None,
+        compressed,
+    };
+    assert_eq!(
+        loader.text_chunks(),
+        &[
+            text_chunk("Title", "A title", false),
+            text_chunk("Comment", "caf\u{e9}", true),
+            TextChunk {
+                language_tag: Some(String::new()),
+                translated_keyword: Some(String::new()),
+                ..text_chunk("Author", "Someone", false)
+            },
+            TextChunk {
+                language_tag: Some("de".to_owned()),
+                translated_keyword: Some("Beschreibung".to_owned()),
+                ..text_chunk("Description", "Gr\u{fc}\u{df}e", true)
+            },
+        ][..]
+    );
+}
+
+fn chromaticity_data(chromaticities: &Chromaticities) -> Vec<u8> {
+    let Chromaticities {
+        white_point,
+        red,
+        green,
+        blue,
+    } = *chromaticities;
+    [white_point, red, green, blue]
+        .iter()
+        .flat_map(|&(x, y)| [x, y])
+        .flat_map(u32::to_be_bytes)
+        .collect()
+}
+
+#[test]
+fn read_color_management_chunks() {
+    let chromaticities = Chromaticities {
+        white_point: (31270, 32900),
+        red: (64000, 33000),
+        green: (30000, 60000),
+        blue: (15000, 6000),
+    };
+    let mut icc_profile = b"Display\0\0".to_vec();
+    icc_profile.extend_from_slice(&compress(b"not really a profile"));
+    let png = TestImage::new(2, 2, RGB, 8)
+        .with_chunk(b"gAMA", &45455u32.to_be_bytes())
+        .with_chunk(b"cHRM", &chromaticity_data(&chromaticities))
+        .with_chunk(b"sRGB", &[1])
+        .with_chunk(b"iCCP", &icc_profile)
+        .encode();
+
+    let mut loader = ImageLoader::new();
+    add_metadata(&mut loader, &png).unwrap();
+    let color_management = loader.color_management();
+    assert_eq!(color_management.gamma.unwrap().scaled, 45455);
+    assert_eq!(color_management.chromaticities, Some(chromaticities));
+    assert_eq!(
+        color_management.srgb_rendering_intent,
+        Some(RenderingIntent::RelativeColorimetric)
+    );
+    let icc_profile = color_management.icc_profile.as_ref().unwrap();
+    assert_eq!(icc_profile.name, "Display");
+    assert_eq!(icc_profile.profile, b"not really a profile");
+    assert!(loader.warnings().is_empty());
+}
+
+#[test]
+fn ignore_malformed_color_management_chunks() {
+    let png = TestImage::new(2, 2, RGB, 8)
+        .with_chunk(b"gAMA", &[0, 1])
+        .with_chunk(b"sRGB", &[4])
+        .encode();
+    let mut loader = ImageLoader::new();
+    add_metadata(&mut loader, &png).unwrap();
+    assert!(loader.color_management().gamma.is_none());
+    assert!(loader.color_management().srgb_rendering_intent.is_none());
+    assert_eq!(loader.warnings().len(), 2);
+    assert_decodes(&TestImage::new(2, 2, RGB, 8).with_chunk(b"gAMA", &[0, 1]));
+}
+
+fn load_with_options(png: &[u8], options: &LoadOptions) -> Image {
+    Image::load_with_options(&mut Cursor::new(png), options).unwrap()
+}
+
+#[test]
+fn correct_gamma_for_the_display() {
+    let options = LoadOptions {
+        display_gamma: Some(2.2),
+        ..LoadOptions::default()
+    };
+    for &bit_depth in &[8, 16] {
+        let test_image = TestImage::new(9, 5, RGB_ALPHA, bit_depth);
+        let max = ((1u32 << bit_depth) - 1) as f64;
+        let linear = test_image.with_chunk(b"gAMA", &100_000u32.to_be_bytes());
+        let image = load_with_options(&linear.encode(), &options);
+        for y in 0..5 {
+            for x in 0..9 {
+                let mut expected = linear.expected_rgba(x, y);
+                for sample in &mut expected[0..3] {
+                    *sample = ((*sample as f64 / max).powf(1.0 / 2.2) * max + 0.5) as u16
+                }
+                assert_eq!(rgba_at(&image, x, y), expected);
+            }
+        }
+
+        // Images already encoded for a display like this one are left alone.
+        let test_image = TestImage::new(9, 5, RGB_ALPHA, bit_depth);
+        assert_decodes_with_options(
+            &test_image.with_chunk(b"gAMA", &45_455u32.to_be_bytes()),
+            &options,
+        );
+    }
+}
+
+/// Decodes the image with the given options, which mustn't change the pixels, and checks every
+/// pixel of the result.
+fn assert_decodes_with_options(test_image: &TestImage, options: &LoadOptions) {
+    let image = load_with_options(&test_image.encode(), options);
+    for y in 0..image.height {
+        for x in 0..image.width {
+            assert_eq!(rgba_at(&image, x, y), test_image.expected_rgba(x, y));
+        }
+    }
+}
+
+#[test]
+fn premultiply_alpha() {
+    let options = LoadOptions {
+        premultiply_alpha: true,
+        ..LoadOptions::default()
+    };
+    for &bit_depth in &[8, 16] {
+        let max = (1u64 << bit_depth) - 1;
+        for &interlaced in &[false, true] {
+            let mut test_image = TestImage::new(19, 7, RGB_ALPHA, bit_depth);
+            test_image.interlaced = interlaced;
+            let image = load_with_options(&test_image.encode(), &options);
+            for y in 0..7 {
+                for x in 0..19 {
+                    let mut expected = test_image.expected_rgba(x, y);
+                    let alpha = expected[3] as u64;
+                    for sample in &mut expected[0..3] {
+                        *sample = ((*sample as u64 * alpha * 2 + max) / (2 * max)) as u16
+                    }
+                    assert_eq!(rgba_at(&image, x, y), expected);
+                }
+            }
+        }
+
+        // Opaque images are unchanged.
+        assert_decodes_with_options(&TestImage::new(19, 7, RGB, bit_depth), &options);
+    }
+}
+
+#[test]
+fn store_channels_in_the_requested_order() {
+    let orders = [
+        ChannelOrder::Rgba,
+        ChannelOrder::Bgra,
+        ChannelOrder::Argb,
+        ChannelOrder::Abgr,
+    ];
+    for &channel_order in &orders {
+        let options = LoadOptions {
+            channel_order,
+            ..LoadOptions::default()
+        };
+        // RGBA images with 8 bits per sample skip the conversion pass unless they're reordered.
+        for &(color_type, bit_depth) in &[(RGB_ALPHA, 8), (RGB, 8), (RGB_ALPHA, 16), (GRAYSCALE, 4)]
+        {
+            for &interlaced in &[false, true] {
+                let mut test_image = TestImage::new(11, 6, color_type, bit_depth);
+                test_image.interlaced = interlaced;
+                let image = load_with_options(&test_image.encode(), &options);
+                assert_eq!(image.channel_order, channel_order);
+                for y in 0..6 {
+                    for x in 0..11 {
+                        let stored = rgba_at(&image, x, y);
+                        let positions = channel_order.sample_positions();
+                        let rgba = [0, 1, 2, 3].map(|channel| stored[positions[channel]]);
+                        assert_eq!(rgba, test_image.expected_rgba(x, y));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the samples of a pixel of an image decoded to `OutputFormat::RgbaFloat`.
+fn float_rgba_at(image: &Image, x: u32, y: u32) -> [f32; 4] {
+    let start = y as usize * image.stride + x as usize * 16;
+    let mut rgba = [0.0; 4];
+    for (channel, sample) in rgba.iter_mut().enumerate() {
+        let offset = start + channel * 4;
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&image.pixels[offset..offset + 4]);
+        *sample = f32::from_ne_bytes(bytes)
+    }
+    rgba
+}
+
+fn assert_decodes_to_float<F>(test_image: &TestImage, options: &LoadOptions, transfer: F)
+where
+    F: Fn(f64) -> f64,
+{
+    let max = if test_image.bit_depth == 16 {
+        65535.0
+    } else {
+        255.0
+    };
+    let image = load_with_options(&test_image.encode(), options);
+    assert_eq!(image.bit_depth, 32);
+    for y in 0..test_image.height {
+        for x in 0..test_image.width {
+            let expected = test_image.expected_rgba(x, y);
+            let rgba = float_rgba_at(&image, x, y);
+            for channel in 0..4 {
+                let mut value = expected[channel] as f64 / max;
+                if channel < 3 {
+                    value = transfer(value)
+                }
+                assert!(
+                    (rgba[channel] as f64 - value).abs() < 1e-5,
+                    "{:?} at ({}, {})",
+                    rgba,
+                    x,
+                    y
+                );
+            }
+        }
+    }
+}
+
+fn srgb_to_linear(value: f64) -> f64 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[test]
+fn decode_to_floating_point() {
+    let options = LoadOptions {
+        output_format: OutputFormat::RgbaFloat,
+        ..LoadOptions::default()
+    };
+    for &(color_type, bit_depth) in &[(RGB_ALPHA, 8), (RGB_ALPHA, 16), (GRAYSCALE, 2), (RGB, 8)] {
+        let test_image = TestImage::new(13, 5, color_type, bit_depth);
+        assert_decodes_to_float(&test_image, &options, |value| value);
+        assert_decodes_to_float(&test_image.interlaced(), &options, |value| value);
+    }
+}
+
+#[test]
+fn decode_to_linear_light() {
+    let options = LoadOptions {
+        output_format: OutputFormat::RgbaFloat,
+        linear_light: true,
+        ..LoadOptions::default()
+    };
+    for &bit_depth in &[8, 16] {
+        let test_image = TestImage::new(13, 5, RGB_ALPHA, bit_depth);
+        assert_decodes_to_float(&test_image, &options, srgb_to_linear);
+        let test_image = TestImage::new(13, 5, RGB_ALPHA, bit_depth).with_chunk(b"sRGB", &[0]);
+        assert_decodes_to_float(&test_image, &options, srgb_to_linear);
+        let test_image = TestImage::new(13, 5, RGB_ALPHA, bit_depth)
+            .with_chunk(b"gAMA", &50_000u32.to_be_bytes());
+        assert_decodes_to_float(&test_image, &options, |value| value.powf(2.0));
+    }
+}
+
+/// Returns the samples of a pixel of an image decoded in any integer pixel format.
+fn samples_at(image: &Image, x: u32, y: u32) -> Vec<u16> {
+    let bytes_per_sample = image.bit_depth as usize / 8;
+    let samples_per_pixel = image.pixel_format.samples_per_pixel() as usize;
+    let start = y as usize * image.stride + x as usize * samples_per_pixel * bytes_per_sample;
+    image.pixels[start..start + samples_per_pixel * bytes_per_sample]
+        .chunks(bytes_per_sample)
+        .map(|sample| match *sample {
+            [high, low] => u16::from_be_bytes([high, low]),
+            [value] => value as u16,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+#[test]
+fn preserve_grayscale() {
+    let options = LoadOptions {
+        preserve_grayscale: true,
+        ..LoadOptions::default()
+    };
+    for &(color_type, bit_depth) in &[
+        (GRAYSCALE, 1),
+        (GRAYSCALE, 4),
+        (GRAYSCALE, 8),
+        (GRAYSCALE, 16),
+        (GRAYSCALE_ALPHA, 8),
+        (GRAYSCALE_ALPHA, 16),
+    ] {
+        for &transparent in &[false, true] {
+            let mut test_image = TestImage::new(17, 5, color_type, bit_depth).interlaced();
+            // 16-bit magic colors are covered with the rest of `tRNS` handling.
+            if transparent && color_type == GRAYSCALE && bit_depth < 16 {
+                let magic = test_image.pixel(0, 0)[0].to_be_bytes();
+                test_image = test_image.with_chunk(b"tRNS", &magic)
+            }
+            let image = load_with_options(&test_image.encode(), &options);
+            let has_alpha = color_type == GRAYSCALE_ALPHA || test_image.chunk(b"tRNS").is_some();
+            let expected_pixel_format = if has_alpha {
+                PixelFormat::GrayscaleAlpha
+            } else {
+                PixelFormat::Grayscale
+            };
+            assert_eq!(image.pixel_format, expected_pixel_format);
+            for y in 0..5 {
+                for x in 0..17 {
+                    let rgba = test_image.expected_rgba(x, y);
+                    let expected = if has_alpha {
+                        vec![rgba[0], rgba[3]]
+                    } else {
+                        vec![rgba[0]]
+                    };
+                    assert_eq!(samples_at(&image, x, y), expected);
+                }
+            }
+        }
+    }
+
+    // Color images are still expanded to RGBA.
+    let image = load_with_options(&TestImage::new(3, 3, RGB, 8).encode(), &options);
+    assert_eq!(image.pixel_format, PixelFormat::Rgba);
+}
+
+#[test]
+fn preserve_indexed() {
+    let options = LoadOptions {
+        preserve_indexed: true,
+        ..LoadOptions::default()
+    };
+    for &bit_depth in &[1, 2, 4, 8] {
+        let test_image = indexed_image(17, 5, bit_depth, 1 << bit_depth)
+            .interlaced()
+            .with_chunk(b"tRNS", &[0, 128]);
+        let image = load_with_options(&test_image.encode(), &options);
+        assert_eq!(image.pixel_format, PixelFormat::Indexed);
+        assert_eq!(image.palette.as_deref(), test_image.chunk(b"PLTE"));
+        assert_eq!(image.palette_alpha.as_deref(), Some(&[0, 128][..]));
+        for y in 0..5 {
+            for x in 0..17 {
+                assert_eq!(samples_at(&image, x, y), test_image.pixel(x, y));
+            }
+        }
+    }
+
+    let image = load_with_options(&TestImage::new(3, 3, RGB, 8).encode(), &options);
+    assert_eq!(image.pixel_format, PixelFormat::Rgba);
+    assert!(image.palette.is_none());
+}
+
+#[test]
+fn pack_pixels_into_16_bits() {
+    let formats = [
+        (OutputFormat::Rgb565, [5, 6, 5, 0]),
+        (OutputFormat::Rgba4444, [4, 4, 4, 4]),
+        (OutputFormat::Rgba5551, [5, 5, 5, 1]),
+    ];
+    for &(output_format, bits) in &formats {
+        let options = LoadOptions {
+            output_format,
+            ..LoadOptions::default()
+        };
+        let test_image = TestImage::new(9, 4, RGB_ALPHA, 8).interlaced();
+        let image = load_with_options(&test_image.encode(), &options);
+        assert_eq!((image.bit_depth, image.output_format), (16, output_format));
+        for y in 0..4 {
+            for x in 0..9 {
+                let mut expected = 0;
+                for (&sample, &bits) in test_image.expected_rgba(x, y).iter().zip(bits.iter()) {
+                    let max = (1 << bits) - 1;
+                    expected = (expected << bits) | (2 * sample as u32 * max + 255) / 510
+                }
+                let offset = y as usize * image.stride + x as usize * 2;
+                let pixel = u16::from_ne_bytes([image.pixels[offset], image.pixels[offset + 1]]);
+                assert_eq!(
+                    pixel as u32, expected,
+                    "{:?} at ({}, {})",
+                    output_format, x, y
+                );
+            }
+        }
+    }
+}
+
+/// Returns the samples of a pixel of a buffer of 8-bit RGBA pixels.
+fn rgba8_at(pixels: &[u8], stride: usize, x: u32, y: u32) -> [u16; 4] {
+    let offset = y as usize * stride + x as usize * 4;
+    [0, 1, 2, 3].map(|channel| pixels[offset + channel] as u16)
+}
+
+#[test]
+fn load_into_a_buffer_of_the_callers() {
+    let test_image = TestImage::new(7, 5, RGB_ALPHA, 8).interlaced();
+    let png = test_image.encode();
+    let options = LoadOptions::default();
+    let stride = 7 * 4 + 3;
+    let length = stride * 4 + 7 * 4;
+
+    let mut buffer = vec![0xaa; length + 1];
+    match Image::load_into(
+        &mut Cursor::new(&png),
+        &mut buffer[..length - 1],
+        stride,
+        &options,
+    ) {
+        Err(PngError::BufferTooSmall(required)) => assert_eq!(required, length),
+        result => panic!("unexpected result: {:?}", result.err()),
+    }
+    match Image::load_into(&mut Cursor::new(&png), &mut buffer, 7 * 4 - 1, &options) {
+        Err(PngError::StrideTooSmall(required)) => assert_eq!(required, 7 * 4),
+        result => panic!("unexpected result: {:?}", result.err()),
+    }
+    assert!(buffer.iter().all(|&byte| byte == 0xaa));
+
+    let info = Image::load_into(
+        &mut Cursor::new(&png),
+        &mut buffer[..length],
+        stride,
+        &options,
+    )
+    .unwrap();
+    assert_eq!((info.width, info.height, info.bit_depth), (7, 5, 8));
+    for y in 0..5 {
+        for x in 0..7 {
+            assert_eq!(
+                rgba8_at(&buffer, stride, x, y as u32),
+                test_image.expected_rgba(x, y as u32)
+            );
+        }
+        // The padding at the end of each row is left alone.
+        let row = &buffer[y * stride..];
+        let padding = if y < 4 {
+            &row[7 * 4..stride]
+        } else {
+            &row[7 * 4..]
+        };
+        assert!(padding.iter().all(|&byte| byte == 0xaa));
+    }
+}
+
+#[test]
+fn load_into_a_rectangle_of_a_surface() {
+    let (x, y) = (3, 2);
+    let options = LoadOptions::default();
+    for &(width, height) in &[(5, 4), (1, 1), (9, 9)] {
+        let test_image = TestImage::new(width, height, RGB_ALPHA, 8).interlaced();
+        let png = test_image.encode();
+        let stride = (x + width) as usize * 4;
+        let length = stride * (y + height - 1) as usize + stride;
+        let mut surface = vec![0xaa; length];
+        Image::load_into_rect(&mut Cursor::new(&png), &mut surface, stride, x, y, &options)
+            .unwrap();
+        for row in 0..y + height {
+            for column in 0..x + width {
+                let pixel = rgba8_at(&surface, stride, column, row);
+                if row >= y && column >= x {
+                    assert_eq!(pixel, test_image.expected_rgba(column - x, row - y));
+                } else {
+                    assert_eq!(
+                        pixel, [0xaa; 4],
+                        "wrote outside the rectangle at ({}, {})",
+                        column, row
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn reject_rectangles_that_overflow() {
+    let png = TestImage::new(2, 2, RGB_ALPHA, 8).encode();
+    let mut surface = vec![0; 64];
+    let options = LoadOptions::default();
+    for &(x, y) in &[(u32::MAX, 0), (0, u32::MAX), (u32::MAX / 4, 1)] {
+        let result =
+            Image::load_into_rect(&mut Cursor::new(&png), &mut surface, 16, x, y, &options);
+        assert!(result.is_err());
+    }
+}
+
+#[test]
+fn flip_images_vertically() {
+    let options = LoadOptions {
+        flip_vertically: true,
+        ..LoadOptions::default()
+    };
+    for &(color_type, bit_depth) in &[(RGB_ALPHA, 8), (RGB, 16), (INDEXED, 2)] {
+        for &interlaced in &[false, true] {
+            let mut test_image = TestImage::new(7, 9, color_type, bit_depth);
+            if color_type == INDEXED {
+                test_image = indexed_image(7, 9, bit_depth, 4);
+            }
+            test_image.interlaced = interlaced;
+            let image = load_with_options(&test_image.encode(), &options);
+            for y in 0..9 {
+                for x in 0..7 {
+                    assert_eq!(rgba_at(&image, x, 8 - y), test_image.expected_rgba(x, y));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn flip_images_within_their_rectangle() {
+    let options = LoadOptions {
+        flip_vertically: true,
+        ..LoadOptions::default()
+    };
+    let test_image = TestImage::new(3, 5, RGB_ALPHA, 8).interlaced();
+    let stride = 5 * 4;
+    let mut surface = vec![0xaa; stride * 7];
+    Image::load_into_rect(
+        &mut Cursor::new(&test_image.encode()),
+        &mut surface,
+        stride,
+        2,
+        1,
+        &options,
+    )
+    .unwrap();
+    for y in 0..5 {
+        for x in 0..3 {
+            let pixel = rgba8_at(&surface, stride, 2 + x, 5 - y);
+            assert_eq!(pixel, test_image.expected_rgba(x, y));
+        }
+    }
+    assert!(surface[..stride].iter().all(|&byte| byte == 0xaa));
+    assert!(surface[stride * 6..].iter().all(|&byte| byte == 0xaa));
+}
+
+#[test]
+fn align_rows_as_requested() {
+    let test_image = TestImage::new(13, 3, RGB, 8);
+    for &(alignment, expected_stride) in &[(Some(1), 13 * 4), (Some(256), 256), (None, 64)] {
+        let options = LoadOptions {
+            row_alignment: alignment,
+            ..LoadOptions::default()
+        };
+        let image = load_with_options(&test_image.encode(), &options);
+        assert_eq!(image.stride, expected_stride);
+        for y in 0..3 {
+            for x in 0..13 {
+                assert_eq!(rgba_at(&image, x, y), test_image.expected_rgba(x, y));
+            }
+        }
+    }
+
+    let options = LoadOptions {
+        row_alignment: Some(0),
+        ..LoadOptions::default()
+    };
+    let result = Image::load_with_options(&mut Cursor::new(&test_image.encode()), &options);
+    assert!(matches!(result, Err(PngError::InvalidOption(_))));
+}
+
+#[test]
+fn make_magic_colors_transparent_at_every_bit_depth() {
+    for &bit_depth in &[1, 2, 4, 8, 16] {
+        for &interlaced in &[false, true] {
+            let mut test_image = TestImage::new(11, 7, GRAYSCALE, bit_depth);
+            test_image.interlaced = interlaced;
+            let magic = test_image.pixel(4, 3)[0].to_be_bytes();
+            assert_decodes(&test_image.with_chunk(b"tRNS", &magic));
+        }
+    }
+    for &bit_depth in &[8, 16] {
+        let test_image = TestImage::new(11, 7, RGB, bit_depth).interlaced();
+        let magic: Vec<u8> = test_image
+            .pixel(4, 3)
+            .iter()
+            .flat_map(|s| s.to_be_bytes())
+            .collect();
+        assert_decodes(&test_image.with_chunk(b"tRNS", &magic));
+    }
+}
+
+#[test]
+fn ignore_magic_colors_of_images_with_alpha() {
+    let png = TestImage::new(3, 3, RGB_ALPHA, 8)
+        .with_chunk(b"tRNS", &[0, 1, 0, 2, 0, 3])
+        .encode();
+    let mut loader = ImageLoader::new();
+    add_metadata(&mut loader, &png).unwrap();
+    assert_eq!(loader.warnings().len(), 1);
+    assert_decodes(&TestImage::new(3, 3, RGB_ALPHA, 8).with_chunk(b"tRNS", &[0, 1, 0, 2, 0, 3]));
+}
+
+#[test]
+fn keep_16_bit_magic_colors_of_preserved_grayscale_images() {
+    let options = LoadOptions {
+        preserve_grayscale: true,
+        ..LoadOptions::default()
+    };
+    let test_image = TestImage::new(9, 5, GRAYSCALE, 16).interlaced();
+    let magic = test_image.pixel(2, 1)[0].to_be_bytes();
+    let test_image = test_image.with_chunk(b"tRNS", &magic);
+    let image = load_with_options(&test_image.encode(), &options);
+    assert_eq!(image.pixel_format, PixelFormat::GrayscaleAlpha);
+    for y in 0..5 {
+        for x in 0..9 {
+            let rgba = test_image.expected_rgba(x, y);
+            assert_eq!(samples_at(&image, x, y), vec![rgba[0], rgba[3]]);
+        }
+    }
+}
+
+#[test]
+fn flatten_alpha_onto_the_background() {
+    for &bit_depth in &[8, 16] {
+        let max = (1u32 << bit_depth) - 1;
+        let background: [u16; 3] = if bit_depth == 16 {
+            [0x1234, 0x5678, 0x9abc]
+        } else {
+            [10, 200, 77]
+        };
+        let background_data: Vec<u8> = background.iter().flat_map(|s| s.to_be_bytes()).collect();
+        let test_image =
+            TestImage::new(9, 4, RGB_ALPHA, bit_depth).with_chunk(b"bKGD", &background_data);
+        let png = test_image.encode();
+
+        let mut loader = ImageLoader::new();
+        add_metadata(&mut loader, &png).unwrap();
+        assert_eq!(
+            loader.background(),
+            Some(Background::Rgb(background[0], background[1], background[2]))
+        );
+
+        let fallback = [1, 2, 3];
+        for &(flatten_alpha, color) in &[
+            (FlattenBackground::Image(fallback), background),
+            (
+                FlattenBackground::Color([10, 200, 77]),
+                [10, 200, 77].map(|sample: u16| sample * (max / 255) as u16),
+            ),
+        ] {
+            let options = LoadOptions {
+                flatten_alpha: Some(flatten_alpha),
+                ..LoadOptions::default()
+            };
+            let image = load_with_options(&png, &options);
+            for y in 0..4 {
+                for x in 0..9 {
+                    let rgba = test_image.expected_rgba(x, y);
+                    let alpha = rgba[3] as u32;
+                    let mut expected = [0, 0, 0, max as u16];
+                    for channel in 0..3 {
+                        expected[channel] = ((rgba[channel] as u32 * alpha
+                            + color[channel] as u32 * (max - alpha)
+                            + max / 2)
+                            / max) as u16
+                    }
+                    assert_eq!(rgba_at(&image, x, y), expected);
+                }
+            }
+        }
+    }
+}
+
+/// Returns big-endian Exif data holding just the given Orientation tag.
+fn exif_orientation(orientation: u16) -> Vec<u8> {
+    let mut exif = b"MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+    exif.extend_from_slice(&orientation.to_be_bytes());
+    exif.extend_from_slice(&[0; 6]);
+    exif
+}
+
+#[test]
+fn read_the_exif_orientation() {
+    let png = TestImage::new(3, 2, RGB, 8)
+        .with_chunk(b"eXIf", &exif_orientation(6))
+        .encode();
+    let mut loader = ImageLoader::new();
+    add_metadata(&mut loader, &png).unwrap();
+    assert_eq!(
+        loader.exif().unwrap().orientation(),
+        Some(Orientation::Rotate90)
+    );
+}
+
+#[test]
+fn orient_images_as_exif_says() {
+    let options = LoadOptions {
+        auto_orient: true,
+        ..LoadOptions::default()
+    };
+    let test_image = TestImage::new(5, 3, RGB_ALPHA, 8).interlaced();
+    let mut png = test_image.encode();
+    // The `eXIf` chunk may follow the image data.
+    let mut exif_chunk = vec![];
+    write_chunk(&mut exif_chunk, b"eXIf", &exif_orientation(6));
+    let end = png.len() - 12;
+    png.splice(end..end, exif_chunk);
+
+    let image = load_with_options(&png, &options);
+    assert_eq!((image.width, image.height), (3, 5));
+    for y in 0..5 {
+        for x in 0..3 {
+            assert_eq!(rgba_at(&image, x, y), test_image.expected_rgba(y, 2 - x));
+        }
+    }
+
+    // Images decoded as stored are left alone.
+    let image = load_with_options(&png, &LoadOptions::default());
+    assert_eq!((image.width, image.height), (5, 3));
+}
+
+#[test]
+fn report_the_pixel_density() {
+    let mut physical_dimensions = 3780u32.to_be_bytes().to_vec();
+    physical_dimensions.extend_from_slice(&7560u32.to_be_bytes());
+    physical_dimensions.push(1);
+    let png = TestImage::new(2, 2, RGB, 8)
+        .with_chunk(b"pHYs", &physical_dimensions)
+        .encode();
+    let mut loader = ImageLoader::new();
+    add_metadata(&mut loader, &png).unwrap();
+    assert_eq!(
+        loader.physical_dimensions(),
+        Some(PhysicalDimensions {
+            pixels_per_unit_x: 3780,
+            pixels_per_unit_y: 7560,
+            unit: PhysicalUnit::Meter,
+        })
+    );
+    let (dpi_x, dpi_y) = loader.dpi().unwrap();
+    assert!((dpi_x - 96.012).abs() < 1e-9 && (dpi_y - 192.024).abs() < 1e-9);
+}
+
+#[test]
+fn leave_the_pixel_density_of_scaled_images_unknown() {
+    let png = TestImage::new(2, 2, RGB, 8)
+        .with_chunk(b"sCAL", b"\x010.5\x000.25")
+        .encode();
+    let mut loader = ImageLoader::new();
+    add_metadata(&mut loader, &png).unwrap();
+    let physical_scale = loader.physical_scale().unwrap();
+    assert_eq!(
+        (physical_scale.pixel_width, physical_scale.pixel_height),
+        (0.5, 0.25)
+    );
+    assert!(loader.dpi().is_none());
+}
+
+#[test]
+fn rescale_significant_bits() {
+    let options = LoadOptions {
+        rescale_significant_bits: true,
+        ..LoadOptions::default()
+    };
+    let bits = [5, 6, 5];
+    let mut test_image = TestImage::new(9, 4, RGB, 8).with_chunk(b"sBIT", &bits);
+    // Only the significant bits are set, as in an image converted from RGB565.
+    for (i, sample) in test_image.samples.iter_mut().enumerate() {
+        *sample &= !0u16 << (8 - bits[i % 3])
+    }
+    let png = test_image.encode();
+
+    let mut loader = ImageLoader::new();
+    add_metadata(&mut loader, &png).unwrap();
+    assert_eq!(
+        loader.significant_bits(),
+        Some(SignificantBits::Rgb(5, 6, 5))
+    );
+
+    let image = load_with_options(&png, &options);
+    for y in 0..4 {
+        for x in 0..9 {
+            let mut expected = test_image.expected_rgba(x, y);
+            for (sample, &bits) in expected.iter_mut().zip(bits.iter()) {
+                let max = (1 << bits) - 1;
+                *sample = ((*sample >> (8 - bits)) * 255 + max / 2) / max
+            }
+            assert_eq!(rgba_at(&image, x, y), expected);
+        }
+    }
+
+    // Without the option, the samples are left alone.
+    assert_decodes(&test_image);
+}
+
+#[test]
+fn read_hdr_metadata() {
+    let mut mastering_display = vec![];
+    for value in &[35400u16, 14600, 8500, 39850, 6550, 2300, 15635, 16450] {
+        mastering_display.extend_from_slice(&value.to_be_bytes())
+    }
+    mastering_display.extend_from_slice(&10_000_000u32.to_be_bytes());
+    mastering_display.extend_from_slice(&50u32.to_be_bytes());
+    let mut content_light_level = 10_000_000u32.to_be_bytes().to_vec();
+    content_light_level.extend_from_slice(&4_000_000u32.to_be_bytes());
+    let png = TestImage::new(2, 2, RGB, 16)
+        .with_chunk(b"cICP", &[9, 16, 0, 1])
+        .with_chunk(b"mDCv", &mastering_display)
+        .with_chunk(b"cLLi", &content_light_level)
+        .encode();
+
+    let mut loader = ImageLoader::new();
+    add_metadata(&mut loader, &png).unwrap();
+    let color_management = loader.color_management();
+    assert_eq!(
+        color_management.cicp,
+        Some(CodingIndependentCodePoints {
+            color_primaries: 9,
+            transfer_function: 16,
+            matrix_coefficients: 0,
+            video_full_range: true,
+        })
+    );
+    let mastering_display = color_management.mastering_display.unwrap();
+    assert_eq!(mastering_display.red, (35400, 14600));
+    assert_eq!(mastering_display.white_point, (15635, 16450));
+    assert_eq!(mastering_display.luminance(), (1000.0, 0.005));
+    assert_eq!(
+        color_management.content_light_level,
+        Some(ContentLightLevel {
+            max_content_light_level: 10_000_000,
+            max_frame_average_light_level: 4_000_000,
+        })
+    );
+}
+
+#[test]
+fn let_cicp_chunks_override_the_transfer_function() {
+    let options = LoadOptions {
+        output_format: OutputFormat::RgbaFloat,
+        linear_light: true,
+        ..LoadOptions::default()
+    };
+    let test_image = TestImage::new(7, 3, RGB_ALPHA, 8)
+        .with_chunk(b"cICP", &[1, 13, 0, 1])
+        .with_chunk(b"gAMA", &100_000u32.to_be_bytes());
+    assert_decodes_to_float(&test_image, &options, srgb_to_linear);
+
+    // Narrow-range samples aren't supported, so they're left alone rather than decoded with the
+    // transfer function of the `gAMA` chunk.
+    let test_image = TestImage::new(7, 3, RGB_ALPHA, 8)
+        .with_chunk(b"cICP", &[1, 13, 0, 0])
+        .with_chunk(b"gAMA", &50_000u32.to_be_bytes());
+    assert_decodes_to_float(&test_image, &options, |value| value);
+}
+
+#[test]
+fn convert_colors_to_the_target_color_space() {
+    let options = LoadOptions {
+        color_transform: Some(TargetColorSpace::Srgb),
+        ..LoadOptions::default()
+    };
+    // Images that are already sRGB are unchanged.
+    assert_decodes_with_options(
+        &TestImage::new(7, 3, RGB, 8).with_chunk(b"sRGB", &[0]),
+        &options,
+    );
+
+    // Linear images with the sRGB primaries only need the sRGB transfer function applied.
+    let test_image =
+        TestImage::new(7, 3, RGB_ALPHA, 8).with_chunk(b"gAMA", &100_000u32.to_be_bytes());
+    let image = load_with_options(&test_image.encode(), &options);
+    for y in 0..3 {
+        for x in 0..7 {
+            let expected = test_image.expected_rgba(x, y);
+            let rgba = rgba_at(&image, x, y);
+            assert_eq!(rgba[3], expected[3]);
+            for channel in 0..3 {
+                let linear = expected[channel] as f64 / 255.0;
+                let encoded = if linear <= 0.003_130_8 {
+                    linear * 12.92
+                } else {
+                    1.055 * linear.powf(1.0 / 2.4) - 0.055
+                };
+                let difference = rgba[channel] as f64 - encoded * 255.0;
+                assert!(difference.abs() <= 1.0, "{:?} at ({}, {})", rgba, x, y);
+            }
+        }
+    }
+
+    // Target primaries must enclose some area.
+    let degenerate = Chromaticities {
+        white_point: (31270, 32900),
+        red: (64000, 33000),
+        green: (64000, 33000),
+        blue: (15000, 6000),
+    };
+    let options = LoadOptions {
+        color_transform: Some(TargetColorSpace::Primaries(degenerate)),
+        ..LoadOptions::default()
+    };
+    let result = Image::load_with_options(&mut Cursor::new(&test_image.encode()), &options);
+    assert!(result.is_err());
+}
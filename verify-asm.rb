@@ -138,13 +138,14 @@ case arch
 when 'arm'
     check(allowed_directives: Set.new(%w(endm macro)),
           allowed_instructions: Set.new(%w(bic ldr mov orr str vabd vabs vadd vand vbic vcgt) +
-                                        %w(veor vhadd vld1 vldr vmax vmin vmov vorr vsri vst1) +
-                                        %w(vstr vsub vtbl vuzp vzip)),
+                                        %w(veor vhadd vld1 vldr vmax vmin vmov vmull vorr vrshr) +
+                                        %w(vrshrn vsri vst1 vstr vsub vtbl vuzp vzip)),
           allowed_memory_locations: Set.new(%w(dest prev src)),
-          allowed_operands: Set.new(%w(r7 d0 d1 d2 d3 d4 d5 d6 d7 s2 s3 s5 s6 s7 s8 s9 s14 s15) +
+          allowed_operands: Set.new(%w(r7 d0 d1 d2 d3 d4 d5 d6 d7 s0 s2 s3 s4 s5 s6 s7 s8 s9) +
+                                    %w(s14 s15) +
                                     %w(q0 q1 q2 q3)),
           allowed_macro_arguments: Set.new(%w(dest_lo dest_hi)),
-          allowed_data_types: Set.new(%w(8 16 32 64 i32 u8 u16 s16)),
+          allowed_data_types: Set.new(%w(8 16 32 64 i16 i32 u8 u16 s16)),
           allowed_macros: Set.new(%w(move_neon_byte_to_register)),
           directive_sigil: '.',
           comment_sigil: '@',
@@ -153,9 +154,9 @@ when 'arm'
 when 'x86_64'
     check(allowed_directives: Set.new(%w(endmacro macro)),
           allowed_instructions: Set.new(%w(and mov movd movddup movdqa movdqu movq or pabsw) +
-                                        %w(paddb paddw pand pcmpgtw pinsrq pmaxsw pmovzxbw por) +
-                                        %w(pshufb psrlw vpandn vpminsw vpshufb vpslldq vpsrldq) +
-                                        %w(vpsubw xorps)),
+                                        %w(packuswb paddb paddw pand pcmpgtw pinsrq pmaxsw) +
+                                        %w(pmovzxbw pmullw por pshufb psrlw vpandn vpminsw) +
+                                        %w(vpshufb vpslldq vpsrldq vpsrlw vpsubw xorps)),
           allowed_memory_locations: Set.new(%w(dest prev src)),
           allowed_operands: Set.new(%w(r11 r11d xmm0 xmm1 xmm2 xmm3 xmm4 xmm5 xmm6 xmm7 xmm8) +
                                     %w(xmm9 xmm10 xmm11 xmm12 xmm13 xmm14 xmm15)),